@@ -0,0 +1,54 @@
+/*
+ * Copyright Stalwart Labs Ltd. See the COPYING
+ * file at the top-level directory of this distribution.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use mail_send::{
+    smtp::{message::Message, mock::MockServer},
+    Credentials,
+};
+
+#[tokio::main]
+async fn main() {
+    // Script the server side of the session
+    let (mut client, server) = MockServer::new()
+        .capabilities(["PIPELINING", "AUTH PLAIN"])
+        .expect("AUTH PLAIN AGpvaG4AcDRzc3cwcmQ=", "235 2.7.0 Accepted")
+        .expect("MAIL FROM:<john@example.com>", "250 OK")
+        .expect("RCPT TO:<jane@example.com>", "250 OK")
+        .expect("DATA", "354 Go ahead")
+        .expect_data("250 2.0.0 Queued as 1A2B3C")
+        .connect()
+        .await
+        .unwrap();
+
+    // Drive the client over the in-memory stream
+    let capabilities = client.ehlo("client.example.com").await.unwrap();
+    client
+        .authenticate(Credentials::new("john", "p4ssw0rd"), &capabilities)
+        .await
+        .unwrap();
+    let reply = client
+        .send_tracked(Message::new(
+            "john@example.com",
+            ["jane@example.com"],
+            &b"Subject: Hi!\r\n\r\nHello, world!"[..],
+        ))
+        .await
+        .unwrap();
+    client.quit().await.unwrap();
+    println!("Server replied: {}", reply.message());
+
+    // Inspect what the server received
+    let transcript = server.await.unwrap().unwrap();
+    for command in &transcript.commands {
+        println!("C: {command}");
+    }
+    println!("{}", String::from_utf8_lossy(&transcript.messages[0]));
+}
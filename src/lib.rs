@@ -26,6 +26,7 @@
 //!   - CRAM-MD5 (_RFC 2195_)
 //!   - DIGEST-MD5 (_RFC 2831_; obsolete but still supported)
 //!   - XOAUTH2 (Google proprietary)
+//!   - NTLM (Microsoft proprietary; requires the `ntlm` feature)
 //!   - LOGIN
 //!   - PLAIN
 //! - Full async (requires Tokio).
@@ -89,6 +90,12 @@
 //!         .unwrap();
 //! ```
 //!
+//! Listing a header one more time than it appears in the message, such as
+//! `.headers(["From", "To", "Subject", "Subject"])`, over-signs it: the extra
+//! `h=` entry covers a header that is not present, so verifiers reject the
+//! message if a second `Subject` header is added after signing (RFC 6376,
+//! section 5.4.2).
+//!
 //! More examples of how to build messages are available in the [`mail-builder`](https://crates.io/crates/mail-builder) crate.
 //! Please note that this library does not support parsing e-mail messages as this functionality is provided separately by the [`mail-parser`](https://crates.io/crates/mail-parser) crate.
 //!
@@ -125,14 +132,23 @@
 //!
 
 pub mod smtp;
-use std::{fmt::Display, hash::Hash, time::Duration};
+use rustls::{client::ClientSessionStore, ClientConfig};
+use smtp::proxy::ProxyHeader;
+use smtp_proto::EhloResponse;
+use std::{
+    fmt::Display,
+    hash::Hash,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::TlsConnector;
 
 #[cfg(feature = "builder")]
 pub use mail_builder;
 
-#[cfg(feature = "dkim")]
+#[cfg(any(feature = "dkim", feature = "mta-sts"))]
 pub use mail_auth;
 
 #[derive(Debug)]
@@ -173,19 +189,197 @@ pub enum Error {
     /// The server does no support any of the available authentication methods.
     UnsupportedAuthMechanism,
 
-    /// Connection timeout.
-    Timeout,
+    /// An operation timed out. `received` holds any bytes of the reply
+    /// that arrived before the timeout expired.
+    Timeout {
+        phase: TimeoutPhase,
+        received: Vec<u8>,
+    },
 
     /// STARTTLS not available
     MissingStartTls,
+
+    /// Authentication attempted over an unencrypted connection.
+    InsecureAuth,
+
+    /// Invalid e-mail address.
+    InvalidAddress,
+
+    /// Message not attempted because an earlier failure closed the session.
+    NotAttempted,
+
+    /// The server does not advertise an extension required by the request.
+    UnsupportedExtension(&'static str),
+
+    /// A parameter exceeds the limits announced by the server.
+    ParameterOutOfRange(&'static str),
+
+    /// The server did not respond with TLS, it likely expects STARTTLS.
+    NotTls,
+
+    /// The message exceeds the size limit of the server, when known.
+    MessageTooLarge(Option<usize>),
+
+    /// The server rejected the STARTTLS command.
+    StartTlsRejected(smtp_proto::Response<String>),
+
+    /// The server rejected the EHLO/LHLO command sent after STARTTLS.
+    EhloAfterTlsRejected(smtp_proto::Response<String>),
+
+    /// MTA-STS policy failure.
+    MtaSts(smtp::mta_sts::Error),
+
+    /// DNS resolution failure.
+    Dns(String),
+
+    /// A header name or value would break the message structure.
+    InvalidHeader,
+
+    /// An XCLIENT or XFORWARD attribute name contains characters other than
+    /// letters, digits and hyphens.
+    InvalidAttribute(String),
+
+    /// A message line exceeds the maximum length of 998 octets.
+    LineTooLong,
+
+    /// The connection was found closed before sending a message.
+    ConnectionClosed,
+}
+
+/// Operation in progress when a timeout expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeoutPhase {
+    /// Establishing the connection and SMTP session.
+    Connect,
+    /// Performing the TLS handshake.
+    TlsHandshake,
+    /// Waiting for the server greeting.
+    Greeting,
+    /// Sending a command and waiting for its reply.
+    Command,
+    /// Transferring a message with DATA or BDAT.
+    Data,
+}
+
+/// Error categories, useful for comparing errors without pattern matching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Io,
+    Tls,
+    Base64,
+    Auth,
+    UnparseableReply,
+    UnexpectedReply,
+    AuthenticationFailed,
+    InvalidTLSName,
+    MissingCredentials,
+    MissingMailFrom,
+    MissingRcptTo,
+    UnsupportedAuthMechanism,
+    Timeout,
+    MissingStartTls,
+    InsecureAuth,
+    InvalidAddress,
+    NotAttempted,
+    UnsupportedExtension,
+    ParameterOutOfRange,
+    NotTls,
+    MessageTooLarge,
+    StartTlsRejected,
+    EhloAfterTlsRejected,
+    MtaSts,
+    Dns,
+    InvalidHeader,
+    InvalidAttribute,
+    LineTooLong,
+    ConnectionClosed,
+}
+
+impl Error {
+    /// Returns the category of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(_) => ErrorKind::Io,
+            Error::Tls(_) => ErrorKind::Tls,
+            Error::Base64(_) => ErrorKind::Base64,
+            Error::Auth(_) => ErrorKind::Auth,
+            Error::UnparseableReply => ErrorKind::UnparseableReply,
+            Error::UnexpectedReply(_) => ErrorKind::UnexpectedReply,
+            Error::AuthenticationFailed(_) => ErrorKind::AuthenticationFailed,
+            Error::InvalidTLSName => ErrorKind::InvalidTLSName,
+            Error::MissingCredentials => ErrorKind::MissingCredentials,
+            Error::MissingMailFrom => ErrorKind::MissingMailFrom,
+            Error::MissingRcptTo => ErrorKind::MissingRcptTo,
+            Error::UnsupportedAuthMechanism => ErrorKind::UnsupportedAuthMechanism,
+            Error::Timeout { .. } => ErrorKind::Timeout,
+            Error::MissingStartTls => ErrorKind::MissingStartTls,
+            Error::InsecureAuth => ErrorKind::InsecureAuth,
+            Error::InvalidAddress => ErrorKind::InvalidAddress,
+            Error::NotAttempted => ErrorKind::NotAttempted,
+            Error::UnsupportedExtension(_) => ErrorKind::UnsupportedExtension,
+            Error::ParameterOutOfRange(_) => ErrorKind::ParameterOutOfRange,
+            Error::NotTls => ErrorKind::NotTls,
+            Error::MessageTooLarge(_) => ErrorKind::MessageTooLarge,
+            Error::StartTlsRejected(_) => ErrorKind::StartTlsRejected,
+            Error::EhloAfterTlsRejected(_) => ErrorKind::EhloAfterTlsRejected,
+            Error::MtaSts(_) => ErrorKind::MtaSts,
+            Error::Dns(_) => ErrorKind::Dns,
+            Error::InvalidHeader => ErrorKind::InvalidHeader,
+            Error::InvalidAttribute(_) => ErrorKind::InvalidAttribute,
+            Error::LineTooLong => ErrorKind::LineTooLong,
+            Error::ConnectionClosed => ErrorKind::ConnectionClosed,
+        }
+    }
+}
+
+/// I/O errors are cloned by kind and message, their inner source is not kept.
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        match self {
+            Error::Io(err) => Error::Io(std::io::Error::new(err.kind(), err.to_string())),
+            Error::Tls(err) => Error::Tls(err.clone()),
+            Error::Base64(err) => Error::Base64(err.clone()),
+            Error::Auth(err) => Error::Auth(err.clone()),
+            Error::UnparseableReply => Error::UnparseableReply,
+            Error::UnexpectedReply(reply) => Error::UnexpectedReply(reply.clone()),
+            Error::AuthenticationFailed(reply) => Error::AuthenticationFailed(reply.clone()),
+            Error::InvalidTLSName => Error::InvalidTLSName,
+            Error::MissingCredentials => Error::MissingCredentials,
+            Error::MissingMailFrom => Error::MissingMailFrom,
+            Error::MissingRcptTo => Error::MissingRcptTo,
+            Error::UnsupportedAuthMechanism => Error::UnsupportedAuthMechanism,
+            Error::Timeout { phase, received } => Error::Timeout {
+                phase: *phase,
+                received: received.clone(),
+            },
+            Error::MissingStartTls => Error::MissingStartTls,
+            Error::InsecureAuth => Error::InsecureAuth,
+            Error::InvalidAddress => Error::InvalidAddress,
+            Error::InvalidHeader => Error::InvalidHeader,
+            Error::InvalidAttribute(name) => Error::InvalidAttribute(name.clone()),
+            Error::LineTooLong => Error::LineTooLong,
+            Error::ConnectionClosed => Error::ConnectionClosed,
+            Error::NotAttempted => Error::NotAttempted,
+            Error::UnsupportedExtension(name) => Error::UnsupportedExtension(name),
+            Error::ParameterOutOfRange(name) => Error::ParameterOutOfRange(name),
+            Error::NotTls => Error::NotTls,
+            Error::MessageTooLarge(limit) => Error::MessageTooLarge(*limit),
+            Error::StartTlsRejected(reply) => Error::StartTlsRejected(reply.clone()),
+            Error::EhloAfterTlsRejected(reply) => Error::EhloAfterTlsRejected(reply.clone()),
+            Error::MtaSts(err) => Error::MtaSts(err.clone()),
+            Error::Dns(err) => Error::Dns(err.clone()),
+        }
+    }
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::Io(ref err) => err.source(),
-            Error::Tls(ref err) => err.source(),
-            Error::Base64(ref err) => err.source(),
+            Error::Io(ref err) => Some(err),
+            Error::Tls(ref err) => Some(err.as_ref()),
+            Error::Base64(ref err) => Some(err),
+            Error::Auth(ref err) => Some(err),
+            Error::MtaSts(ref err) => Some(err),
             _ => None,
         }
     }
@@ -197,27 +391,167 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Clone)]
 pub struct SmtpClientBuilder<T: AsRef<str> + PartialEq + Eq + Hash> {
     pub timeout: Duration,
-    pub tls_connector: TlsConnector,
+    pub tls_handshake_timeout: Duration,
+    pub greeting_timeout: Option<Duration>,
+    pub tls_config: Option<Arc<ClientConfig>>,
+    #[cfg(feature = "dane")]
+    pub dane: Option<Arc<smtp::dane::DaneVerifier>>,
+    pub allow_invalid_certs: bool,
+    pub tls_session_cache: Option<Arc<dyn ClientSessionStore>>,
     pub tls_hostname: T,
     pub tls_implicit: bool,
     pub credentials: Option<Credentials<T>>,
+    pub credentials_resolver: Option<CredentialsResolver<T>>,
+    pub auth_mechanisms: u64,
+    pub require_tls_for_auth: bool,
+    pub starttls_policy: StartTlsPolicy,
     pub addr: String,
+    pub addrs: Vec<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: Option<Duration>,
     pub is_lmtp: bool,
     pub say_ehlo: bool,
     pub local_host: String,
+    pub ehlo_ip_literal: bool,
+    pub normalize_line_endings: bool,
+    pub reset_on_error: bool,
+    pub login_initial_response: bool,
+    pub max_auth_rounds: usize,
+    pub add_missing_headers: bool,
+    pub max_rcpt_per_message: Option<usize>,
+    pub line_length_policy: LineLengthPolicy,
+    pub probe_before_send: bool,
+    pub mta_sts_policy: Option<Arc<smtp::mta_sts::Policy>>,
+    pub proxy_header: Option<ProxyHeader>,
+    pub on_wire: Option<WireObserver>,
+    pub(crate) tls_connector: OnceLock<TlsConnector>,
 }
 
 /// SMTP client builder
+///
+/// Extensions required by a command or a MAIL FROM parameter, such as
+/// CHUNKING, ATRN, DELIVERBY, FUTURERELEASE or MT-PRIORITY, are only checked
+/// against the capabilities cached by the last EHLO/LHLO exchange. When no
+/// capabilities are cached, for example on a client created with `new`, the
+/// command is sent as requested and the reply of the server decides.
 pub struct SmtpClient<T: AsyncRead + AsyncWrite> {
     pub stream: T,
     pub timeout: Duration,
+    pub normalize_line_endings: bool,
+    pub reset_on_error: bool,
+    pub login_initial_response: bool,
+    pub max_auth_rounds: usize,
+    pub add_missing_headers: bool,
+    pub max_rcpt_per_message: Option<usize>,
+    pub line_length_policy: LineLengthPolicy,
+    pub probe_before_send: bool,
+    pub require_tls_for_auth: bool,
+    pub starttls_policy: StartTlsPolicy,
+    pub(crate) auth_mechanism: Option<u64>,
+    pub(crate) capabilities: Option<EhloResponse<String>>,
+    pub(crate) extensions: Vec<String>,
+    pub(crate) greeting_delay: Duration,
+    pub(crate) partial_reply: Vec<u8>,
+    pub(crate) tls_kind: TlsKind,
+    pub(crate) on_wire: Option<WireObserver>,
+    pub(crate) in_flight: bool,
+    pub(crate) poisoned: bool,
 }
 
+/// Direction of the bytes passed to a [`WireObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Bytes read from the server.
+    Read,
+    /// Bytes written to the server.
+    Write,
+}
+
+/// Callback invoked with every chunk of plaintext SMTP data read from or
+/// written to the server.
+pub type WireObserver = Arc<dyn Fn(Direction, &[u8]) + Send + Sync>;
+
+/// Callback returning the credentials to use with a host, invoked with the
+/// hostname of the server before authenticating.
+pub type CredentialsResolver<T> = Arc<dyn Fn(&str) -> Option<Credentials<T>> + Send + Sync>;
+
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Credentials<T: AsRef<str> + PartialEq + Eq + Hash> {
-    Plain { username: T, secret: T },
-    OAuthBearer { token: T },
-    XOauth2 { username: T, secret: T },
+    Plain {
+        username: T,
+        secret: T,
+        /// Identity to act as once authenticated, sent only with PLAIN.
+        authzid: Option<T>,
+    },
+    OAuthBearer {
+        token: T,
+    },
+    XOauth2 {
+        username: T,
+        secret: T,
+    },
+    #[cfg(feature = "ntlm")]
+    Ntlm {
+        username: T,
+        secret: T,
+        domain: T,
+        workstation: T,
+    },
+}
+
+/// STARTTLS policy for connections not using implicit TLS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartTlsPolicy {
+    /// `connect` upgrades with STARTTLS while `connect_plain` stays in clear text.
+    #[default]
+    Auto,
+    /// Every non-implicit TLS connection must be upgraded with STARTTLS:
+    /// `connect_plain` is refused, and clients opened with `connect_bare_plain`
+    /// or `connect_over` fail with `MissingStartTls` when authenticating or
+    /// sending a message before the connection is secured.
+    Require,
+}
+
+/// How the connection to the server was secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TlsKind {
+    /// TLS was negotiated as soon as the connection was established.
+    Implicit,
+    /// The connection was upgraded with STARTTLS.
+    StartTls,
+    /// The connection is in clear text, or was secured by the caller.
+    #[default]
+    None,
+}
+
+/// How the server accepted a message, parsed from its final reply.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Accepted {
+    /// The server took responsibility for the message without reporting a
+    /// queue ID.
+    Delivered,
+    /// The server will forward the message elsewhere (251) or accepted it
+    /// without verifying the recipient (252).
+    Forwarded,
+    /// The server queued the message under the reported ID, e.g.
+    /// `250 2.0.0 Ok: queued as 4B2A1C`.
+    Queued(String),
+}
+
+/// Handling of message lines longer than the 998 octets allowed by
+/// RFC 5321, section 4.5.3.1.6.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineLengthPolicy {
+    /// Messages are sent as they are.
+    #[default]
+    Ignore,
+    /// Messages with an overlong line are refused with `Error::LineTooLong`
+    /// before the transaction is started.
+    Reject,
+    /// Overlong lines are broken with a CRLF, folding header lines. This
+    /// alters the content of body lines and is best used for plain text.
+    Wrap,
 }
 
 impl Default for Credentials<String> {
@@ -225,10 +559,23 @@ impl Default for Credentials<String> {
         Credentials::Plain {
             username: String::new(),
             secret: String::new(),
+            authzid: None,
         }
     }
 }
 
+impl Display for TimeoutPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TimeoutPhase::Connect => "connection",
+            TimeoutPhase::TlsHandshake => "TLS handshake",
+            TimeoutPhase::Greeting => "greeting",
+            TimeoutPhase::Command => "command",
+            TimeoutPhase::Data => "message transfer",
+        })
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -247,8 +594,40 @@ impl Display for Error {
                 f,
                 "The server does no support any of the available authentication methods"
             ),
-            Error::Timeout => write!(f, "Connection timeout"),
+            Error::Timeout { phase, received } if received.is_empty() => {
+                write!(f, "Timeout during {phase}")
+            }
+            Error::Timeout { phase, received } => write!(
+                f,
+                "Timeout during {phase}, received {:?}",
+                String::from_utf8_lossy(received)
+            ),
             Error::MissingStartTls => write!(f, "STARTTLS extension unavailable"),
+            Error::InsecureAuth => {
+                write!(f, "Refusing to authenticate over an unencrypted connection")
+            }
+            Error::InvalidAddress => write!(f, "Invalid e-mail address"),
+            Error::NotAttempted => write!(f, "Message not attempted"),
+            Error::UnsupportedExtension(e) => {
+                write!(f, "The server does not support the {e} extension")
+            }
+            Error::ParameterOutOfRange(e) => write!(
+                f,
+                "The {e} parameter exceeds the limits announced by the server"
+            ),
+            Error::NotTls => write!(f, "Server does not speak TLS on this port, try STARTTLS"),
+            Error::MessageTooLarge(Some(limit)) => {
+                write!(f, "Message exceeds the server size limit of {limit} bytes")
+            }
+            Error::MessageTooLarge(None) => write!(f, "Message exceeds the server size limit"),
+            Error::StartTlsRejected(e) => write!(f, "STARTTLS rejected: {e}"),
+            Error::EhloAfterTlsRejected(e) => write!(f, "EHLO after STARTTLS rejected: {e}"),
+            Error::MtaSts(e) => write!(f, "MTA-STS error: {e}"),
+            Error::Dns(e) => write!(f, "DNS error: {e}"),
+            Error::InvalidHeader => write!(f, "Invalid message header"),
+            Error::InvalidAttribute(e) => write!(f, "Invalid attribute name {e:?}"),
+            Error::LineTooLong => write!(f, "Message line exceeds 998 octets"),
+            Error::ConnectionClosed => write!(f, "Connection closed by the server"),
         }
     }
 }
@@ -264,3 +643,115 @@ impl From<base64::DecodeError> for Error {
         Error::Base64(err)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as _;
+
+    use smtp_proto::Response;
+
+    use crate::{smtp, Error, ErrorKind, TimeoutPhase};
+
+    /// Returns whether the variant wraps another error. This match is
+    /// intentionally exhaustive so that new variants must be classified.
+    fn has_source(err: &Error) -> bool {
+        match err {
+            Error::Io(_) | Error::Tls(_) | Error::Base64(_) | Error::Auth(_) | Error::MtaSts(_) => {
+                true
+            }
+            Error::UnparseableReply
+            | Error::UnexpectedReply(_)
+            | Error::AuthenticationFailed(_)
+            | Error::InvalidTLSName
+            | Error::MissingCredentials
+            | Error::MissingMailFrom
+            | Error::MissingRcptTo
+            | Error::UnsupportedAuthMechanism
+            | Error::Timeout { .. }
+            | Error::MissingStartTls
+            | Error::InsecureAuth
+            | Error::InvalidAddress
+            | Error::NotAttempted
+            | Error::UnsupportedExtension(_)
+            | Error::ParameterOutOfRange(_)
+            | Error::NotTls
+            | Error::MessageTooLarge(_)
+            | Error::StartTlsRejected(_)
+            | Error::EhloAfterTlsRejected(_)
+            | Error::Dns(_)
+            | Error::InvalidHeader
+            | Error::InvalidAttribute(_)
+            | Error::LineTooLong
+            | Error::ConnectionClosed => false,
+        }
+    }
+
+    fn all_errors() -> Vec<Error> {
+        vec![
+            Error::Io(std::io::Error::other("io")),
+            Error::Tls(Box::new(rustls::Error::HandshakeNotComplete)),
+            Error::Base64(base64::DecodeError::InvalidLength),
+            Error::Auth(smtp::auth::Error::InvalidChallenge),
+            Error::UnparseableReply,
+            Error::UnexpectedReply(Response::new(550, 5, 1, 1, "Unknown user".to_string())),
+            Error::AuthenticationFailed(Response::new(535, 5, 7, 8, "Bad".to_string())),
+            Error::InvalidTLSName,
+            Error::MissingCredentials,
+            Error::MissingMailFrom,
+            Error::MissingRcptTo,
+            Error::UnsupportedAuthMechanism,
+            Error::Timeout {
+                phase: TimeoutPhase::Data,
+                received: b"250-2.0.0".to_vec(),
+            },
+            Error::MissingStartTls,
+            Error::InsecureAuth,
+            Error::InvalidAddress,
+            Error::NotAttempted,
+            Error::UnsupportedExtension("FUTURERELEASE"),
+            Error::ParameterOutOfRange("HOLDFOR"),
+            Error::NotTls,
+            Error::MessageTooLarge(Some(1024)),
+            Error::StartTlsRejected(Response::new(454, 4, 7, 0, "TLS not available".to_string())),
+            Error::EhloAfterTlsRejected(Response::new(503, 5, 5, 1, "Bad sequence".to_string())),
+            Error::MtaSts(smtp::mta_sts::Error::MxMismatch),
+            Error::Dns("SERVFAIL".to_string()),
+            Error::InvalidHeader,
+            Error::InvalidAttribute("X=Y".to_string()),
+            Error::LineTooLong,
+            Error::ConnectionClosed,
+        ]
+    }
+
+    #[test]
+    fn error_display_and_source() {
+        let errors = all_errors();
+        let mut messages = std::collections::HashSet::new();
+        let mut kinds = std::collections::HashSet::new();
+
+        for err in &errors {
+            let message = err.to_string();
+            assert!(!message.is_empty(), "{err:?}");
+            assert_eq!(err.clone().to_string(), message, "{err:?}");
+            assert!(messages.insert(message), "Duplicate message for {err:?}");
+            assert_eq!(err.source().is_some(), has_source(err), "{err:?}");
+            assert!(kinds.insert(err.kind()), "Duplicate kind for {err:?}");
+            assert_eq!(err.clone().kind(), err.kind());
+        }
+    }
+
+    #[test]
+    fn error_kind() {
+        let timeout = Error::Timeout {
+            phase: TimeoutPhase::Greeting,
+            received: Vec::new(),
+        };
+        assert_eq!(timeout.kind(), ErrorKind::Timeout);
+        assert_ne!(timeout.kind(), ErrorKind::Io);
+        assert_eq!(timeout.to_string(), "Timeout during greeting");
+        assert_eq!(
+            Error::UnexpectedReply(Response::new(421, 4, 4, 2, "Bye".to_string())).kind(),
+            ErrorKind::UnexpectedReply
+        );
+    }
+}
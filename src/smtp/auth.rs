@@ -8,16 +8,16 @@
  * except according to those terms.
  */
 
-use std::{fmt::Display, hash::Hash};
+use std::{fmt::Display, hash::Hash, ops::BitOr, str::FromStr, sync::Arc};
 
 use base64::{engine, Engine};
 use smtp_proto::{
     response::generate::BitToString, EhloResponse, AUTH_CRAM_MD5, AUTH_DIGEST_MD5, AUTH_LOGIN,
-    AUTH_OAUTHBEARER, AUTH_PLAIN, AUTH_XOAUTH2,
+    AUTH_NTLM, AUTH_OAUTHBEARER, AUTH_PLAIN, AUTH_XOAUTH2,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::{Credentials, SmtpClient};
+use crate::{Credentials, SmtpClient, TlsKind};
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
     pub async fn authenticate<U>(
@@ -28,12 +28,24 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
     where
         U: AsRef<str> + PartialEq + Eq + Hash,
     {
+        // Credentials are never sent in clear text unless allowed
+        self.check_starttls_policy()?;
+        if self.require_tls_for_auth && self.tls_kind == TlsKind::None {
+            return Err(crate::Error::InsecureAuth);
+        }
+
         let credentials = credentials.as_ref();
         let capabilities = capabilities.as_ref();
         let mut available_mechanisms = match &credentials {
+            // Only PLAIN carries an authorization identity
+            Credentials::Plain {
+                authzid: Some(_), ..
+            } => AUTH_PLAIN,
             Credentials::Plain { .. } => AUTH_CRAM_MD5 | AUTH_DIGEST_MD5 | AUTH_LOGIN | AUTH_PLAIN,
             Credentials::OAuthBearer { .. } => AUTH_OAUTHBEARER,
             Credentials::XOauth2 { .. } => AUTH_XOAUTH2,
+            #[cfg(feature = "ntlm")]
+            Credentials::Ntlm { .. } => smtp_proto::AUTH_NTLM,
         } & capabilities.auth_mechanisms;
 
         // Try authenticating from most secure to least secure
@@ -45,6 +57,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
             available_mechanisms ^= mechanism;
             match self.auth(mechanism, credentials).await {
                 Ok(_) => {
+                    self.auth_mechanism = Some(mechanism);
                     return Ok(self);
                 }
                 Err(err) => match err {
@@ -65,6 +78,12 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         }
     }
 
+    /// Returns the SASL mechanism used in the last successful authentication,
+    /// as one of the `AUTH_*` flags defined in `smtp_proto`.
+    pub fn auth_mechanism(&self) -> Option<u64> {
+        self.auth_mechanism
+    }
+
     pub(crate) async fn auth<U>(
         &mut self,
         mechanism: u64,
@@ -73,29 +92,38 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
     where
         U: AsRef<str> + PartialEq + Eq + Hash,
     {
-        let mut reply = if (mechanism & (AUTH_PLAIN | AUTH_XOAUTH2 | AUTH_OAUTHBEARER)) != 0 {
-            self.cmd(
-                format!(
-                    "AUTH {} {}\r\n",
-                    mechanism.to_mechanism(),
-                    credentials.encode(mechanism, "")?,
+        // The username is sent as the initial response of LOGIN if enabled,
+        // the password then follows the first challenge.
+        let login_initial_response = mechanism == AUTH_LOGIN && self.login_initial_response;
+        let mut reply =
+            if (mechanism & (AUTH_PLAIN | AUTH_XOAUTH2 | AUTH_OAUTHBEARER | AUTH_NTLM)) != 0 {
+                self.cmd(
+                    format!(
+                        "AUTH {} {}\r\n",
+                        mechanism.to_mechanism(),
+                        credentials.encode(mechanism, "")?,
+                    )
+                    .as_bytes(),
                 )
-                .as_bytes(),
-            )
-            .await?
-        } else {
-            self.cmd(format!("AUTH {}\r\n", mechanism.to_mechanism()).as_bytes())
                 .await?
-        };
+            } else if login_initial_response {
+                self.cmd(format!("AUTH LOGIN {}\r\n", credentials.encode_login(0)?).as_bytes())
+                    .await?
+            } else {
+                self.cmd(format!("AUTH {}\r\n", mechanism.to_mechanism()).as_bytes())
+                    .await?
+            };
 
-        for _ in 0..3 {
+        for step in usize::from(login_initial_response)..self.max_auth_rounds {
             match reply.code() {
                 334 => {
+                    let response = if mechanism == AUTH_LOGIN {
+                        credentials.encode_login(step)?
+                    } else {
+                        credentials.encode(mechanism, reply.message())?
+                    };
                     reply = self
-                        .cmd(
-                            format!("{}\r\n", credentials.encode(mechanism, reply.message())?)
-                                .as_bytes(),
-                        )
+                        .cmd_secret(format!("{}\r\n", response).as_bytes())
                         .await?;
                 }
                 235 => {
@@ -111,15 +139,156 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
     }
 }
 
+/// Default maximum number of 334 continuations accepted during
+/// authentication, see `SmtpClient::max_auth_rounds`.
+pub(crate) const DEFAULT_MAX_AUTH_ROUNDS: usize = 16;
+
 #[derive(Debug, Clone)]
 pub enum Error {
     InvalidChallenge,
 }
 
+/// A password or token that is only exposed when credentials are encoded.
+/// Implement it on a wrapper around a secret type, such as
+/// `secrecy::SecretString`, to use it with [`SecretString`].
+pub trait Secret: Send + Sync {
+    fn expose_secret(&self) -> &str;
+}
+
+/// A shared secret string whose `Debug` output is redacted, for use as
+/// `Credentials<SecretString>`.
+#[derive(Clone)]
+pub struct SecretString(Arc<dyn Secret>);
+
+/// SASL mechanisms supported by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mechanism {
+    Plain,
+    Login,
+    CramMd5,
+    DigestMd5,
+    XOauth2,
+    OAuthBearer,
+    Ntlm,
+}
+
+impl Mechanism {
+    const ALL: [Mechanism; 7] = [
+        Mechanism::Plain,
+        Mechanism::Login,
+        Mechanism::CramMd5,
+        Mechanism::DigestMd5,
+        Mechanism::XOauth2,
+        Mechanism::OAuthBearer,
+        Mechanism::Ntlm,
+    ];
+
+    /// Returns the `AUTH_*` flag defined in `smtp_proto` for this mechanism.
+    pub fn to_flag(self) -> u64 {
+        match self {
+            Mechanism::Plain => AUTH_PLAIN,
+            Mechanism::Login => AUTH_LOGIN,
+            Mechanism::CramMd5 => AUTH_CRAM_MD5,
+            Mechanism::DigestMd5 => AUTH_DIGEST_MD5,
+            Mechanism::XOauth2 => AUTH_XOAUTH2,
+            Mechanism::OAuthBearer => AUTH_OAUTHBEARER,
+            Mechanism::Ntlm => AUTH_NTLM,
+        }
+    }
+
+    /// Returns the mechanisms set in a bitmask of `AUTH_*` flags, such as
+    /// the `auth_mechanisms` announced in an EHLO response. Flags of
+    /// mechanisms not supported by the client are skipped.
+    pub fn from_flags(flags: u64) -> impl Iterator<Item = Mechanism> {
+        Self::ALL
+            .into_iter()
+            .filter(move |mechanism| flags & mechanism.to_flag() != 0)
+    }
+
+    /// Returns the SASL name of the mechanism.
+    pub fn as_str(&self) -> &'static str {
+        self.to_flag().to_mechanism()
+    }
+}
+
+impl Display for Mechanism {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Mechanism {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mechanism| mechanism.as_str().eq_ignore_ascii_case(s))
+            .ok_or(crate::Error::UnsupportedAuthMechanism)
+    }
+}
+
+impl TryFrom<u64> for Mechanism {
+    type Error = crate::Error;
+
+    fn try_from(flag: u64) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|mechanism| mechanism.to_flag() == flag)
+            .ok_or(crate::Error::UnsupportedAuthMechanism)
+    }
+}
+
+impl From<Mechanism> for u64 {
+    fn from(mechanism: Mechanism) -> Self {
+        mechanism.to_flag()
+    }
+}
+
+impl BitOr for Mechanism {
+    type Output = u64;
+
+    fn bitor(self, rhs: Self) -> u64 {
+        self.to_flag() | rhs.to_flag()
+    }
+}
+
+impl BitOr<Mechanism> for u64 {
+    type Output = u64;
+
+    fn bitor(self, rhs: Mechanism) -> u64 {
+        self | rhs.to_flag()
+    }
+}
+
 impl<T: AsRef<str> + PartialEq + Eq + Hash> Credentials<T> {
     /// Creates a new `Credentials` instance.
     pub fn new(username: T, secret: T) -> Credentials<T> {
-        Credentials::Plain { username, secret }
+        Credentials::Plain {
+            username,
+            secret,
+            authzid: None,
+        }
+    }
+
+    /// Creates a new `Credentials` instance that authenticates as `username`
+    /// and then acts as `authzid`, e.g. a shared mailbox the user may send
+    /// as. The authorization identity is only supported by AUTH PLAIN.
+    pub fn new_with_authzid(username: T, secret: T, authzid: T) -> Credentials<T> {
+        Credentials::Plain {
+            username,
+            secret,
+            authzid: Some(authzid),
+        }
+    }
+
+    /// Sets the authorization identity of username and password credentials,
+    /// other credentials are returned as is.
+    pub fn with_authzid(mut self, authzid: T) -> Credentials<T> {
+        if let Credentials::Plain { authzid: value, .. } = &mut self {
+            *value = Some(authzid);
+        }
+        self
     }
 
     /// Creates a new XOAuth2 `Credentials` instance.
@@ -139,14 +308,42 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> Credentials<T> {
         }
     }
 
+    /// Creates a new NTLM `Credentials` instance.
+    #[cfg(feature = "ntlm")]
+    pub fn new_ntlm(username: T, secret: T, domain: T, workstation: T) -> Credentials<T> {
+        Credentials::Ntlm {
+            username,
+            secret,
+            domain,
+            workstation,
+        }
+    }
+
     pub fn encode(&self, mechanism: u64, challenge: &str) -> crate::Result<String> {
         Ok(engine::general_purpose::STANDARD.encode(
             match (mechanism, self) {
-                (AUTH_PLAIN, Credentials::Plain { username, secret }) => {
-                    format!("\u{0}{}\u{0}{}", username.as_ref(), secret.as_ref())
+                (
+                    AUTH_PLAIN,
+                    Credentials::Plain {
+                        username,
+                        secret,
+                        authzid,
+                    },
+                ) => {
+                    format!(
+                        "{}\u{0}{}\u{0}{}",
+                        authzid.as_ref().map_or("", |authzid| authzid.as_ref()),
+                        username.as_ref(),
+                        secret.as_ref()
+                    )
                 }
 
-                (AUTH_LOGIN, Credentials::Plain { username, secret }) => {
+                (
+                    AUTH_LOGIN,
+                    Credentials::Plain {
+                        username, secret, ..
+                    },
+                ) => {
                     let challenge = engine::general_purpose::STANDARD.decode(challenge)?;
                     let username = username.as_ref();
                     let secret = secret.as_ref();
@@ -170,7 +367,12 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> Credentials<T> {
                 }
 
                 #[cfg(feature = "digest-md5")]
-                (AUTH_DIGEST_MD5, Credentials::Plain { username, secret }) => {
+                (
+                    AUTH_DIGEST_MD5,
+                    Credentials::Plain {
+                        username, secret, ..
+                    },
+                ) => {
                     let mut buf = Vec::with_capacity(10);
                     let mut key = None;
                     let mut in_quote = false;
@@ -227,7 +429,7 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> Credentials<T> {
                         md5::compute(format!("{username}:{realm}:{secret}").as_bytes());
 
                     let a2 = md5::compute(
-                        if values.get("qpop").map_or(false, |v| v == "auth") {
+                        if values.get("qpop").is_some_and(|v| v == "auth") {
                             format!("AUTHENTICATE:{digest_uri}")
                         } else {
                             format!("AUTHENTICATE:{digest_uri}:00000000000000000000000000000000")
@@ -271,7 +473,12 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> Credentials<T> {
                 }
 
                 #[cfg(feature = "cram-md5")]
-                (AUTH_CRAM_MD5, Credentials::Plain { username, secret }) => {
+                (
+                    AUTH_CRAM_MD5,
+                    Credentials::Plain {
+                        username, secret, ..
+                    },
+                ) => {
                     let mut secret_opad: Vec<u8> = vec![0x5c; 64];
                     let mut secret_ipad: Vec<u8> = vec![0x36; 64];
                     let username = username.as_ref();
@@ -304,11 +511,199 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> Credentials<T> {
                 (AUTH_OAUTHBEARER, Credentials::OAuthBearer { token }) => {
                     token.as_ref().to_string()
                 }
+
+                #[cfg(feature = "ntlm")]
+                (
+                    AUTH_NTLM,
+                    Credentials::Ntlm {
+                        username,
+                        secret,
+                        domain,
+                        workstation,
+                    },
+                ) => {
+                    // The negotiate message is sent as the initial response and
+                    // the authenticate message in reply to the server challenge.
+                    return Ok(
+                        engine::general_purpose::STANDARD.encode(if challenge.is_empty() {
+                            super::ntlm::negotiate_message()
+                        } else {
+                            super::ntlm::authenticate_message(
+                                username.as_ref(),
+                                secret.as_ref(),
+                                domain.as_ref(),
+                                workstation.as_ref(),
+                                &engine::general_purpose::STANDARD.decode(challenge)?,
+                            )?
+                        }),
+                    );
+                }
                 _ => return Err(crate::Error::UnsupportedAuthMechanism),
             }
             .as_bytes(),
         ))
     }
+
+    /// Encodes the response to the LOGIN challenge at the provided step. The
+    /// prompt text is ignored: the username is sent first and the password
+    /// second, as servers word (and localize) the prompts differently.
+    pub(crate) fn encode_login(&self, step: usize) -> crate::Result<String> {
+        match (step, self) {
+            (0, Credentials::Plain { username, .. }) => {
+                Ok(engine::general_purpose::STANDARD.encode(username.as_ref()))
+            }
+            (1, Credentials::Plain { secret, .. }) => {
+                Ok(engine::general_purpose::STANDARD.encode(secret.as_ref()))
+            }
+            (_, Credentials::Plain { .. }) => Err(Error::InvalidChallenge.into()),
+            _ => Err(crate::Error::UnsupportedAuthMechanism),
+        }
+    }
+}
+
+impl Credentials<SecretString> {
+    /// Creates a new `Credentials` instance with a secret that is only
+    /// exposed when encoded.
+    pub fn new_secret(username: impl Into<String>, secret: impl Secret + 'static) -> Self {
+        Credentials::Plain {
+            username: SecretString::new(username.into()),
+            secret: SecretString::new(secret),
+            authzid: None,
+        }
+    }
+
+    /// Creates a new XOAuth2 `Credentials` instance with a secret that is
+    /// only exposed when encoded.
+    pub fn new_xoauth2_secret(username: impl Into<String>, secret: impl Secret + 'static) -> Self {
+        Credentials::XOauth2 {
+            username: SecretString::new(username.into()),
+            secret: SecretString::new(secret),
+        }
+    }
+
+    /// Creates a new OAuthBearer `Credentials` instance with a payload that
+    /// is only exposed when encoded.
+    pub fn new_oauth_secret(payload: impl Secret + 'static) -> Self {
+        Credentials::OAuthBearer {
+            token: SecretString::new(payload),
+        }
+    }
+}
+
+impl SecretString {
+    pub fn new(secret: impl Secret + 'static) -> Self {
+        SecretString(Arc::new(secret))
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        SecretString::new(value)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        SecretString::new(value.to_string())
+    }
+}
+
+impl Secret for String {
+    fn expose_secret(&self) -> &str {
+        self
+    }
+}
+
+impl Secret for &'static str {
+    fn expose_secret(&self) -> &str {
+        self
+    }
+}
+
+impl Secret for Box<str> {
+    fn expose_secret(&self) -> &str {
+        self
+    }
+}
+
+impl Secret for SecretString {
+    fn expose_secret(&self) -> &str {
+        self.0.expose_secret()
+    }
+}
+
+impl AsRef<str> for SecretString {
+    fn as_ref(&self) -> &str {
+        self.0.expose_secret()
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.expose_secret() == other.0.expose_secret()
+    }
+}
+
+impl Eq for SecretString {}
+
+impl Hash for SecretString {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.expose_secret().hash(state);
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretString(\"***\")")
+    }
+}
+
+impl<T: AsRef<str> + PartialEq + Eq + Hash> std::fmt::Debug for Credentials<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Plain {
+                username,
+                authzid: None,
+                ..
+            } => f
+                .debug_struct("Plain")
+                .field("username", &username.as_ref())
+                .field("secret", &"***")
+                .finish(),
+            Credentials::Plain {
+                username,
+                authzid: Some(authzid),
+                ..
+            } => f
+                .debug_struct("Plain")
+                .field("username", &username.as_ref())
+                .field("secret", &"***")
+                .field("authzid", &authzid.as_ref())
+                .finish(),
+            Credentials::OAuthBearer { .. } => f
+                .debug_struct("OAuthBearer")
+                .field("token", &"***")
+                .finish(),
+            Credentials::XOauth2 { username, .. } => f
+                .debug_struct("XOauth2")
+                .field("username", &username.as_ref())
+                .field("secret", &"***")
+                .finish(),
+            #[cfg(feature = "ntlm")]
+            Credentials::Ntlm {
+                username,
+                domain,
+                workstation,
+                ..
+            } => f
+                .debug_struct("Ntlm")
+                .field("username", &username.as_ref())
+                .field("secret", &"***")
+                .field("domain", &domain.as_ref())
+                .field("workstation", &workstation.as_ref())
+                .finish(),
+        }
+    }
 }
 
 impl<'x> From<(&'x str, &'x str)> for Credentials<&'x str> {
@@ -316,6 +711,7 @@ impl<'x> From<(&'x str, &'x str)> for Credentials<&'x str> {
         Credentials::Plain {
             username: credentials.0,
             secret: credentials.1,
+            authzid: None,
         }
     }
 }
@@ -325,6 +721,7 @@ impl From<(String, String)> for Credentials<String> {
         Credentials::Plain {
             username: credentials.0,
             secret: credentials.1,
+            authzid: None,
         }
     }
 }
@@ -343,12 +740,24 @@ impl Display for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
 
-    use smtp_proto::{AUTH_CRAM_MD5, AUTH_DIGEST_MD5, AUTH_LOGIN, AUTH_PLAIN, AUTH_XOAUTH2};
+    use smtp_proto::{
+        AUTH_CRAM_MD5, AUTH_DIGEST_MD5, AUTH_LOGIN, AUTH_NTLM, AUTH_PLAIN, AUTH_XOAUTH2,
+    };
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 
-    use crate::smtp::auth::Credentials;
+    use crate::{
+        smtp::{
+            auth::{Credentials, Mechanism, Secret, SecretString},
+            mock::MockServer,
+        },
+        SmtpClient, SmtpClientBuilder,
+    };
 
     #[test]
     fn auth_encode() {
@@ -422,4 +831,286 @@ mod test {
             "AHRpbQB0YW5zdGFhZnRhbnN0YWFm"
         );
     }
+
+    #[tokio::test]
+    async fn auth_login_prompts() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server_stream);
+            let mut lines = Vec::new();
+            // "Username:" and a localized password prompt
+            for reply in [
+                "334 VXNlcm5hbWU6\r\n",
+                "334 Q29udHJhc2XxYTo=\r\n",
+                "235 OK\r\n",
+            ] {
+                let mut line = String::new();
+                server.read_line(&mut line).await.unwrap();
+                lines.push(line);
+                server.write_all(reply.as_bytes()).await.unwrap();
+            }
+            lines
+        });
+
+        let mut client = SmtpClient::new(client_stream, Duration::from_secs(30));
+        client
+            .auth(AUTH_LOGIN, &Credentials::new("tim", "tanstaaftanstaaf"))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            ["AUTH LOGIN\r\n", "dGlt\r\n", "dGFuc3RhYWZ0YW5zdGFhZg==\r\n"]
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_login_initial_response() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server_stream);
+            let mut lines = Vec::new();
+            for reply in ["334 UGFzc3dvcmQ6\r\n", "235 OK\r\n"] {
+                let mut line = String::new();
+                server.read_line(&mut line).await.unwrap();
+                lines.push(line);
+                server.write_all(reply.as_bytes()).await.unwrap();
+            }
+            lines
+        });
+
+        let mut client = SmtpClient::new(client_stream, Duration::from_secs(30));
+        client.login_initial_response = true;
+        client
+            .auth(AUTH_LOGIN, &Credentials::new("tim", "tanstaaftanstaaf"))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            ["AUTH LOGIN dGlt\r\n", "dGFuc3RhYWZ0YW5zdGFhZg==\r\n"]
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_requires_tls() {
+        let (mut client, server) = MockServer::new()
+            .capabilities(["AUTH PLAIN"])
+            .connect()
+            .await
+            .unwrap();
+        client.require_tls_for_auth = true;
+        let capabilities = client.ehlo("client.example.com").await.unwrap();
+        assert!(matches!(
+            client
+                .authenticate(Credentials::new("john", "secret"), &capabilities)
+                .await,
+            Err(crate::Error::InsecureAuth)
+        ));
+        client.quit().await.unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap().commands,
+            ["EHLO client.example.com", "QUIT"]
+        );
+
+        // The caller is responsible for the security of streams passed to
+        // connect_over
+        let (addr, server) = MockServer::new()
+            .capabilities(["AUTH PLAIN"])
+            .expect("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0 Accepted")
+            .listen()
+            .await
+            .unwrap();
+        let client = SmtpClientBuilder::new("mx.example.com", addr.port())
+            .helo_host("client.example.com")
+            .credentials(("john", "secret"))
+            .connect_over(tokio::net::TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(client.auth_mechanism(), Some(AUTH_PLAIN));
+        client.quit().await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn secret_credentials() {
+        // A secret kept by the application, exposed only when encoded
+        struct Vault(&'static str);
+
+        impl Secret for Vault {
+            fn expose_secret(&self) -> &str {
+                self.0
+            }
+        }
+
+        let credentials = Credentials::new_secret("tim", Vault("tanstaaftanstaaf"));
+        assert_eq!(
+            credentials.encode(AUTH_PLAIN, "").unwrap(),
+            "AHRpbQB0YW5zdGFhZnRhbnN0YWFm"
+        );
+        assert_eq!(
+            format!("{:?}", SecretString::new(Vault("tanstaaftanstaaf"))),
+            "SecretString(\"***\")"
+        );
+        assert!(credentials == Credentials::new_secret("tim", "tanstaaftanstaaf"));
+
+        let (addr, server) = MockServer::new()
+            .capabilities(["AUTH PLAIN"])
+            .expect(
+                "AUTH PLAIN AHRpbQB0YW5zdGFhZnRhbnN0YWFm",
+                "235 2.7.0 Accepted",
+            )
+            .listen()
+            .await
+            .unwrap();
+        let mut builder = SmtpClientBuilder::new(SecretString::from("127.0.0.1"), addr.port())
+            .require_tls_for_auth(false)
+            .helo_host("client.example.com")
+            .credentials(credentials);
+        builder.addr = addr.to_string();
+        builder.connect_plain().await.unwrap().quit().await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn auth_plain_authzid() {
+        let credentials =
+            Credentials::new_with_authzid("tim", "tanstaaftanstaaf", "sales@example.com");
+        assert_eq!(
+            credentials.encode(AUTH_PLAIN, "").unwrap(),
+            "c2FsZXNAZXhhbXBsZS5jb20AdGltAHRhbnN0YWFmdGFuc3RhYWY="
+        );
+        assert_eq!(
+            format!("{credentials:?}"),
+            "Plain { username: \"tim\", secret: \"***\", authzid: \"sales@example.com\" }"
+        );
+
+        // LOGIN cannot carry the authorization identity and is not tried
+        let (addr, server) = MockServer::new()
+            .capabilities(["AUTH LOGIN PLAIN"])
+            .expect(
+                "AUTH PLAIN c2FsZXNAZXhhbXBsZS5jb20AdGltAHRhbnN0YWFmdGFuc3RhYWY=",
+                "235 2.7.0 Accepted",
+            )
+            .listen()
+            .await
+            .unwrap();
+        let mut builder = SmtpClientBuilder::new("127.0.0.1", addr.port())
+            .require_tls_for_auth(false)
+            .helo_host("client.example.com")
+            .credentials(("tim", "tanstaaftanstaaf"))
+            .authzid("sales@example.com");
+        builder.addr = addr.to_string();
+        assert_eq!(builder.credentials, Some(credentials));
+        let client = builder.connect_plain().await.unwrap();
+        assert_eq!(client.auth_mechanism(), Some(AUTH_PLAIN));
+        client.quit().await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn redacted_debug() {
+        assert_eq!(
+            format!("{:?}", Credentials::new("john", "p4ssw0rd")),
+            "Plain { username: \"john\", secret: \"***\" }"
+        );
+        assert_eq!(
+            format!("{:?}", Credentials::new_xoauth2("john", "p4ssw0rd")),
+            "XOauth2 { username: \"john\", secret: \"***\" }"
+        );
+        assert_eq!(
+            format!("{:?}", Credentials::new_oauth("p4ssw0rd")),
+            "OAuthBearer { token: \"***\" }"
+        );
+
+        let builder = SmtpClientBuilder::new("smtp.example.com", 587)
+            .credentials(("john", "p4ssw0rd"))
+            .helo_host("client.example.com");
+        let debug = format!("{builder:?}");
+        assert!(!debug.contains("p4ssw0rd"), "{debug}");
+        assert!(debug.contains("username: \"john\""), "{debug}");
+        assert!(
+            debug.contains("tls_hostname: \"smtp.example.com\""),
+            "{debug}"
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_rounds_exhausted() {
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server_stream);
+            let mut lines = 0;
+            let mut line = String::new();
+            while server.read_line(&mut line).await.unwrap_or(0) > 0 {
+                lines += 1;
+                line.clear();
+                server.write_all(b"334 \r\n").await.unwrap();
+            }
+            lines
+        });
+
+        let mut client = SmtpClient::new(client_stream, Duration::from_secs(30));
+        let result = client
+            .auth(AUTH_PLAIN, &Credentials::new("tim", "tanstaaftanstaaf"))
+            .await;
+        assert!(matches!(result, Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 334));
+        drop(client);
+        assert_eq!(server.await.unwrap(), 1 + super::DEFAULT_MAX_AUTH_ROUNDS);
+    }
+
+    #[tokio::test]
+    async fn auth_rounds_limit() {
+        // A server sending five challenges before accepting the credentials
+        let server = |server_stream| async move {
+            let mut server = BufReader::new(server_stream);
+            let mut line = String::new();
+            for reply in ["334 \r\n"; 5].into_iter().chain(["235 OK\r\n"]) {
+                line.clear();
+                if server.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    break;
+                }
+                server.write_all(reply.as_bytes()).await.unwrap();
+            }
+        };
+
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let server_task = tokio::spawn(server(server_stream));
+        let mut client = SmtpClient::new(client_stream, Duration::from_secs(30));
+        client
+            .auth(AUTH_PLAIN, &Credentials::new("tim", "tanstaaftanstaaf"))
+            .await
+            .unwrap();
+        server_task.await.unwrap();
+
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let server_task = tokio::spawn(server(server_stream));
+        let mut client = SmtpClient::new(client_stream, Duration::from_secs(30));
+        client.max_auth_rounds = 3;
+        assert!(matches!(
+            client
+                .auth(AUTH_PLAIN, &Credentials::new("tim", "tanstaaftanstaaf"))
+                .await,
+            Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 334
+        ));
+        drop(client);
+        server_task.await.unwrap();
+    }
+
+    #[test]
+    fn mechanism_names() {
+        for mechanism in Mechanism::ALL {
+            assert_eq!(mechanism.as_str().parse::<Mechanism>().unwrap(), mechanism);
+            assert_eq!(Mechanism::try_from(mechanism.to_flag()).unwrap(), mechanism);
+        }
+        assert_eq!("cram-md5".parse::<Mechanism>().unwrap(), Mechanism::CramMd5);
+        assert_eq!(Mechanism::XOauth2.to_string(), "XOAUTH2");
+        assert!("SCRAM-SHA-256".parse::<Mechanism>().is_err());
+        assert!(Mechanism::try_from(AUTH_PLAIN | AUTH_LOGIN).is_err());
+
+        let flags = Mechanism::Plain | Mechanism::Login | Mechanism::Ntlm;
+        assert_eq!(flags, AUTH_PLAIN | AUTH_LOGIN | AUTH_NTLM);
+        assert_eq!(
+            Mechanism::from_flags(flags | smtp_proto::AUTH_SCRAM_SHA_256).collect::<Vec<_>>(),
+            [Mechanism::Plain, Mechanism::Login, Mechanism::Ntlm]
+        );
+    }
 }
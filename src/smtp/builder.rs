@@ -8,40 +8,123 @@
  * except according to those terms.
  */
 
+use rustls::{
+    client::{ClientSessionStore, Resumption},
+    ClientConfig,
+};
 use smtp_proto::{EhloResponse, EXT_START_TLS};
+use std::borrow::Cow;
 use std::hash::Hash;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
-use tokio_rustls::client::TlsStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{
+    Credentials, Direction, LineLengthPolicy, SmtpClient, SmtpClientBuilder, StartTlsPolicy,
+    TimeoutPhase, TlsKind,
+};
 
-use crate::{Credentials, SmtpClient, SmtpClientBuilder};
+use super::{auth::DEFAULT_MAX_AUTH_ROUNDS, mta_sts, proxy::ProxyHeader};
 
-use super::{tls::build_tls_connector, AssertReply};
+use super::{client::connect_from, tls::shared_tls_config, AssertReply};
 
 impl<T: AsRef<str> + PartialEq + Eq + Hash> SmtpClientBuilder<T> {
     pub fn new(hostname: T, port: u16) -> Self {
         SmtpClientBuilder {
-            addr: format!("{}:{}", hostname.as_ref(), port),
-            timeout: Duration::from_secs(60 * 60),
-            tls_connector: build_tls_connector(false),
+            addr: if hostname.as_ref().parse::<std::net::Ipv6Addr>().is_ok() {
+                format!("[{}]:{}", hostname.as_ref(), port)
+            } else {
+                format!("{}:{}", hostname.as_ref(), port)
+            },
+            addrs: Vec::new(),
+            local_addr: None,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            timeout: Duration::from_secs(60),
+            tls_handshake_timeout: Duration::from_secs(30),
+            greeting_timeout: None,
+            tls_config: None,
+            #[cfg(feature = "dane")]
+            dane: None,
+            allow_invalid_certs: false,
+            tls_session_cache: None,
             tls_hostname: hostname,
             tls_implicit: true,
             is_lmtp: false,
-            local_host: gethostname::gethostname()
-                .to_str()
-                .unwrap_or("[127.0.0.1]")
-                .to_string(),
+            local_host: system_hostname(),
             credentials: None,
+            credentials_resolver: None,
+            auth_mechanisms: u64::MAX,
+            require_tls_for_auth: true,
+            starttls_policy: StartTlsPolicy::Auto,
             say_ehlo: true,
+            ehlo_ip_literal: false,
+            normalize_line_endings: false,
+            reset_on_error: false,
+            login_initial_response: false,
+            max_auth_rounds: DEFAULT_MAX_AUTH_ROUNDS,
+            add_missing_headers: false,
+            max_rcpt_per_message: None,
+            line_length_policy: LineLengthPolicy::Ignore,
+            probe_before_send: false,
+            mta_sts_policy: None,
+            proxy_header: None,
+            on_wire: None,
+            tls_connector: OnceLock::new(),
         }
     }
 
-    /// Allow invalid TLS certificates
+    /// Allow invalid TLS certificates. Ignored when a configuration is
+    /// provided with `tls_config` or the server is authenticated with DANE.
     pub fn allow_invalid_certs(mut self) -> Self {
-        self.tls_connector = build_tls_connector(true);
+        self.allow_invalid_certs = true;
+        self.tls_connector = OnceLock::new();
+        self
+    }
+
+    /// Store TLS sessions in the provided cache instead of the in-memory
+    /// cache of the default TLS configuration, which is shared by all the
+    /// builders that do not override it. The cache is also used with a
+    /// configuration provided with `tls_config` or DANE.
+    pub fn tls_session_cache(mut self, cache: Arc<dyn ClientSessionStore>) -> Self {
+        self.tls_session_cache = Some(cache);
+        self.tls_connector = OnceLock::new();
+        self
+    }
+
+    /// Use a caller-provided rustls configuration for TLS connections.
+    /// `allow_invalid_certs` is ignored, whether it is called before or
+    /// after, while a cache set with `tls_session_cache` replaces the
+    /// session storage of the configuration.
+    pub fn tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls_config = Some(config);
+        self.tls_connector = OnceLock::new();
+        self
+    }
+
+    /// Authenticate the server with its DANE TLSA records (RFC 7672), as
+    /// returned by [`super::dane::TlsaResolver`]. DANE takes precedence over
+    /// `tls_config` and `allow_invalid_certs` regardless of the order they
+    /// are called in. The setting is left unchanged when none of the records
+    /// is usable.
+    #[cfg(feature = "dane")]
+    pub fn dane(mut self, records: Vec<super::dane::Tlsa>) -> Self {
+        if records.iter().any(super::dane::Tlsa::is_usable) {
+            self.dane = Some(Arc::new(super::dane::DaneVerifier::new(records)));
+            self.tls_connector = OnceLock::new();
+        }
+        self
+    }
+
+    /// Set the hostname sent as SNI and used to validate the server
+    /// certificate, while still dialing the address passed to `new`
+    pub fn tls_sni(mut self, name: T) -> Self {
+        self.tls_hostname = name;
         self
     }
 
@@ -51,6 +134,29 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> SmtpClientBuilder<T> {
         self
     }
 
+    /// Select the TLS mode from the server port: implicit TLS for port 465
+    /// (submissions, RFC 8314) and STARTTLS for ports 25 (SMTP) and 587
+    /// (submission). Other ports keep the current setting. The heuristic is
+    /// applied when called, so a later call to `implicit_tls` overrides it.
+    pub fn auto_tls(mut self) -> Self {
+        match self
+            .addr
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse::<u16>().ok())
+        {
+            Some(465) => self.tls_implicit = true,
+            Some(25 | 587) => self.tls_implicit = false,
+            _ => (),
+        }
+        self
+    }
+
+    /// Sets the STARTTLS policy for connections not using implicit TLS
+    pub fn starttls(mut self, policy: StartTlsPolicy) -> Self {
+        self.starttls_policy = policy;
+        self
+    }
+
     /// Use LMTP instead of SMTP
     pub fn lmtp(mut self, is_lmtp: bool) -> Self {
         self.is_lmtp = is_lmtp;
@@ -63,52 +169,265 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> SmtpClientBuilder<T> {
         self
     }
 
-    /// Set the EHLO/LHLO hostname
+    /// Set the EHLO/LHLO hostname. Defaults to the system hostname or, if it
+    /// is not a valid domain name, an address literal of the local socket
+    /// address.
     pub fn helo_host(mut self, host: impl Into<String>) -> Self {
         self.local_host = host.into();
         self
     }
 
+    /// Convert bare CR and LF line endings in message bodies to CRLF
+    pub fn normalize_line_endings(mut self, normalize_line_endings: bool) -> Self {
+        self.normalize_line_endings = normalize_line_endings;
+        self
+    }
+
+    /// Prepend `Date` and `Message-ID` headers to messages that lack them
+    pub fn add_missing_headers(mut self, add_missing_headers: bool) -> Self {
+        self.add_missing_headers = add_missing_headers;
+        self
+    }
+
+    /// Check message lines against the 998 octet limit of RFC 5321,
+    /// either refusing or wrapping messages with longer lines
+    pub fn line_length_policy(mut self, policy: LineLengthPolicy) -> Self {
+        self.line_length_policy = policy;
+        self
+    }
+
+    /// Send a NOOP before each message to detect connections closed by the
+    /// server while idle, which fail with `Error::ConnectionClosed`
+    pub fn probe_before_send(mut self, probe_before_send: bool) -> Self {
+        self.probe_before_send = probe_before_send;
+        self
+    }
+
+    /// Split messages with more recipients than `max` into several
+    /// transactions, for servers that limit the recipients per message
+    pub fn max_rcpt_per_message(mut self, max: usize) -> Self {
+        self.max_rcpt_per_message = Some(max);
+        self
+    }
+
+    /// Apply the MTA-STS policy of the recipient domain to connections to
+    /// this MX host. In `enforce` mode, connecting fails if the host is not
+    /// listed in the policy or the connection would not use TLS with a
+    /// validated certificate; in `testing` mode failures are only logged.
+    pub fn mta_sts_policy(mut self, policy: Arc<mta_sts::Policy>) -> Self {
+        self.mta_sts_policy = Some(policy);
+        self
+    }
+
+    /// Send a RSET after a recoverable `send` failure so that the connection
+    /// can be used for the next message. Failures are recoverable when the
+    /// server rejects the transaction with a reply other than 421 or the
+    /// message is refused locally (e.g. an invalid address); I/O, TLS,
+    /// timeout and unparseable reply errors are not.
+    pub fn reset_on_error(mut self, reset_on_error: bool) -> Self {
+        self.reset_on_error = reset_on_error;
+        self
+    }
+
+    /// Send the username with `AUTH LOGIN` as an initial response (RFC 4954),
+    /// saving a round trip. Disabled by default as some servers only accept
+    /// LOGIN with the username sent after the first challenge.
+    pub fn login_initial_response(mut self, login_initial_response: bool) -> Self {
+        self.login_initial_response = login_initial_response;
+        self
+    }
+
+    /// Maximum number of 334 continuations accepted during authentication
+    /// before giving up with `UnexpectedReply` (16 by default), which bounds
+    /// mechanisms with a variable number of steps.
+    pub fn max_auth_rounds(mut self, max_auth_rounds: usize) -> Self {
+        self.max_auth_rounds = max_auth_rounds;
+        self
+    }
+
+    /// Send a PROXY protocol header at the start of every connection
+    pub fn send_proxy_header(mut self, header: ProxyHeader) -> Self {
+        self.proxy_header = Some(header);
+        self
+    }
+
     /// Sets the authentication credentials
     pub fn credentials(mut self, credentials: impl Into<Credentials<T>>) -> Self {
         self.credentials = Some(credentials.into());
         self
     }
 
-    /// Sets the SMTP connection timeout
+    /// Sets a callback that returns the credentials for the server's
+    /// hostname, allowing a single builder to serve hosts that require
+    /// different credentials. It is only invoked when no credentials were
+    /// set with `credentials`, and the session is not authenticated if it
+    /// returns `None`.
+    pub fn credentials_resolver(
+        mut self,
+        resolver: impl Fn(&str) -> Option<Credentials<T>> + Send + Sync + 'static,
+    ) -> Self {
+        self.credentials_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Replace the token of XOAUTH2 or OAUTHBEARER credentials, keeping the
+    /// rest of the configuration, e.g. to refresh an expired token before
+    /// reconnecting. For OAUTHBEARER the value replaces the whole payload as
+    /// passed to `Credentials::new_oauth`. Other credentials are left as is.
+    pub fn with_token(mut self, token: T) -> Self {
+        match &mut self.credentials {
+            Some(Credentials::XOauth2 { secret, .. }) => *secret = token,
+            Some(Credentials::OAuthBearer { token: payload }) => *payload = token,
+            _ => (),
+        }
+        self
+    }
+
+    /// Set the SASL authorization identity to act as after authenticating
+    /// with username and password credentials, e.g. to send as a shared
+    /// mailbox. Only AUTH PLAIN carries it, other mechanisms are not tried
+    /// once it is set. Must be called after `credentials`.
+    pub fn authzid(mut self, authzid: T) -> Self {
+        self.credentials = self
+            .credentials
+            .map(|credentials| credentials.with_authzid(authzid));
+        self
+    }
+
+    /// Restricts the SASL mechanisms that may be used during authentication
+    /// to those included in the provided `AUTH_*` bitmask, which may also be
+    /// built from `Mechanism` values (e.g. `Mechanism::Plain | Mechanism::Login`).
+    pub fn allowed_mechanisms(mut self, mechanisms: impl Into<u64>) -> Self {
+        self.auth_mechanisms = mechanisms.into();
+        self
+    }
+
+    /// Require TLS before sending credentials (enabled by default). The
+    /// setting is kept by the connected client, whose `authenticate` then
+    /// fails with `InsecureAuth` unless the connection was secured with
+    /// implicit TLS or STARTTLS. When disabled, `connect_plain` will
+    /// authenticate over clear text.
+    pub fn require_tls_for_auth(mut self, require_tls_for_auth: bool) -> Self {
+        self.require_tls_for_auth = require_tls_for_auth;
+        self
+    }
+
+    /// Disable Nagle's algorithm on the connection (`TCP_NODELAY`), which
+    /// lowers latency when exchanging short commands
+    pub fn tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    /// Enable TCP keepalive probes (`SO_KEEPALIVE`) after the connection has
+    /// been idle for the provided time, so that connections kept open between
+    /// messages are not silently dropped by NAT devices or firewalls. `None`
+    /// (the default) leaves the system setting in place.
+    pub fn tcp_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.tcp_keepalive = idle;
+        self
+    }
+
+    /// Connect to the provided addresses, tried in order, instead of
+    /// resolving the hostname passed to `new`. The hostname is still used
+    /// for TLS validation and SNI.
+    pub fn addrs(mut self, addrs: Vec<SocketAddr>) -> Self {
+        self.addrs = addrs;
+        self
+    }
+
+    /// Bind outgoing connections to the provided local address. A port of 0
+    /// selects an ephemeral port.
+    pub fn local_addr(mut self, local_addr: SocketAddr) -> Self {
+        self.local_addr = Some(local_addr);
+        self
+    }
+
+    /// Sets the SMTP connection timeout (60 seconds by default), which also
+    /// bounds each command and the transmission of a message
     pub fn timeout(mut self, timeout: Duration) -> Self {
         self.timeout = timeout;
         self
     }
 
+    /// Sets the maximum time allowed for the TLS handshake (30 seconds by
+    /// default)
+    pub fn tls_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.tls_handshake_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum time to wait for the server greeting, allowing to
+    /// fail fast on tarpitting servers without shortening the connection
+    /// timeout, which is used by default and still bounds the greeting
+    pub fn greeting_timeout(mut self, timeout: Duration) -> Self {
+        self.greeting_timeout = Some(timeout);
+        self
+    }
+
+    /// Use an address literal of the local socket address (e.g. `[192.0.2.1]`)
+    /// in EHLO/LHLO when the configured hostname is not fully qualified
+    pub fn ehlo_ip_literal(mut self, ehlo_ip_literal: bool) -> Self {
+        self.ehlo_ip_literal = ehlo_ip_literal;
+        self
+    }
+
+    /// Sets a callback that receives every chunk of plaintext SMTP data read
+    /// from or written to the server, after TLS decryption.
+    pub fn on_wire(mut self, observer: impl Fn(Direction, &[u8]) + Send + Sync + 'static) -> Self {
+        self.on_wire = Some(Arc::new(observer));
+        self
+    }
+
     /// Connect over TLS
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(addr = %self.addr), err(Display))
+    )]
     pub async fn connect(&self) -> crate::Result<SmtpClient<TlsStream<TcpStream>>> {
+        self.check_mta_sts(self.verifies_certs())?;
         tokio::time::timeout(self.timeout, async {
-            let mut client = SmtpClient {
-                stream: TcpStream::connect(&self.addr).await?,
-                timeout: self.timeout,
-            };
+            let stream = self.tcp_connect().await?;
+            let local_host = self.ehlo_hostname(&stream);
+            let mut client = self.bare_client(stream);
+            self.write_proxy_header(&mut client, self.tls_implicit.then_some(false))
+                .await?;
 
             let mut client = if self.tls_implicit {
                 let mut client = client
-                    .into_tls(&self.tls_connector, self.tls_hostname.as_ref())
+                    .into_tls_with_timeout(
+                        &self.tls_connector(),
+                        self.tls_hostname.as_ref(),
+                        self.tls_handshake_timeout,
+                        TlsKind::Implicit,
+                    )
                     .await?;
+                self.write_proxy_header(&mut client, Some(true)).await?;
                 // Read greeting
-                client.read().await?.assert_positive_completion()?;
+                self.read_greeting(&mut client).await?;
                 client
             } else {
                 // Read greeting
-                client.read().await?.assert_positive_completion()?;
+                self.read_greeting(&mut client).await?;
 
                 // Send EHLO
                 let response = if !self.is_lmtp {
-                    client.ehlo(&self.local_host).await?
+                    client.ehlo(&local_host).await?
                 } else {
-                    client.lhlo(&self.local_host).await?
+                    client.lhlo(&local_host).await?
                 };
                 if response.has_capability(EXT_START_TLS) {
+                    let response = client.cmd(b"STARTTLS\r\n").await?;
+                    if !response.is_positive_completion() {
+                        return Err(crate::Error::StartTlsRejected(response));
+                    }
                     client
-                        .start_tls(&self.tls_connector, self.tls_hostname.as_ref())
+                        .into_tls_with_timeout(
+                            &self.tls_connector(),
+                            self.tls_hostname.as_ref(),
+                            self.tls_handshake_timeout,
+                            TlsKind::StartTls,
+                        )
                         .await?
                 } else {
                     return Err(crate::Error::MissingStartTls);
@@ -117,44 +436,453 @@ impl<T: AsRef<str> + PartialEq + Eq + Hash> SmtpClientBuilder<T> {
 
             if self.say_ehlo {
                 // Obtain capabilities
-                let capabilities = client.capabilities(&self.local_host, self.is_lmtp).await?;
+                let mut capabilities = client
+                    .capabilities(&local_host, self.is_lmtp)
+                    .await
+                    .map_err(|err| match err {
+                        crate::Error::UnexpectedReply(reply) if !self.tls_implicit => {
+                            crate::Error::EhloAfterTlsRejected(reply)
+                        }
+                        err => err,
+                    })?;
+                capabilities.auth_mechanisms &= self.auth_mechanisms;
                 // Authenticate
-                if let Some(credentials) = &self.credentials {
-                    client.authenticate(&credentials, &capabilities).await?;
+                let resolved = self.resolve_credentials();
+                if let Some(credentials) = self.credentials.as_ref().or(resolved.as_ref()) {
+                    client.authenticate(credentials, &capabilities).await?;
                 }
             }
 
             Ok(client)
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .map_err(|_| crate::Error::Timeout {
+            phase: TimeoutPhase::Connect,
+            received: Vec::new(),
+        })?
     }
 
     /// Connect over clear text (should not be used)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(addr = %self.addr), err(Display))
+    )]
     pub async fn connect_plain(&self) -> crate::Result<SmtpClient<TcpStream>> {
-        let mut client = SmtpClient {
-            stream: tokio::time::timeout(self.timeout, async {
-                TcpStream::connect(&self.addr).await
-            })
+        if self.starttls_policy == StartTlsPolicy::Require {
+            return Err(crate::Error::MissingStartTls);
+        }
+        self.check_mta_sts(false)?;
+
+        let resolved = self.resolve_credentials();
+        let credentials = self.credentials.as_ref().or(resolved.as_ref());
+        if self.say_ehlo && credentials.is_some() && self.require_tls_for_auth {
+            return Err(crate::Error::InsecureAuth);
+        }
+
+        let stream = tokio::time::timeout(self.timeout, self.tcp_connect())
             .await
-            .map_err(|_| crate::Error::Timeout)??,
-            timeout: self.timeout,
+            .map_err(|_| crate::Error::Timeout {
+                phase: TimeoutPhase::Connect,
+                received: Vec::new(),
+            })??;
+
+        let local_host = self.ehlo_hostname(&stream);
+        self.handshake(self.bare_client(stream), &local_host, credentials)
+            .await
+    }
+
+    /// Verifies that the server is reachable by connecting as `connect`
+    /// does, authenticating if credentials are set, and closing the session
+    /// with QUIT. Returns the capabilities announced by the server.
+    pub async fn verify(&self) -> crate::Result<EhloResponse<String>> {
+        let mut client = self.connect().await?;
+        let capabilities = match client.capabilities.take() {
+            Some(capabilities) => capabilities,
+            None => {
+                let local_host = self.ehlo_hostname(client.stream.get_ref().0);
+                client.capabilities(&local_host, self.is_lmtp).await?
+            }
         };
+        client.quit().await?;
+        Ok(capabilities)
+    }
+
+    /// Verifies that the server is reachable as `verify` does, using a clear
+    /// text connection established as `connect_plain` does.
+    pub async fn verify_plain(&self) -> crate::Result<EhloResponse<String>> {
+        let mut client = self.connect_plain().await?;
+        let capabilities = match client.capabilities.take() {
+            Some(capabilities) => capabilities,
+            None => {
+                let local_host = self.ehlo_hostname(&client.stream);
+                client.capabilities(&local_host, self.is_lmtp).await?
+            }
+        };
+        client.quit().await?;
+        Ok(capabilities)
+    }
+
+    /// Establishes an implicit TLS connection and reads the greeting without
+    /// sending EHLO/LHLO or authenticating, leaving the rest of the session
+    /// to the caller.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(addr = %self.addr), err(Display))
+    )]
+    pub async fn connect_bare(&self) -> crate::Result<SmtpClient<TlsStream<TcpStream>>> {
+        self.check_mta_sts(self.verifies_certs())?;
+        tokio::time::timeout(self.timeout, async {
+            let mut client = self.bare_client(self.tcp_connect().await?);
+            self.write_proxy_header(&mut client, Some(false)).await?;
+            let mut client = client
+                .into_tls_with_timeout(
+                    &self.tls_connector(),
+                    self.tls_hostname.as_ref(),
+                    self.tls_handshake_timeout,
+                    TlsKind::Implicit,
+                )
+                .await?;
+            self.write_proxy_header(&mut client, Some(true)).await?;
+            self.read_greeting(&mut client).await?;
+            Ok(client)
+        })
+        .await
+        .map_err(|_| crate::Error::Timeout {
+            phase: TimeoutPhase::Connect,
+            received: Vec::new(),
+        })?
+    }
+
+    /// Establishes a clear text connection and reads the greeting without
+    /// sending EHLO/LHLO or authenticating. The caller may upgrade the
+    /// connection using `start_tls`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(addr = %self.addr), err(Display))
+    )]
+    pub async fn connect_bare_plain(&self) -> crate::Result<SmtpClient<TcpStream>> {
+        self.check_mta_sts(false)?;
+        tokio::time::timeout(self.timeout, async {
+            let mut client = self.bare_client(self.tcp_connect().await?);
+            self.write_proxy_header(&mut client, None).await?;
+            self.read_greeting(&mut client).await?;
+            Ok(client)
+        })
+        .await
+        .map_err(|_| crate::Error::Timeout {
+            phase: TimeoutPhase::Connect,
+            received: Vec::new(),
+        })?
+    }
+
+    /// Runs the SMTP handshake over an already established stream, such as a
+    /// Unix domain socket or a tunnel. The caller is responsible for the
+    /// security of the provided stream, credentials are sent over it
+    /// regardless of `require_tls_for_auth`. Since the stream is not known
+    /// to use TLS, an enforced MTA-STS policy refuses it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(addr = %self.addr), err(Display))
+    )]
+    pub async fn connect_over<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> crate::Result<SmtpClient<S>> {
+        self.check_mta_sts(false)?;
+        let resolved = self.resolve_credentials();
+        let credentials = self.credentials.as_ref().or(resolved.as_ref());
+        let mut client = self.bare_client(stream);
+        client.require_tls_for_auth = false;
+        self.handshake(client, &self.default_ehlo_hostname(), credentials)
+            .await
+    }
+
+    async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut client: SmtpClient<S>,
+        local_host: &str,
+        credentials: Option<&Credentials<T>>,
+    ) -> crate::Result<SmtpClient<S>> {
+        self.write_proxy_header(&mut client, None).await?;
 
         // Read greeting
-        client.read().await?.assert_positive_completion()?;
+        self.read_greeting(&mut client).await?;
 
         if self.say_ehlo {
             // Obtain capabilities
-            let capabilities = client.capabilities(&self.local_host, self.is_lmtp).await?;
+            let mut capabilities = client.capabilities(local_host, self.is_lmtp).await?;
+            capabilities.auth_mechanisms &= self.auth_mechanisms;
             // Authenticate
-            if let Some(credentials) = &self.credentials {
-                client.authenticate(&credentials, &capabilities).await?;
+            if let Some(credentials) = credentials {
+                client.authenticate(credentials, &capabilities).await?;
             }
         }
 
         Ok(client)
     }
+
+    /// Returns the credentials provided by the resolver for the server's
+    /// hostname, unless credentials were set explicitly.
+    fn resolve_credentials(&self) -> Option<Credentials<T>> {
+        match (&self.credentials, &self.credentials_resolver) {
+            (None, Some(resolver)) => resolver(self.tls_hostname.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Reads the server greeting within the greeting timeout.
+    async fn read_greeting<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: &mut SmtpClient<S>,
+    ) -> crate::Result<()> {
+        let started = Instant::now();
+        let reply =
+            tokio::time::timeout(self.greeting_timeout.unwrap_or(self.timeout), client.read())
+                .await
+                .unwrap_or_else(|_| Err(client.timeout_error(TimeoutPhase::Greeting)))?;
+        client.greeting_delay = started.elapsed();
+        reply.assert_positive_completion()
+    }
+
+    /// Opens a TCP connection to the server, bound to `local_addr` if set.
+    async fn tcp_connect(&self) -> std::io::Result<TcpStream> {
+        let stream = self.tcp_connect_addr().await?;
+        stream.set_nodelay(self.tcp_nodelay)?;
+        if let Some(idle) = self.tcp_keepalive {
+            socket2::SockRef::from(&stream)
+                .set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(stream)
+    }
+
+    async fn tcp_connect_addr(&self) -> std::io::Result<TcpStream> {
+        let remote_addrs = if self.addrs.is_empty() {
+            tokio::net::lookup_host(&self.addr)
+                .await?
+                .collect::<Vec<_>>()
+        } else {
+            self.addrs.clone()
+        };
+
+        let mut last_err = None;
+        for remote_addr in remote_addrs {
+            let result = match self.local_addr {
+                Some(local_addr) if remote_addr.is_ipv4() == local_addr.is_ipv4() => {
+                    connect_from(local_addr, remote_addr).await
+                }
+                Some(_) => continue,
+                None => TcpStream::connect(remote_addr).await,
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            if self.local_addr.is_some() {
+                std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "No remote address matches the local address family",
+                )
+            } else {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "No remote address to connect to",
+                )
+            }
+        }))
+    }
+
+    /// Writes the PROXY protocol header, if configured. On implicit TLS
+    /// connections `after_tls` indicates whether the handshake has completed,
+    /// other connections pass `None`.
+    async fn write_proxy_header<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: &mut SmtpClient<S>,
+        after_tls: Option<bool>,
+    ) -> crate::Result<()> {
+        match &self.proxy_header {
+            Some(header) if after_tls.unwrap_or(header.after_tls) == header.after_tls => {
+                client.write_bytes(&header.to_bytes()).await?;
+                client.stream.flush().await?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns `false` if invalid certificates are accepted by the TLS
+    /// connector built from the settings.
+    fn verifies_certs(&self) -> bool {
+        #[cfg(feature = "dane")]
+        if self.dane.is_some() {
+            return true;
+        }
+        self.tls_config.is_some() || !self.allow_invalid_certs
+    }
+
+    /// Returns the TLS connector built from the TLS settings, which is only
+    /// built once per builder: DANE verification if enabled, otherwise the
+    /// caller-provided configuration, otherwise the default configuration
+    /// selected by `allow_invalid_certs`. Sessions are stored in the
+    /// `tls_session_cache`, if set.
+    fn tls_connector(&self) -> TlsConnector {
+        self.tls_connector
+            .get_or_init(|| {
+                let config = match &self.tls_config {
+                    Some(config) => config.clone(),
+                    None => shared_tls_config(self.allow_invalid_certs),
+                };
+                #[cfg(feature = "dane")]
+                let config = match &self.dane {
+                    Some(verifier) => Arc::new(super::dane::tls_config(verifier.clone())),
+                    None => config,
+                };
+                TlsConnector::from(match &self.tls_session_cache {
+                    Some(cache) => {
+                        let mut config = (*config).clone();
+                        config.resumption = Resumption::store(cache.clone());
+                        Arc::new(config)
+                    }
+                    None => config,
+                })
+            })
+            .clone()
+    }
+
+    fn bare_client<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> SmtpClient<S> {
+        #[cfg(feature = "metrics")]
+        super::metrics::connection();
+        SmtpClient {
+            stream,
+            timeout: self.timeout,
+            normalize_line_endings: self.normalize_line_endings,
+            auth_mechanism: None,
+            capabilities: None,
+            on_wire: self.on_wire.clone(),
+            in_flight: false,
+            poisoned: false,
+            extensions: Vec::new(),
+            greeting_delay: Duration::ZERO,
+            partial_reply: Vec::new(),
+            tls_kind: TlsKind::None,
+            reset_on_error: self.reset_on_error,
+            login_initial_response: self.login_initial_response,
+            max_auth_rounds: self.max_auth_rounds,
+            add_missing_headers: self.add_missing_headers,
+            max_rcpt_per_message: self.max_rcpt_per_message,
+            line_length_policy: self.line_length_policy,
+            probe_before_send: self.probe_before_send,
+            require_tls_for_auth: self.require_tls_for_auth,
+            starttls_policy: self.starttls_policy,
+        }
+    }
+
+    /// Verifies the connection against the MTA-STS policy, if any.
+    fn check_mta_sts(&self, is_tls: bool) -> crate::Result<()> {
+        match &self.mta_sts_policy {
+            Some(policy) => match policy.verify(self.tls_hostname.as_ref(), is_tls) {
+                Err(err) if policy.mode == mta_sts::Mode::Enforce => Err(err.into()),
+                Err(_err) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        host = self.tls_hostname.as_ref(),
+                        reason = %_err,
+                        "MTA-STS policy failure in testing mode"
+                    );
+                    Ok(())
+                }
+                Ok(()) => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the hostname to use in EHLO/LHLO, falling back to an address
+    /// literal of the local socket address when no hostname is available or,
+    /// if enabled, the configured hostname is not fully qualified.
+    fn ehlo_hostname(&self, stream: &TcpStream) -> Cow<'_, str> {
+        let hostname = if self.local_host.is_empty()
+            || (self.ehlo_ip_literal
+                && !self.local_host.contains('.')
+                && !self.local_host.starts_with('['))
+        {
+            match stream.local_addr() {
+                Ok(local_addr) => match local_addr.ip() {
+                    IpAddr::V4(ip) => format!("[{ip}]").into(),
+                    IpAddr::V6(ip) => format!("[IPv6:{ip}]").into(),
+                },
+                Err(_) => self.default_ehlo_hostname(),
+            }
+        } else {
+            self.local_host.as_str().into()
+        };
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(hostname = %hostname, "EHLO hostname");
+        hostname
+    }
+
+    /// Returns the hostname to use in EHLO/LHLO when the local socket
+    /// address is not known.
+    fn default_ehlo_hostname(&self) -> Cow<'_, str> {
+        if self.local_host.is_empty() {
+            "[127.0.0.1]".into()
+        } else {
+            self.local_host.as_str().into()
+        }
+    }
+}
+
+impl<T: AsRef<str> + PartialEq + Eq + Hash> std::fmt::Debug for SmtpClientBuilder<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpClientBuilder")
+            .field("addr", &self.addr)
+            .field("addrs", &self.addrs)
+            .field("local_addr", &self.local_addr)
+            .field("tls_hostname", &self.tls_hostname.as_ref())
+            .field("tls_implicit", &self.tls_implicit)
+            .field("allow_invalid_certs", &self.allow_invalid_certs)
+            .field("starttls_policy", &self.starttls_policy)
+            .field("credentials", &self.credentials)
+            .field("credentials_resolver", &self.credentials_resolver.is_some())
+            .field("auth_mechanisms", &self.auth_mechanisms)
+            .field("require_tls_for_auth", &self.require_tls_for_auth)
+            .field("timeout", &self.timeout)
+            .field("tls_handshake_timeout", &self.tls_handshake_timeout)
+            .field("greeting_timeout", &self.greeting_timeout)
+            .field("local_host", &self.local_host)
+            .field("is_lmtp", &self.is_lmtp)
+            .field("say_ehlo", &self.say_ehlo)
+            .field("line_length_policy", &self.line_length_policy)
+            .field("probe_before_send", &self.probe_before_send)
+            .field("proxy_header", &self.proxy_header)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the system hostname, or an empty string if it is not a valid
+/// domain name, in which case an address literal is used in EHLO/LHLO.
+fn system_hostname() -> String {
+    gethostname::gethostname()
+        .into_string()
+        .ok()
+        .filter(|hostname| is_valid_hostname(hostname))
+        .unwrap_or_default()
+}
+
+fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 255
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == b'-')
+        })
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
@@ -170,3 +898,776 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use smtp_proto::{AUTH_PLAIN, EXT_DSN, EXT_PIPELINING};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    use crate::{
+        smtp::{
+            mock::MockServer,
+            mta_sts::{self, Mode, Policy},
+            proxy::ProxyHeader,
+        },
+        Credentials, Direction, SmtpClientBuilder, StartTlsPolicy, TimeoutPhase,
+    };
+
+    #[tokio::test]
+    async fn connect_over_stream() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut line = String::new();
+            server
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            server.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "EHLO client.example.com\r\n");
+            server
+                .write_all(b"250-mx.example.com\r\n250 PIPELINING\r\n")
+                .await
+                .unwrap();
+            line.clear();
+            server.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "QUIT\r\n");
+            server.write_all(b"221 Bye\r\n").await.unwrap();
+        });
+
+        let client = SmtpClientBuilder::new("mx.example.com", 25)
+            .helo_host("client.example.com")
+            .connect_over(client)
+            .await
+            .unwrap();
+        let capabilities = client.capabilities_cached().unwrap();
+        assert_eq!(capabilities.hostname, "mx.example.com");
+        assert!(capabilities.has_capability(EXT_PIPELINING));
+        client.quit().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_plain_caches_capabilities() {
+        let (addr, server) = MockServer::new()
+            .capabilities(["SIZE 1000", "DSN"])
+            .listen()
+            .await
+            .unwrap();
+
+        let mut builder =
+            SmtpClientBuilder::new("mx.example.com", addr.port()).helo_host("client.example.com");
+        builder.addr = addr.to_string();
+        let client = builder.connect_plain().await.unwrap();
+        let capabilities = client.capabilities_cached().unwrap();
+        assert_eq!(capabilities.hostname, "mock.example.com");
+        assert!(capabilities.has_capability(EXT_DSN));
+        assert_eq!(capabilities.size, 1000);
+        client.quit().await.unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap().commands,
+            ["EHLO client.example.com", "QUIT"]
+        );
+    }
+
+    #[tokio::test]
+    async fn credentials_resolver() {
+        let (addr, server) = MockServer::new()
+            .capabilities(["AUTH PLAIN"])
+            .expect("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0 Accepted")
+            .listen()
+            .await
+            .unwrap();
+
+        let mut builder = SmtpClientBuilder::new("mx.example.com".to_string(), addr.port())
+            .helo_host("client.example.com")
+            .require_tls_for_auth(false)
+            .credentials_resolver(|host| {
+                (host == "mx.example.com")
+                    .then(|| Credentials::new("john".to_string(), "secret".to_string()))
+            });
+        builder.addr = addr.to_string();
+        assert!(format!("{builder:?}").contains("credentials_resolver: true"));
+        let client = builder.connect_plain().await.unwrap();
+        assert_eq!(client.auth_mechanism(), Some(AUTH_PLAIN));
+        client.quit().await.unwrap();
+        server.await.unwrap().unwrap();
+
+        // TLS is still required before sending resolved credentials
+        let builder = SmtpClientBuilder::new("mx.example.com", 25)
+            .credentials_resolver(|_| Some(Credentials::new("john", "secret")));
+        assert!(matches!(
+            builder.connect_plain().await,
+            Err(crate::Error::InsecureAuth)
+        ));
+    }
+
+    #[tokio::test]
+    async fn ehlo_ip_literal() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = BufReader::new(stream);
+            let mut line = String::new();
+            server
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            server.read_line(&mut line).await.unwrap();
+            server.write_all(b"250 mx.example.com\r\n").await.unwrap();
+            line
+        });
+
+        SmtpClientBuilder::new("127.0.0.1", port)
+            .helo_host("localhost")
+            .ehlo_ip_literal(true)
+            .connect_plain()
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap(), "EHLO [127.0.0.1]\r\n");
+    }
+
+    #[tokio::test]
+    async fn ehlo_hostname_fallback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = BufReader::new(stream);
+            let mut line = String::new();
+            server
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            server.read_line(&mut line).await.unwrap();
+            server.write_all(b"250 mx.example.com\r\n").await.unwrap();
+            line
+        });
+
+        // No usable system hostname
+        let mut builder = SmtpClientBuilder::new("127.0.0.1", port);
+        builder.local_host = String::new();
+        builder.connect_plain().await.unwrap();
+        assert_eq!(server.await.unwrap(), "EHLO [127.0.0.1]\r\n");
+
+        for (hostname, expected) in [
+            ("mx1.example.com", true),
+            ("localhost", true),
+            ("my-host", true),
+            ("", false),
+            ("-host.example.com", false),
+            ("host..example.com", false),
+            ("host_name", false),
+            ("h\u{f6}st", false),
+        ] {
+            assert_eq!(super::is_valid_hostname(hostname), expected, "{hostname}");
+        }
+        assert!(
+            super::is_valid_hostname(&super::system_hostname())
+                || super::system_hostname().is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_bare_plain() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = BufReader::new(stream);
+            let mut line = String::new();
+            server
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            server.read_line(&mut line).await.unwrap();
+            server.write_all(b"250 OK\r\n").await.unwrap();
+            line
+        });
+
+        let mut client = SmtpClientBuilder::new("127.0.0.1", port)
+            .credentials(("user", "pass"))
+            .connect_bare_plain()
+            .await
+            .unwrap();
+        assert!(client.capabilities_cached().is_none());
+        client.noop().await.unwrap();
+        assert_eq!(server.await.unwrap(), "NOOP\r\n");
+    }
+
+    #[tokio::test]
+    async fn starttls_policy_require() {
+        let message = || {
+            crate::smtp::message::Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            )
+        };
+
+        // Bare clear text connections may not authenticate or send
+        let (addr, server) = MockServer::new().listen().await.unwrap();
+        let mut builder = SmtpClientBuilder::new("mx.example.com", addr.port())
+            .helo_host("client.example.com")
+            .starttls(StartTlsPolicy::Require);
+        builder.addr = addr.to_string();
+        assert!(matches!(
+            builder.connect_plain().await,
+            Err(crate::Error::MissingStartTls)
+        ));
+        let mut client = builder.connect_bare_plain().await.unwrap();
+        let capabilities = client.ehlo("client.example.com").await.unwrap();
+        assert!(matches!(
+            client
+                .authenticate(Credentials::new("john", "secret"), &capabilities)
+                .await,
+            Err(crate::Error::MissingStartTls)
+        ));
+        assert!(matches!(
+            client.send(message()).await,
+            Err(crate::Error::MissingStartTls)
+        ));
+        client.quit().await.unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap().commands,
+            ["EHLO client.example.com", "QUIT"]
+        );
+
+        // Neither may streams passed to connect_over
+        let (addr, server) = MockServer::new().listen().await.unwrap();
+        let mut client = builder
+            .connect_over(tokio::net::TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.send(message()).await,
+            Err(crate::Error::MissingStartTls)
+        ));
+        client.quit().await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_keepalive() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream
+                    .write_all(b"220 mx.example.com ESMTP\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = SmtpClientBuilder::new("127.0.0.1", port)
+            .tcp_keepalive(Some(Duration::from_secs(75)))
+            .connect_bare_plain()
+            .await
+            .unwrap();
+        let socket = socket2::SockRef::from(&client.stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(not(any(windows, target_os = "openbsd")))]
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(75)
+        );
+
+        let client = SmtpClientBuilder::new("127.0.0.1", port)
+            .connect_bare_plain()
+            .await
+            .unwrap();
+        assert!(!socket2::SockRef::from(&client.stream).keepalive().unwrap());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_nodelay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream
+                    .write_all(b"220 mx.example.com ESMTP\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+
+        for tcp_nodelay in [true, false] {
+            let client = SmtpClientBuilder::new("127.0.0.1", port)
+                .tcp_nodelay(tcp_nodelay)
+                .connect_bare_plain()
+                .await
+                .unwrap();
+            assert_eq!(client.stream.nodelay().unwrap(), tcp_nodelay);
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_plain() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = BufReader::new(stream);
+            server
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            let mut lines = Vec::new();
+            for reply in [
+                &b"250-mx.example.com\r\n250-AUTH PLAIN\r\n250 SIZE 1000\r\n"[..],
+                b"235 OK\r\n",
+                b"221 Bye\r\n",
+            ] {
+                let mut line = String::new();
+                server.read_line(&mut line).await.unwrap();
+                lines.push(line.split(' ').next().unwrap().to_string());
+                server.write_all(reply).await.unwrap();
+            }
+            lines
+        });
+
+        let capabilities = SmtpClientBuilder::new("127.0.0.1", port)
+            .credentials(("user", "pass"))
+            .require_tls_for_auth(false)
+            .verify_plain()
+            .await
+            .unwrap();
+        assert_eq!(capabilities.size, 1000);
+        assert_eq!(server.await.unwrap(), ["EHLO", "AUTH", "QUIT\r\n"]);
+    }
+
+    #[tokio::test]
+    async fn wire_observer() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut line = String::new();
+            server.write_all(b"220 mx.example.com\r\n").await.unwrap();
+            server.read_line(&mut line).await.unwrap();
+            server.write_all(b"250 mx.example.com\r\n").await.unwrap();
+            line.clear();
+            server.read_line(&mut line).await.unwrap();
+            server.write_all(b"221 Bye\r\n").await.unwrap();
+        });
+
+        let wire = Arc::new(Mutex::new(Vec::new()));
+        let wire_ = wire.clone();
+        let client = SmtpClientBuilder::new("mx.example.com", 25)
+            .helo_host("client.example.com")
+            .on_wire(move |direction, bytes| {
+                wire_
+                    .lock()
+                    .unwrap()
+                    .push((direction, String::from_utf8_lossy(bytes).into_owned()))
+            })
+            .connect_over(client)
+            .await
+            .unwrap();
+        client.quit().await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(
+            *wire.lock().unwrap(),
+            [
+                (Direction::Read, "220 mx.example.com\r\n"),
+                (Direction::Write, "EHLO client.example.com\r\n"),
+                (Direction::Read, "250 mx.example.com\r\n"),
+                (Direction::Write, "QUIT\r\n"),
+                (Direction::Read, "221 Bye\r\n"),
+            ]
+            .map(|(direction, bytes)| (direction, bytes.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // Accept the connection but never complete the handshake
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(stream);
+        });
+
+        let started = Instant::now();
+        let result = SmtpClientBuilder::new("localhost", port)
+            .tls_handshake_timeout(Duration::from_millis(100))
+            .connect_bare()
+            .await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::Timeout {
+                phase: TimeoutPhase::TlsHandshake,
+                ..
+            })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn greeting_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // Accept the connection but never send the greeting
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(stream);
+        });
+
+        let started = Instant::now();
+        let mut builder = SmtpClientBuilder::new("localhost", port)
+            .timeout(Duration::from_secs(30))
+            .greeting_timeout(Duration::from_millis(100));
+        builder.addr = format!("127.0.0.1:{port}");
+        let result = builder.connect_bare_plain().await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::Timeout {
+                phase: TimeoutPhase::Greeting,
+                ..
+            })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn greeting_delay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            stream
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            stream
+        });
+
+        let mut builder = SmtpClientBuilder::new("localhost", port);
+        builder.addr = format!("127.0.0.1:{port}");
+        let client = builder.connect_bare_plain().await.unwrap();
+        assert!(client.greeting_delay() >= Duration::from_millis(200));
+        assert!(client.greeting_delay() < Duration::from_secs(5));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn implicit_tls_against_plaintext() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let result = SmtpClientBuilder::new("localhost", port)
+            .connect_bare()
+            .await;
+        assert!(matches!(result, Err(crate::Error::NotTls)));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn tls_sni() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // Capture the ClientHello, which carries the SNI in clear text
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            buf.truncate(len);
+            buf
+        });
+
+        let result = SmtpClientBuilder::new("127.0.0.1", port)
+            .tls_sni("mail.example.com")
+            .connect_bare()
+            .await;
+        assert!(result.is_err());
+        let client_hello = server.await.unwrap();
+        assert!(client_hello
+            .windows(b"mail.example.com".len())
+            .any(|window| window == b"mail.example.com"));
+    }
+
+    #[test]
+    fn ip_addresses() {
+        for (hostname, addr) in [
+            ("192.0.2.1", "192.0.2.1:25"),
+            ("2001:db8::1", "[2001:db8::1]:25"),
+            ("[2001:db8::1]", "[2001:db8::1]:25"),
+            ("mx.example.com", "mx.example.com:25"),
+        ] {
+            assert_eq!(SmtpClientBuilder::new(hostname, 25).addr, addr);
+        }
+    }
+
+    #[test]
+    fn with_token() {
+        let builder = SmtpClientBuilder::new("mx.example.com", 587)
+            .credentials(Credentials::new_xoauth2("john@example.com", "expired"));
+        let refreshed = builder.clone().with_token("fresh");
+        assert!(matches!(
+            builder.credentials,
+            Some(Credentials::XOauth2 {
+                secret: "expired",
+                ..
+            })
+        ));
+        assert!(matches!(
+            refreshed.credentials,
+            Some(Credentials::XOauth2 {
+                username: "john@example.com",
+                secret: "fresh"
+            })
+        ));
+
+        let builder = SmtpClientBuilder::new("mx.example.com", 587)
+            .credentials(Credentials::new_oauth("expired"))
+            .with_token("fresh");
+        assert!(matches!(
+            builder.credentials,
+            Some(Credentials::OAuthBearer { token: "fresh" })
+        ));
+
+        let builder = SmtpClientBuilder::new("mx.example.com", 587)
+            .credentials(("john@example.com", "secret"))
+            .with_token("fresh");
+        assert!(matches!(
+            builder.credentials,
+            Some(Credentials::Plain {
+                secret: "secret",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn default_timeouts() {
+        let builder = SmtpClientBuilder::new("mx.example.com", 25);
+        assert_eq!(builder.timeout, Duration::from_secs(60));
+        assert_eq!(builder.tls_handshake_timeout, Duration::from_secs(30));
+        assert_eq!(builder.greeting_timeout, None);
+    }
+
+    #[tokio::test]
+    async fn proxy_header() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = BufReader::new(stream);
+            let mut line = String::new();
+            server.read_line(&mut line).await.unwrap();
+            server
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            line
+        });
+
+        SmtpClientBuilder::new("127.0.0.1", port)
+            .send_proxy_header(ProxyHeader::v1(
+                "192.0.2.1:4321".parse().unwrap(),
+                "198.51.100.7:25".parse().unwrap(),
+            ))
+            .connect_bare_plain()
+            .await
+            .unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            "PROXY TCP4 192.0.2.1 198.51.100.7 4321 25\r\n"
+        );
+    }
+
+    #[test]
+    fn auto_tls() {
+        for (port, tls_implicit) in [(465, true), (587, false), (25, false), (2525, true)] {
+            assert_eq!(
+                SmtpClientBuilder::new("mx.example.com", port)
+                    .auto_tls()
+                    .tls_implicit,
+                tls_implicit,
+                "port {port}"
+            );
+        }
+        assert!(
+            SmtpClientBuilder::new("mx.example.com", 587)
+                .auto_tls()
+                .implicit_tls(true)
+                .tls_implicit
+        );
+        assert!(
+            !SmtpClientBuilder::new("mx.example.com", 2525)
+                .implicit_tls(false)
+                .auto_tls()
+                .tls_implicit
+        );
+    }
+
+    #[tokio::test]
+    async fn local_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Binding to a port in use fails promptly
+        let result = SmtpClientBuilder::new("127.0.0.1", port)
+            .local_addr(listener.local_addr().unwrap())
+            .connect_bare_plain()
+            .await;
+        assert!(matches!(result, Err(crate::Error::Io(_))));
+
+        let local_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = tokio::spawn(async move {
+            let (mut stream, peer_addr) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            peer_addr
+        });
+        let client = SmtpClientBuilder::new("127.0.0.1", port)
+            .local_addr(SocketAddr::from(([127, 0, 0, 1], local_port)))
+            .connect_bare_plain()
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap().port(), local_port);
+        assert_eq!(client.local_addr().unwrap().port(), local_port);
+        assert_eq!(client.peer_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn mta_sts_policy() {
+        let policy = Policy {
+            mode: Mode::Enforce,
+            mx: vec!["*.example.com".to_string()],
+            max_age: Duration::from_secs(86400),
+        };
+
+        // Enforced policies are checked before connecting
+        for (builder, expected) in [
+            (
+                SmtpClientBuilder::new("mx.example.org", 25),
+                mta_sts::Error::MxMismatch,
+            ),
+            (
+                SmtpClientBuilder::new("mx.example.com", 25).allow_invalid_certs(),
+                mta_sts::Error::TlsRequired,
+            ),
+        ] {
+            let err = builder
+                .mta_sts_policy(Arc::new(policy.clone()))
+                .connect()
+                .await
+                .err()
+                .unwrap();
+            assert!(
+                matches!(err, crate::Error::MtaSts(ref err) if err == &expected),
+                "{err}"
+            );
+        }
+        assert!(matches!(
+            SmtpClientBuilder::new("mx.example.com", 25)
+                .mta_sts_policy(Arc::new(policy.clone()))
+                .connect_plain()
+                .await,
+            Err(crate::Error::MtaSts(mta_sts::Error::TlsRequired))
+        ));
+
+        // Bare connections and caller provided streams are checked as well
+        let builder =
+            SmtpClientBuilder::new("mx.example.org", 25).mta_sts_policy(Arc::new(policy.clone()));
+        assert!(matches!(
+            builder.connect_bare().await,
+            Err(crate::Error::MtaSts(mta_sts::Error::MxMismatch))
+        ));
+        let builder =
+            SmtpClientBuilder::new("mx.example.com", 25).mta_sts_policy(Arc::new(policy.clone()));
+        assert!(matches!(
+            builder.connect_bare_plain().await,
+            Err(crate::Error::MtaSts(mta_sts::Error::TlsRequired))
+        ));
+        let (stream, _server) = tokio::io::duplex(1024);
+        assert!(matches!(
+            builder.connect_over(stream).await,
+            Err(crate::Error::MtaSts(mta_sts::Error::TlsRequired))
+        ));
+
+        // Failures in testing mode do not prevent the connection
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"220 mx.example.org\r\n").await.unwrap();
+        });
+        let mut builder = SmtpClientBuilder::new("mx.example.org", 25)
+            .mta_sts_policy(Arc::new(Policy {
+                mode: Mode::Testing,
+                ..policy
+            }))
+            .say_ehlo(false);
+        builder.addr = format!("127.0.0.1:{port}");
+        builder.connect_plain().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pre_resolved_addrs() {
+        // An address with nothing listening on it
+        let closed_addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"220 mx.example.com\r\n").await.unwrap();
+        });
+
+        // The hostname is never resolved, addresses are tried in order
+        let client = SmtpClientBuilder::new("mx.example.invalid", 25)
+            .addrs(vec![closed_addr, addr])
+            .connect_bare_plain()
+            .await
+            .unwrap();
+        assert_eq!(client.peer_addr().unwrap(), addr);
+        server.await.unwrap();
+
+        assert!(matches!(
+            SmtpClientBuilder::new("mx.example.invalid", 25)
+                .addrs(vec![closed_addr])
+                .connect_bare_plain()
+                .await,
+            Err(crate::Error::Io(_))
+        ));
+    }
+}
@@ -19,19 +19,62 @@ use tokio::{
     net::{TcpSocket, TcpStream},
 };
 
-use crate::SmtpClient;
+use crate::{
+    Direction, LineLengthPolicy, SmtpClient, StartTlsPolicy, TimeoutPhase, TlsKind, WireObserver,
+};
+
+use super::auth::DEFAULT_MAX_AUTH_ROUNDS;
+
+/// Maximum number of bytes of a partial reply kept for timeout errors.
+const MAX_PARTIAL_REPLY: usize = 1024;
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
+    /// Creates a client over an already established stream, such as a TLS
+    /// stream set up by the caller, a pipe or a mock server. No greeting is
+    /// read, call `read` to consume it when the stream is freshly connected.
+    /// Since the client cannot tell whether the stream is secure,
+    /// `require_tls_for_auth` is disabled.
+    pub fn new(stream: T, timeout: Duration) -> Self {
+        SmtpClient {
+            stream,
+            timeout,
+            normalize_line_endings: false,
+            reset_on_error: false,
+            login_initial_response: false,
+            max_auth_rounds: DEFAULT_MAX_AUTH_ROUNDS,
+            add_missing_headers: false,
+            max_rcpt_per_message: None,
+            line_length_policy: LineLengthPolicy::Ignore,
+            probe_before_send: false,
+            require_tls_for_auth: false,
+            starttls_policy: StartTlsPolicy::Auto,
+            auth_mechanism: None,
+            capabilities: None,
+            extensions: Vec::new(),
+            greeting_delay: Duration::ZERO,
+            partial_reply: Vec::new(),
+            tls_kind: TlsKind::None,
+            on_wire: None,
+            in_flight: false,
+            poisoned: false,
+        }
+    }
+
     pub async fn read(&mut self) -> crate::Result<Response<String>> {
         let mut buf = vec![0u8; 1024];
         let mut parser = ResponseReceiver::default();
+        self.partial_reply.clear();
 
         loop {
-            let br = self.stream.read(&mut buf).await?;
+            let br = self.read_bytes(&mut buf).await?;
 
             if br > 0 {
                 match parser.parse(&mut buf[..br].iter()) {
-                    Ok(reply) => return Ok(reply),
+                    Ok(reply) => {
+                        #[cfg(feature = "tracing")]
+                        trace_reply(&reply);
+                        return Ok(reply);
+                    }
                     Err(err) => match err {
                         smtp_proto::Error::NeedsMoreData { .. } => (),
                         _ => {
@@ -45,13 +88,16 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         }
     }
 
+    /// Reads `num` replies from the server, stopping early if the server
+    /// replies with 421 as it is about to close the connection.
     pub async fn read_many(&mut self, num: usize) -> crate::Result<Vec<Response<String>>> {
         let mut buf = vec![0u8; 1024];
         let mut response = Vec::with_capacity(num);
         let mut parser = ResponseReceiver::default();
+        self.partial_reply.clear();
 
         'outer: loop {
-            let br = self.stream.read(&mut buf).await?;
+            let br = self.read_bytes(&mut buf).await?;
 
             if br > 0 {
                 let mut iter = buf[..br].iter();
@@ -59,8 +105,13 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
                 loop {
                     match parser.parse(&mut iter) {
                         Ok(reply) => {
+                            #[cfg(feature = "tracing")]
+                            trace_reply(&reply);
+                            // A 421 reply means the server is closing the
+                            // connection, no further replies will follow.
+                            let is_closing = reply.code() == 421;
                             response.push(reply);
-                            if response.len() != num {
+                            if response.len() != num && !is_closing {
                                 parser.reset();
                             } else {
                                 break 'outer;
@@ -82,15 +133,60 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         Ok(response)
     }
 
+    /// Sets a callback that receives every chunk of plaintext SMTP data read
+    /// from or written to the server.
+    pub fn set_on_wire(&mut self, observer: Option<WireObserver>) {
+        self.on_wire = observer;
+    }
+
+    pub(crate) async fn read_bytes(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let br = self.stream.read(buf).await?;
+        #[cfg(feature = "metrics")]
+        super::metrics::bytes_received(br);
+        let keep = br.min(MAX_PARTIAL_REPLY.saturating_sub(self.partial_reply.len()));
+        self.partial_reply.extend_from_slice(&buf[..keep]);
+        if let Some(on_wire) = &self.on_wire {
+            if br > 0 {
+                on_wire(Direction::Read, &buf[..br]);
+            }
+        }
+        Ok(br)
+    }
+
+    pub(crate) async fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if let Some(on_wire) = &self.on_wire {
+            on_wire(Direction::Write, bytes);
+        }
+        #[cfg(feature = "metrics")]
+        super::metrics::bytes_sent(bytes.len());
+        self.stream.write_all(bytes).await
+    }
+
     /// Sends a command to the SMTP server and waits for a reply.
     pub async fn cmd(&mut self, cmd: impl AsRef<[u8]>) -> crate::Result<Response<String>> {
-        tokio::time::timeout(self.timeout, async {
-            self.stream.write_all(cmd.as_ref()).await?;
+        #[cfg(feature = "tracing")]
+        trace_command(cmd.as_ref());
+        self.write_cmd(cmd.as_ref()).await
+    }
+
+    /// Sends a command containing secrets, such as a SASL response, which is
+    /// never included in traces.
+    pub(crate) async fn cmd_secret(&mut self, cmd: &[u8]) -> crate::Result<Response<String>> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(command = "<redacted>", "SMTP command");
+        self.write_cmd(cmd).await
+    }
+
+    async fn write_cmd(&mut self, cmd: &[u8]) -> crate::Result<Response<String>> {
+        let in_flight = self.begin_exchange();
+        let result = tokio::time::timeout(self.timeout, async {
+            self.write_bytes(cmd).await?;
             self.stream.flush().await?;
             self.read().await
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .unwrap_or_else(|_| Err(self.timeout_error(TimeoutPhase::Command)));
+        self.end_exchange(in_flight, result)
     }
 
     /// Pipelines multiple command to the SMTP server and waits for a reply.
@@ -98,31 +194,160 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         &mut self,
         cmds: impl IntoIterator<Item = impl AsRef<[u8]>>,
     ) -> crate::Result<Vec<Response<String>>> {
-        tokio::time::timeout(self.timeout, async {
+        let in_flight = self.begin_exchange();
+        let result = tokio::time::timeout(self.timeout, async {
             let mut num_replies = 0;
             for cmd in cmds {
-                self.stream.write_all(cmd.as_ref()).await?;
+                #[cfg(feature = "tracing")]
+                trace_command(cmd.as_ref());
+                self.write_bytes(cmd.as_ref()).await?;
                 num_replies += 1;
             }
             self.stream.flush().await?;
             self.read_many(num_replies).await
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .unwrap_or_else(|_| Err(self.timeout_error(TimeoutPhase::Command)));
+        self.end_exchange(in_flight, result)
+    }
+
+    /// Returns `true` if an operation was interrupted, for example because
+    /// its future was dropped, or failed in a way that leaves the connection
+    /// in an undefined state. Poisoned clients should not be reused.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned || self.in_flight
+    }
+
+    /// Returns how long the server took to send its greeting once the
+    /// connection was established, which may reveal tarpitting or
+    /// greylisting. Zero if the greeting was not read by `SmtpClientBuilder`.
+    pub fn greeting_delay(&self) -> Duration {
+        self.greeting_delay
+    }
+
+    /// Returns whether the connection was secured with implicit TLS or
+    /// upgraded with STARTTLS, which is more exposed to downgrade attacks.
+    pub fn tls_kind(&self) -> TlsKind {
+        self.tls_kind
+    }
+
+    /// Fails with `MissingStartTls` if `StartTlsPolicy::Require` is set and
+    /// the connection was not secured with TLS.
+    pub(crate) fn check_starttls_policy(&self) -> crate::Result<()> {
+        if self.starttls_policy == StartTlsPolicy::Require && self.tls_kind == TlsKind::None {
+            Err(crate::Error::MissingStartTls)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Marks the start of an exchange with the server, returning the
+    /// previous state to be restored by `end_exchange`. If the future is
+    /// dropped before `end_exchange` runs, the client remains poisoned.
+    pub(crate) fn begin_exchange(&mut self) -> bool {
+        self.partial_reply.clear();
+        std::mem::replace(&mut self.in_flight, true)
+    }
+
+    /// Returns a timeout error carrying the bytes received so far.
+    pub(crate) fn timeout_error(&mut self, phase: TimeoutPhase) -> crate::Error {
+        crate::Error::Timeout {
+            phase,
+            received: std::mem::take(&mut self.partial_reply),
+        }
+    }
+
+    pub(crate) fn end_exchange<R>(
+        &mut self,
+        in_flight: bool,
+        result: crate::Result<R>,
+    ) -> crate::Result<R> {
+        self.in_flight = in_flight;
+        if result.as_ref().is_err_and(is_session_closed) {
+            self.poisoned = true;
+        }
+        result
+    }
+}
+
+/// Returns `true` if the error leaves the connection unusable for further
+/// transactions.
+pub(crate) fn is_session_closed(err: &crate::Error) -> bool {
+    match err {
+        crate::Error::UnexpectedReply(reply) => reply.code() == 421,
+        crate::Error::Io(_)
+        | crate::Error::Tls(_)
+        | crate::Error::UnparseableReply
+        | crate::Error::Timeout { .. }
+        | crate::Error::ConnectionClosed => true,
+        _ => false,
+    }
+}
+
+/// Opens a TCP connection bound to the provided local address.
+pub(crate) async fn connect_from(
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+) -> std::io::Result<TcpStream> {
+    let socket = if local_addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(local_addr)?;
+    socket.connect(remote_addr).await
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn trace_command(cmd: &[u8]) {
+    tracing::trace!(command = %redact_command(cmd), "SMTP command");
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn trace_reply(reply: &Response<String>) {
+    tracing::trace!(code = reply.code(), message = reply.message(), "SMTP reply");
+}
+
+/// Removes the initial SASL response from AUTH commands.
+#[cfg(feature = "tracing")]
+fn redact_command(cmd: &[u8]) -> String {
+    let cmd = String::from_utf8_lossy(cmd);
+    let cmd = cmd.trim_end();
+    match cmd.get(..5) {
+        Some(verb) if verb.eq_ignore_ascii_case("AUTH ") => {
+            match cmd[5..].trim_start().split_once(' ') {
+                Some((mechanism, _)) => format!("AUTH {mechanism} <redacted>"),
+                None => cmd.to_string(),
+            }
+        }
+        _ => cmd.to_string(),
     }
 }
 
 impl SmtpClient<TcpStream> {
+    /// Returns the address of the remote server.
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Returns the local address of the connection.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
     /// Connects to a remote host address
     pub async fn connect(remote_addr: SocketAddr, timeout: Duration) -> crate::Result<Self> {
         tokio::time::timeout(timeout, async {
-            Ok(SmtpClient {
-                stream: TcpStream::connect(remote_addr).await?,
-                timeout,
-            })
+            let stream = TcpStream::connect(remote_addr).await?;
+            #[cfg(feature = "metrics")]
+            super::metrics::connection();
+            Ok(SmtpClient::new(stream, timeout))
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .map_err(|_| crate::Error::Timeout {
+            phase: TimeoutPhase::Connect,
+            received: Vec::new(),
+        })?
     }
 
     /// Connects to a remote host address using the provided local IP
@@ -130,32 +355,60 @@ impl SmtpClient<TcpStream> {
         local_ip: IpAddr,
         remote_addr: SocketAddr,
         timeout: Duration,
+    ) -> crate::Result<Self> {
+        Self::connect_from(SocketAddr::new(local_ip, 0), remote_addr, timeout).await
+    }
+
+    /// Connects to a remote host address from the provided local address. A
+    /// port of 0 selects an ephemeral port.
+    pub async fn connect_from(
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        timeout: Duration,
     ) -> crate::Result<Self> {
         tokio::time::timeout(timeout, async {
-            let socket = if local_ip.is_ipv4() {
-                TcpSocket::new_v4()?
-            } else {
-                TcpSocket::new_v6()?
-            };
-            socket.bind(SocketAddr::new(local_ip, 0))?;
-
-            Ok(SmtpClient {
-                stream: socket.connect(remote_addr).await?,
-                timeout,
-            })
+            let stream = connect_from(local_addr, remote_addr).await?;
+            #[cfg(feature = "metrics")]
+            super::metrics::connection();
+            Ok(SmtpClient::new(stream, timeout))
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .map_err(|_| crate::Error::Timeout {
+            phase: TimeoutPhase::Connect,
+            received: Vec::new(),
+        })?
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
 
-    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-    use crate::{SmtpClient, SmtpClientBuilder};
+    use crate::{SmtpClient, SmtpClientBuilder, TimeoutPhase};
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn redact_auth_commands() {
+        for (cmd, expected) in [
+            ("AUTH PLAIN AGpvaG4AcGFzcw==\r\n", "AUTH PLAIN <redacted>"),
+            (
+                "auth xoauth2 dXNlcj1qb2huAWF1dGg9\r\n",
+                "AUTH xoauth2 <redacted>",
+            ),
+            ("AUTH LOGIN\r\n", "AUTH LOGIN"),
+            (
+                "MAIL FROM:<john@example.com>\r\n",
+                "MAIL FROM:<john@example.com>",
+            ),
+        ] {
+            assert_eq!(super::redact_command(cmd.as_bytes()), expected);
+        }
+    }
 
     #[tokio::test]
     async fn smtp_basic() {
@@ -194,6 +447,8 @@ mod test {
     #[derive(Default)]
     struct AsyncBufWriter {
         buf: Vec<u8>,
+        flushed: usize,
+        max_unflushed: usize,
     }
 
     impl AsyncRead for AsyncBufWriter {
@@ -213,6 +468,65 @@ mod test {
             buf: &[u8],
         ) -> std::task::Poll<Result<usize, std::io::Error>> {
             self.buf.extend_from_slice(buf);
+            self.max_unflushed = self.max_unflushed.max(self.buf.len() - self.flushed);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), std::io::Error>> {
+            self.flushed = self.buf.len();
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), std::io::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Returns the data in chunks of at most `chunk` bytes per read, as
+    /// happens when replies are fragmented by TLS proxies.
+    struct ChunkedReader {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl ChunkedReader {
+        fn new(data: &str, chunk: usize) -> Self {
+            ChunkedReader {
+                data: data.as_bytes().to_vec(),
+                pos: 0,
+                chunk,
+            }
+        }
+    }
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let end = (self.pos + self.chunk)
+                .min(self.data.len())
+                .min(self.pos + buf.remaining());
+            buf.put_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for ChunkedReader {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<Result<usize, std::io::Error>> {
             std::task::Poll::Ready(Ok(buf.len()))
         }
 
@@ -231,6 +545,100 @@ mod test {
         }
     }
 
+    fn chunked_client(data: &str, chunk: usize) -> SmtpClient<ChunkedReader> {
+        SmtpClient::new(ChunkedReader::new(data, chunk), Duration::from_secs(30))
+    }
+
+    #[tokio::test]
+    async fn timeout_partial_reply() {
+        let (stream, mut server) = tokio::io::duplex(1024);
+        let mut client = SmtpClient::new(stream, Duration::from_millis(100));
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 6];
+            server.read_exact(&mut buf).await.unwrap();
+            // Start the reply but never finish it
+            server.write_all(b"250-mx.example.com\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let err = client.noop().await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                crate::Error::Timeout {
+                    phase: TimeoutPhase::Command,
+                    received,
+                } if received == b"250-mx.example.com\r\n"
+            ),
+            "{err:?}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "Timeout during command, received \"250-mx.example.com\\r\\n\""
+        );
+        assert!(client.is_poisoned());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn fragmented_replies() {
+        let greeting = concat!(
+            "220-mx.example.com ESMTP\r\n",
+            "220-Unsolicited bulk mail prohibited\r\n",
+            "220 Ready\r\n"
+        );
+        let ehlo = concat!(
+            "250-mx.example.com at your service\r\n",
+            "250-PIPELINING\r\n",
+            "250-SIZE 10485760\r\n",
+            "250-AUTH PLAIN LOGIN\r\n",
+            "250-8BITMIME\r\n",
+            "250 CHUNKING\r\n"
+        );
+
+        for chunk in [1, 2, 3, 7, 1024] {
+            let mut client = chunked_client(greeting, chunk);
+            let reply = client.read().await.unwrap();
+            assert_eq!(reply.code(), 220, "chunk {chunk}");
+            assert_eq!(
+                reply.message(),
+                "mx.example.com ESMTP\nUnsolicited bulk mail prohibited\nReady",
+                "chunk {chunk}"
+            );
+
+            let mut client = chunked_client(ehlo, chunk);
+            let reply = client.read_ehlo().await.unwrap();
+            assert_eq!(reply.hostname, "mx.example.com", "chunk {chunk}");
+            assert_eq!(reply.size, 10485760, "chunk {chunk}");
+            assert!(
+                reply.has_capability(smtp_proto::EXT_CHUNKING),
+                "chunk {chunk}"
+            );
+            assert_eq!(
+                reply.auth_mechanisms,
+                smtp_proto::AUTH_PLAIN | smtp_proto::AUTH_LOGIN,
+                "chunk {chunk}"
+            );
+
+            let mut client = chunked_client("550 5.1.1 No such user\r\n", chunk);
+            match client.read_ehlo().await {
+                Err(crate::Error::UnexpectedReply(reply)) => {
+                    assert_eq!(reply.code(), 550, "chunk {chunk}");
+                    assert_eq!(reply.message(), "No such user", "chunk {chunk}");
+                }
+                other => panic!("chunk {chunk}: unexpected result {:?}", other.err()),
+            }
+
+            let mut client = chunked_client("250 2.1.0 OK\r\n354 Go ahead\r\n", chunk);
+            let replies = client.read_many(2).await.unwrap();
+            assert_eq!(
+                replies.iter().map(|r| r.code()).collect::<Vec<_>>(),
+                [250, 354],
+                "chunk {chunk}"
+            );
+        }
+    }
+
     #[tokio::test]
     async fn transparency_procedure() {
         const SMUGGLER: &str = r#"From: Joe SixPack <john@foobar.net>
@@ -255,16 +663,37 @@ This is a smuggled message
 "#;
 
         for (test, result) in [
+            (String::new(), ".\r\n".to_string()),
             (
                 "A: b\r\n.\r\n".to_string(),
-                "A: b\r\n..\r\n\r\n.\r\n".to_string(),
+                "A: b\r\n..\r\n.\r\n".to_string(),
             ),
             ("A: b\r\n.".to_string(), "A: b\r\n..\r\n.\r\n".to_string()),
             (
                 "A: b\r\n..\r\n".to_string(),
-                "A: b\r\n...\r\n\r\n.\r\n".to_string(),
+                "A: b\r\n...\r\n.\r\n".to_string(),
             ),
             ("A: ...b".to_string(), "A: ...b\r\n.\r\n".to_string()),
+            (".".to_string(), "..\r\n.\r\n".to_string()),
+            ("..".to_string(), "...\r\n.\r\n".to_string()),
+            (".A: b".to_string(), "..A: b\r\n.\r\n".to_string()),
+            (".\r\nA: b".to_string(), "..\r\nA: b\r\n.\r\n".to_string()),
+            (
+                "A: b\r\n\r\nc\r\n.".to_string(),
+                "A: b\r\n\r\nc\r\n..\r\n.\r\n".to_string(),
+            ),
+            (
+                "A: b\r\n\r\nc\r\n..\r\nd".to_string(),
+                "A: b\r\n\r\nc\r\n...\r\nd\r\n.\r\n".to_string(),
+            ),
+            (
+                "A: b\r\n\r\n.\r\n.\r\n".to_string(),
+                "A: b\r\n\r\n..\r\n..\r\n.\r\n".to_string(),
+            ),
+            (
+                "A: b\r\n.\r".to_string(),
+                "A: b\r\n..\r\r\n.\r\n".to_string(),
+            ),
             (
                 "A: \n.\r\nMAIL FROM:<>".to_string(),
                 "A: \n..\r\nMAIL FROM:<>\r\n.\r\n".to_string(),
@@ -282,7 +711,7 @@ This is a smuggled message
                     .replace('\r', "")
                     .replace('\n', "\r\n")
                     .replace("<SEP>", "\r.")
-                    + "\r\n.\r\n",
+                    + ".\r\n",
             ),
             (
                 SMUGGLER
@@ -293,15 +722,70 @@ This is a smuggled message
                     .replace('\r', "")
                     .replace('\n', "\r\n")
                     .replace("<SEP>", "\n.")
-                    + "\r\n.\r\n",
+                    + ".\r\n",
             ),
         ] {
-            let mut client = SmtpClient {
-                stream: AsyncBufWriter::default(),
-                timeout: Duration::from_secs(30),
-            };
+            let mut client = SmtpClient::new(AsyncBufWriter::default(), Duration::from_secs(30));
             client.write_message(test.as_bytes()).await.unwrap();
             assert_eq!(String::from_utf8(client.stream.buf).unwrap(), result);
         }
+
+        // Bare line endings are converted before the transparency procedure
+        for (test, result) in [
+            ("A: b\n.\nc", "A: b\r\n..\r\nc\r\n.\r\n"),
+            ("A: b\r.\r\nc\n", "A: b\r\n..\r\nc\r\n.\r\n"),
+            ("A: b\r\n\nc", "A: b\r\n\r\nc\r\n.\r\n"),
+        ] {
+            let mut client = SmtpClient::new(AsyncBufWriter::default(), Duration::from_secs(30));
+            client.normalize_line_endings = true;
+            client.write_message(test.as_bytes()).await.unwrap();
+            assert_eq!(String::from_utf8(client.stream.buf).unwrap(), result);
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesced_writes() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let writes_ = writes.clone();
+        let mut client = SmtpClient::new(AsyncBufWriter::default(), Duration::from_secs(30));
+        client.set_on_wire(Some(Arc::new(move |_, bytes: &[u8]| {
+            writes_.lock().unwrap().push(bytes.len())
+        })));
+
+        // Dot-stuffed lines are sent in a single write
+        let message = ".line\r\n".repeat(1000);
+        client.write_message(message.as_bytes()).await.unwrap();
+        assert_eq!(*writes.lock().unwrap(), [message.len() + 1000 + 3]);
+
+        // Large messages are written in buffer sized chunks
+        writes.lock().unwrap().clear();
+        client.stream.buf.clear();
+        let message = ".line\r\n".repeat(20000);
+        client.write_message(message.as_bytes()).await.unwrap();
+        let writes = writes.lock().unwrap();
+        assert!(writes.len() > 1 && writes.len() <= 4, "{writes:?}");
+        assert!(writes.iter().all(|&len| len <= 64 * 1024));
+        assert_eq!(writes.iter().sum::<usize>(), client.stream.buf.len());
+    }
+
+    #[tokio::test]
+    async fn bounded_flushes() {
+        let mut client = SmtpClient::new(AsyncBufWriter::default(), Duration::from_secs(30));
+
+        // Large messages are flushed at least every buffer sized chunk,
+        // including long runs that bypass the write buffer
+        let message = format!(
+            "{}{}\r\n{}",
+            ".line\r\n".repeat(100_000),
+            "a".repeat(1_000_000),
+            "line\r\n".repeat(100_000)
+        );
+        client.write_message(message.as_bytes()).await.unwrap();
+        assert_eq!(client.stream.buf.len(), message.len() + 100_000 + 3);
+        assert!(
+            client.stream.max_unflushed <= 64 * 1024,
+            "{}",
+            client.stream.max_unflushed
+        );
     }
 }
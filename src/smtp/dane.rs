@@ -0,0 +1,423 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! SMTP security via opportunistic DANE TLS (RFC 7672).
+
+use std::sync::Arc;
+
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
+    TokioAsyncResolver,
+};
+use ring::digest::{digest, SHA256, SHA512};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+
+/// Certificate usage of a trust anchor (DANE-TA).
+pub const USAGE_DANE_TA: u8 = 2;
+/// Certificate usage of an end entity certificate (DANE-EE).
+pub const USAGE_DANE_EE: u8 = 3;
+
+/// A TLSA record (RFC 6698).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlsa {
+    /// Certificate usage, only DANE-TA (2) and DANE-EE (3) are used with SMTP.
+    pub usage: u8,
+    /// Whether the full certificate (0) or its public key (1) is matched.
+    pub selector: u8,
+    /// Whether `data` is the selected content (0), or its SHA-256 (1) or
+    /// SHA-512 (2) digest.
+    pub matching: u8,
+    pub data: Vec<u8>,
+}
+
+/// Resolves TLSA records with DNSSEC validation.
+pub struct TlsaResolver {
+    resolver: TokioAsyncResolver,
+}
+
+/// Verifies server certificates against a set of TLSA records.
+#[derive(Debug)]
+pub struct DaneVerifier {
+    records: Vec<Tlsa>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl Tlsa {
+    /// Returns `true` if the record can be used to authenticate an SMTP
+    /// server. PKIX usages are not used with SMTP (RFC 7672, section 3.1.3).
+    pub fn is_usable(&self) -> bool {
+        matches!(self.usage, USAGE_DANE_TA | USAGE_DANE_EE)
+            && matches!(self.selector, 0 | 1)
+            && matches!(self.matching, 0..=2)
+    }
+
+    /// Returns `true` if the certificate matches the record.
+    pub fn matches(&self, cert: &CertificateDer<'_>) -> bool {
+        let spki;
+        let selected = match self.selector {
+            0 => cert.as_ref(),
+            1 => match webpki::EndEntityCert::try_from(cert) {
+                Ok(cert) => {
+                    spki = cert.subject_public_key_info();
+                    spki.as_ref()
+                }
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+
+        match self.matching {
+            0 => selected == self.data,
+            1 => digest(&SHA256, selected).as_ref() == self.data,
+            2 => digest(&SHA512, selected).as_ref() == self.data,
+            _ => false,
+        }
+    }
+}
+
+impl TlsaResolver {
+    /// Creates a resolver with the provided configuration, DNSSEC
+    /// validation is always enabled.
+    pub fn new(config: ResolverConfig, mut options: ResolverOpts) -> Self {
+        options.validate = true;
+        TlsaResolver {
+            resolver: TokioAsyncResolver::tokio(config, options),
+        }
+    }
+
+    /// Creates a resolver using the system configuration.
+    pub fn new_system_conf() -> crate::Result<Self> {
+        let (config, options) = hickory_resolver::system_conf::read_system_conf()
+            .map_err(|err| crate::Error::Dns(err.to_string()))?;
+        Ok(Self::new(config, options))
+    }
+
+    /// Returns the DNSSEC-validated TLSA records of `_<port>._tcp.<mx_host>`,
+    /// or an empty list when the host does not publish any. Lookup and
+    /// validation failures are returned as errors, in which case delivery
+    /// should be deferred rather than attempted without DANE.
+    pub async fn lookup(&self, mx_host: &str, port: u16) -> crate::Result<Vec<Tlsa>> {
+        let name = format!("_{port}._tcp.{}.", mx_host.trim_end_matches('.'));
+        match self.resolver.tlsa_lookup(name).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|record| Tlsa {
+                    usage: record.cert_usage().into(),
+                    selector: record.selector().into(),
+                    matching: record.matching().into(),
+                    data: record.cert_data().to_vec(),
+                })
+                .collect()),
+            Err(err) => match err.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => Ok(Vec::new()),
+                _ => Err(crate::Error::Dns(err.to_string())),
+            },
+        }
+    }
+}
+
+impl DaneVerifier {
+    /// Creates a verifier from the usable records in `records`.
+    pub fn new(records: Vec<Tlsa>) -> Self {
+        DaneVerifier {
+            records: records.into_iter().filter(Tlsa::is_usable).collect(),
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+
+    /// Builds a TLS configuration that authenticates the server with DANE.
+    pub fn into_tls_config(self) -> ClientConfig {
+        tls_config(Arc::new(self))
+    }
+}
+
+pub(crate) fn tls_config(verifier: Arc<DaneVerifier>) -> ClientConfig {
+    ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth()
+}
+
+impl ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // DANE-EE records authenticate the certificate regardless of its
+        // name, issuer or validity period (RFC 7672, section 3.1.1).
+        if self
+            .records
+            .iter()
+            .any(|record| record.usage == USAGE_DANE_EE && record.matches(end_entity))
+        {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        // DANE-TA records designate a trust anchor in the presented chain,
+        // which must validate the end entity certificate and its name.
+        for anchor in intermediates {
+            if self
+                .records
+                .iter()
+                .any(|record| record.usage == USAGE_DANE_TA && record.matches(anchor))
+            {
+                let mut roots = RootCertStore::empty();
+                if roots.add(anchor.clone()).is_ok() {
+                    if let Ok(verifier) = WebPkiServerVerifier::builder(Arc::new(roots)).build() {
+                        if let Ok(verified) = verifier.verify_server_cert(
+                            end_entity,
+                            intermediates,
+                            server_name,
+                            ocsp_response,
+                            now,
+                        ) {
+                            return Ok(verified);
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        ))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use ring::digest::{digest, SHA256, SHA512};
+    use rustls::{client::ClientSessionMemoryCache, ServerConfig};
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::{
+        smtp::tls::test::{decode, CA_CERT, SERVER_CERT, SERVER_KEY},
+        SmtpClientBuilder,
+    };
+
+    use super::{Tlsa, USAGE_DANE_EE, USAGE_DANE_TA};
+
+    fn spki(cert: &[u8]) -> Vec<u8> {
+        webpki::EndEntityCert::try_from(&CertificateDer::from(cert))
+            .unwrap()
+            .subject_public_key_info()
+            .as_ref()
+            .to_vec()
+    }
+
+    #[test]
+    fn match_records() {
+        let cert = CertificateDer::from(decode(SERVER_CERT));
+        let spki = spki(&cert);
+        for (selector, matching, data, expected) in [
+            (0, 0, cert.to_vec(), true),
+            (0, 1, digest(&SHA256, &cert).as_ref().to_vec(), true),
+            (1, 1, digest(&SHA256, &spki).as_ref().to_vec(), true),
+            (1, 2, digest(&SHA512, &spki).as_ref().to_vec(), true),
+            (1, 1, digest(&SHA256, &cert).as_ref().to_vec(), false),
+            (2, 1, digest(&SHA256, &spki).as_ref().to_vec(), false),
+        ] {
+            let record = Tlsa {
+                usage: USAGE_DANE_EE,
+                selector,
+                matching,
+                data,
+            };
+            assert_eq!(record.matches(&cert), expected, "{selector} {matching}");
+        }
+
+        let record = Tlsa {
+            usage: 1,
+            selector: 1,
+            matching: 1,
+            data: vec![],
+        };
+        assert!(!record.is_usable());
+    }
+
+    #[tokio::test]
+    async fn dane_handshake() {
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![
+                    CertificateDer::from(decode(SERVER_CERT)),
+                    CertificateDer::from(decode(CA_CERT)),
+                ],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(decode(SERVER_KEY))),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            for _ in 0..4 {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    stream.write_all(b"220 mx.example.com\r\n").await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+            }
+        });
+
+        let ee_record = Tlsa {
+            usage: USAGE_DANE_EE,
+            selector: 1,
+            matching: 1,
+            data: digest(&SHA256, &spki(&decode(SERVER_CERT)))
+                .as_ref()
+                .to_vec(),
+        };
+        let ta_record = Tlsa {
+            usage: USAGE_DANE_TA,
+            selector: 0,
+            matching: 1,
+            data: digest(&SHA256, &decode(CA_CERT)).as_ref().to_vec(),
+        };
+
+        for (records, hostname, expect_ok) in [
+            // DANE-EE ignores the certificate name
+            (vec![ee_record.clone()], "mx.example.com", true),
+            // DANE-TA validates the chain and the name
+            (vec![ta_record.clone()], "127.0.0.1", true),
+            (vec![ta_record], "mx.example.com", false),
+            // The CA is not an end entity certificate
+            (
+                vec![Tlsa {
+                    usage: USAGE_DANE_EE,
+                    matching: 0,
+                    selector: 0,
+                    data: decode(CA_CERT),
+                }],
+                "127.0.0.1",
+                false,
+            ),
+        ] {
+            let mut builder = SmtpClientBuilder::new(hostname.to_string(), port).dane(records);
+            builder.addr = format!("127.0.0.1:{port}");
+            let result = builder.connect_bare().await;
+            assert_eq!(result.is_ok(), expect_ok, "{hostname}");
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dane_not_overridden() {
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(decode(SERVER_CERT))],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(decode(SERVER_KEY))),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    stream.write_all(b"220 mx.example.com\r\n").await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+            }
+        });
+
+        // A record that does not match the server certificate
+        let records = vec![Tlsa {
+            usage: USAGE_DANE_EE,
+            selector: 1,
+            matching: 1,
+            data: vec![0; 32],
+        }];
+        let cache = || Arc::new(ClientSessionMemoryCache::new(8));
+        let insecure = Arc::new(crate::smtp::tls::build_tls_config(true));
+
+        for builder in [
+            SmtpClientBuilder::new("127.0.0.1", port)
+                .dane(records.clone())
+                .allow_invalid_certs(),
+            SmtpClientBuilder::new("127.0.0.1", port)
+                .allow_invalid_certs()
+                .dane(records.clone()),
+            SmtpClientBuilder::new("127.0.0.1", port)
+                .allow_invalid_certs()
+                .dane(records.clone())
+                .tls_session_cache(cache()),
+            SmtpClientBuilder::new("127.0.0.1", port)
+                .dane(records.clone())
+                .tls_config(insecure.clone()),
+            SmtpClientBuilder::new("127.0.0.1", port)
+                .tls_config(insecure.clone())
+                .dane(records.clone()),
+        ] {
+            assert!(
+                matches!(builder.connect_bare().await, Err(crate::Error::Tls(_))),
+                "{builder:?}"
+            );
+        }
+
+        // DANE is combined with a session cache set before
+        let ee_record = Tlsa {
+            usage: USAGE_DANE_EE,
+            selector: 1,
+            matching: 1,
+            data: digest(&SHA256, &spki(&decode(SERVER_CERT)))
+                .as_ref()
+                .to_vec(),
+        };
+        SmtpClientBuilder::new("127.0.0.1", port)
+            .tls_session_cache(cache())
+            .dane(vec![ee_record])
+            .connect_bare()
+            .await
+            .unwrap();
+        server.abort();
+    }
+}
@@ -8,86 +8,264 @@
  * except according to those terms.
  */
 
+use std::time::Duration;
+
 use smtp_proto::{
     response::parser::{ResponseReceiver, MAX_RESPONSE_LENGTH},
-    EhloResponse,
+    EhloResponse, MtPriority, EXT_DELIVER_BY, EXT_FUTURE_RELEASE, EXT_MT_PRIORITY, EXT_SIZE,
 };
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::SmtpClient;
+use crate::{SmtpClient, TimeoutPhase};
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
     /// Sends a EHLO command to the server.
     pub async fn ehlo(&mut self, hostname: &str) -> crate::Result<EhloResponse<String>> {
-        tokio::time::timeout(self.timeout, async {
-            self.stream
-                .write_all(format!("EHLO {hostname}\r\n").as_bytes())
+        #[cfg(feature = "tracing")]
+        tracing::trace!(command = %format_args!("EHLO {hostname}"), "SMTP command");
+        let in_flight = self.begin_exchange();
+        let result = tokio::time::timeout(self.timeout, async {
+            self.write_bytes(format!("EHLO {hostname}\r\n").as_bytes())
                 .await?;
             self.stream.flush().await?;
             self.read_ehlo().await
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .unwrap_or_else(|_| Err(self.timeout_error(TimeoutPhase::Command)));
+        let response = self.end_exchange(in_flight, result)?;
+        self.capabilities = Some(response.clone());
+        Ok(response)
     }
 
     /// Sends a LHLO command to the server.
     pub async fn lhlo(&mut self, hostname: &str) -> crate::Result<EhloResponse<String>> {
-        tokio::time::timeout(self.timeout, async {
-            self.stream
-                .write_all(format!("LHLO {hostname}\r\n").as_bytes())
+        #[cfg(feature = "tracing")]
+        tracing::trace!(command = %format_args!("LHLO {hostname}"), "SMTP command");
+        let in_flight = self.begin_exchange();
+        let result = tokio::time::timeout(self.timeout, async {
+            self.write_bytes(format!("LHLO {hostname}\r\n").as_bytes())
                 .await?;
             self.stream.flush().await?;
             self.read_ehlo().await
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .unwrap_or_else(|_| Err(self.timeout_error(TimeoutPhase::Command)));
+        let response = self.end_exchange(in_flight, result)?;
+        self.capabilities = Some(response.clone());
+        Ok(response)
+    }
+
+    /// Returns the capabilities announced in the last successful EHLO/LHLO
+    /// exchange. These are discarded when the connection is upgraded to TLS.
+    pub fn capabilities_cached(&self) -> Option<&EhloResponse<String>> {
+        self.capabilities.as_ref()
+    }
+
+    /// Returns `false` only if the capabilities of the server are cached and
+    /// do not include the provided `EXT_*` extension.
+    pub(crate) fn may_use(&self, extension: u32) -> bool {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.has_capability(extension),
+            None => true,
+        }
+    }
+
+    /// Returns the hostname the server announced in the first line of its
+    /// last EHLO/LHLO response.
+    pub fn server_hostname(&self) -> Option<&str> {
+        self.capabilities
+            .as_ref()
+            .map(|capabilities| capabilities.hostname.as_str())
+    }
+
+    /// Returns the maximum message size announced with the SIZE extension,
+    /// if the server declared a limit.
+    pub fn max_size(&self) -> Option<usize> {
+        self.capability(EXT_SIZE)
+            .map(|capabilities| capabilities.size)
+            .filter(|&size| size > 0)
+    }
+
+    /// Returns the minimum delivery time accepted by the DELIVERBY extension.
+    pub fn deliver_by_min(&self) -> Option<Duration> {
+        self.capability(EXT_DELIVER_BY)
+            .map(|capabilities| Duration::from_secs(capabilities.deliver_by))
+    }
+
+    /// Returns the maximum hold time accepted by the FUTURERELEASE extension,
+    /// if the server declared a limit.
+    pub fn future_release_max(&self) -> Option<Duration> {
+        self.capability(EXT_FUTURE_RELEASE)
+            .map(|capabilities| capabilities.future_release_interval)
+            .filter(|&interval| interval > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Returns the priority assignment policy announced with the MT-PRIORITY
+    /// extension.
+    pub fn mt_priority_policy(&self) -> Option<MtPriority> {
+        self.capability(EXT_MT_PRIORITY)
+            .map(|capabilities| capabilities.mt_priority)
+    }
+
+    /// Returns the parameters of an extension advertised in the last
+    /// EHLO/LHLO response, including those not known to `EhloResponse`.
+    pub fn extension(&self, keyword: &str) -> Option<&str> {
+        self.extensions.iter().find_map(|line| {
+            let (name, params) = line.split_once(' ').unwrap_or((line, ""));
+            name.eq_ignore_ascii_case(keyword).then_some(params)
+        })
+    }
+
+    fn capability(&self, capability: u32) -> Option<&EhloResponse<String>> {
+        self.capabilities
+            .as_ref()
+            .filter(|capabilities| capabilities.has_capability(capability))
     }
 
     pub async fn read_ehlo(&mut self) -> crate::Result<EhloResponse<String>> {
         let mut buf = vec![0u8; 1024];
-        let mut buf_concat = Vec::with_capacity(0);
+        let mut response = Vec::new();
+        self.partial_reply.clear();
 
+        // EhloResponse::parse is not incremental, every partial read is
+        // appended and the response is parsed again from the start.
         loop {
-            let br = self.stream.read(&mut buf).await?;
+            let br = self.read_bytes(&mut buf).await?;
 
-            if br == 0 {
+            if br == 0 || response.len() + br > MAX_RESPONSE_LENGTH {
                 return Err(crate::Error::UnparseableReply);
             }
-            let mut iter = if buf_concat.is_empty() {
-                buf[..br].iter()
-            } else if br + buf_concat.len() < MAX_RESPONSE_LENGTH {
-                buf_concat.extend_from_slice(&buf[..br]);
-                buf_concat.iter()
-            } else {
-                return Err(crate::Error::UnparseableReply);
-            };
+            response.extend_from_slice(&buf[..br]);
+            let mut iter = response.iter();
 
             match EhloResponse::parse(&mut iter) {
-                Ok(reply) => return Ok(reply),
-                Err(err) => match err {
-                    smtp_proto::Error::NeedsMoreData { .. } => {
-                        if buf_concat.is_empty() {
-                            buf_concat = buf[..br].to_vec();
+                Ok(reply) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!(
+                        hostname = reply.hostname,
+                        capabilities = reply.capabilities,
+                        auth_mechanisms = reply.auth_mechanisms,
+                        "SMTP EHLO reply"
+                    );
+                    self.extensions = parse_extensions(&response);
+                    return Ok(reply);
+                }
+                Err(smtp_proto::Error::NeedsMoreData { .. }) => (),
+                Err(smtp_proto::Error::InvalidResponse { code }) => {
+                    match ResponseReceiver::from_code(code).parse(&mut iter) {
+                        Ok(response) => {
+                            #[cfg(feature = "tracing")]
+                            super::client::trace_reply(&response);
+                            return Err(crate::Error::UnexpectedReply(response));
                         }
+                        Err(smtp_proto::Error::NeedsMoreData { .. }) => (),
+                        Err(_) => return Err(crate::Error::UnparseableReply),
                     }
-                    smtp_proto::Error::InvalidResponse { code } => {
-                        match ResponseReceiver::from_code(code).parse(&mut iter) {
-                            Ok(response) => {
-                                return Err(crate::Error::UnexpectedReply(response));
-                            }
-                            Err(smtp_proto::Error::NeedsMoreData { .. }) => {
-                                if buf_concat.is_empty() {
-                                    buf_concat = buf[..br].to_vec();
-                                }
-                            }
-                            Err(_) => return Err(crate::Error::UnparseableReply),
-                        }
-                    }
-                    _ => {
-                        return Err(crate::Error::UnparseableReply);
-                    }
-                },
+                }
+                Err(_) => return Err(crate::Error::UnparseableReply),
             }
         }
     }
 }
+
+/// Returns the keyword and parameters of each EHLO line after the greeting,
+/// including extensions unknown to the parser such as XCLIENT.
+fn parse_extensions(response: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(response)
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.get(4..))
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use smtp_proto::{EhloResponse, MtPriority, AUTH_LOGIN, AUTH_PLAIN, EXT_PIPELINING};
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use crate::SmtpClient;
+
+    fn test_client(stream: DuplexStream) -> SmtpClient<DuplexStream> {
+        SmtpClient::new(stream, Duration::from_secs(30))
+    }
+
+    #[tokio::test]
+    async fn ehlo_segments() {
+        // Capability lines split mid-line across three reads
+        let segments = [
+            "250-mx.example.com Hello\r\n250-PIPE",
+            "LINING\r\n250-SIZE 1048",
+            "5760\r\n250 AUTH PLAIN LOGIN\r\n",
+        ];
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = test_client(stream);
+        let reader = tokio::spawn(async move { client.read_ehlo().await });
+        for segment in segments {
+            server.write_all(segment.as_bytes()).await.unwrap();
+            server.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let reply = reader.await.unwrap().unwrap();
+        assert_eq!(reply.hostname, "mx.example.com");
+        assert!(reply.has_capability(EXT_PIPELINING));
+        assert_eq!(reply.size, 10485760);
+        assert_eq!(reply.auth_mechanisms, AUTH_PLAIN | AUTH_LOGIN);
+
+        // Responses exceeding the maximum length are rejected
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = test_client(stream);
+        let reader = tokio::spawn(async move { client.read_ehlo().await });
+        server.write_all(b"250-mx.example.com\r\n").await.unwrap();
+        let line = format!("250-X-{}\r\n", "A".repeat(1000));
+        while !reader.is_finished() {
+            if server.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            reader.await.unwrap(),
+            Err(crate::Error::UnparseableReply)
+        ));
+    }
+
+    #[test]
+    fn numeric_capabilities() {
+        let mut client = test_client(tokio::io::duplex(1).0);
+        assert_eq!(client.max_size(), None);
+        assert_eq!(client.server_hostname(), None);
+
+        client.capabilities = Some(
+            EhloResponse::parse(
+                &mut concat!(
+                    "250-mx.example.com\r\n",
+                    "250-SIZE 10485760\r\n",
+                    "250-DELIVERBY 240\r\n",
+                    "250-FUTURERELEASE 604800 2040-01-01T00:00:00Z\r\n",
+                    "250 MT-PRIORITY STANAG4406\r\n"
+                )
+                .as_bytes()
+                .iter(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(client.server_hostname(), Some("mx.example.com"));
+        assert_eq!(client.max_size(), Some(10485760));
+        assert_eq!(client.deliver_by_min(), Some(Duration::from_secs(240)));
+        assert_eq!(
+            client.future_release_max(),
+            Some(Duration::from_secs(604800))
+        );
+        assert_eq!(client.mt_priority_policy(), Some(MtPriority::Stanag4406));
+
+        client.capabilities =
+            Some(EhloResponse::parse(&mut b"250-mx.example.com\r\n250 SIZE\r\n".iter()).unwrap());
+        assert_eq!(client.max_size(), None);
+        assert_eq!(client.deliver_by_min(), None);
+        assert_eq!(client.mt_priority_policy(), None);
+    }
+}
@@ -8,54 +8,165 @@
  * except according to those terms.
  */
 
+use smtp_proto::{Response, EXT_ATRN, EXT_CHUNKING};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::SmtpClient;
+use crate::{Accepted, SmtpClient, TimeoutPhase};
 
 use super::{message::Parameters, AssertReply};
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
     /// Sends a MAIL FROM command to the server.
     pub async fn mail_from(&mut self, addr: &str, params: &Parameters<'_>) -> crate::Result<()> {
-        self.cmd(format!("MAIL FROM:<{addr}>{params}\r\n").as_bytes())
+        self.mail_from_response(addr, params)
             .await?
             .assert_positive_completion()
     }
 
+    /// Sends a MAIL FROM command to the server and returns its reply
+    /// without checking the status code.
+    pub async fn mail_from_response(
+        &mut self,
+        addr: &str,
+        params: &Parameters<'_>,
+    ) -> crate::Result<Response<String>> {
+        validate_address(addr)?;
+        self.cmd(format!("MAIL FROM:<{addr}>{params}\r\n").as_bytes())
+            .await
+    }
+
     /// Sends a RCPT TO command to the server.
     pub async fn rcpt_to(&mut self, addr: &str, params: &Parameters<'_>) -> crate::Result<()> {
-        self.cmd(format!("RCPT TO:<{addr}>{params}\r\n").as_bytes())
+        self.rcpt_to_response(addr, params)
             .await?
             .assert_positive_completion()
     }
 
-    /// Sends a DATA command to the server.
-    pub async fn data(&mut self, message: impl AsRef<[u8]>) -> crate::Result<()> {
+    /// Sends a RCPT TO command to the server and returns its reply
+    /// without checking the status code.
+    pub async fn rcpt_to_response(
+        &mut self,
+        addr: &str,
+        params: &Parameters<'_>,
+    ) -> crate::Result<Response<String>> {
+        validate_address(addr)?;
+        self.cmd(format!("RCPT TO:<{addr}>{params}\r\n").as_bytes())
+            .await
+    }
+
+    /// Sends a DATA command to the server followed by the message, returning
+    /// how the server accepted it.
+    ///
+    /// If the future is dropped before completion the client is marked as
+    /// poisoned, see `is_poisoned`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(size = message.as_ref().len()))
+    )]
+    pub async fn data(&mut self, message: impl AsRef<[u8]>) -> crate::Result<Accepted> {
         self.cmd(b"DATA\r\n").await?.assert_code(354)?;
-        tokio::time::timeout(self.timeout, async {
+        self.write_data(message.as_ref()).await?.try_into()
+    }
+
+    /// Sends a DATA command to the server followed by the message and returns
+    /// the final reply without checking the status code. If the server does
+    /// not reply to DATA with a 354 code, the message is not sent and that
+    /// reply is returned instead.
+    pub async fn data_response(
+        &mut self,
+        message: impl AsRef<[u8]>,
+    ) -> crate::Result<Response<String>> {
+        let response = self.cmd(b"DATA\r\n").await?;
+        if response.code() == 354 {
+            self.write_data(message.as_ref()).await
+        } else {
+            Ok(response)
+        }
+    }
+
+    /// Sends a DATA command to the server followed by a message that has
+    /// already been dot-stuffed. The message is written verbatim and only the
+    /// end of data indicator is appended, `.\r\n` when the message is empty
+    /// or ends with `\r\n` and `\r\n.\r\n` otherwise: neither the
+    /// transparency procedure nor line ending normalization are applied. A
+    /// line starting with an
+    /// unstuffed dot is misread by the server, possibly as the end of the
+    /// message followed by commands; use `data` unless the message is known to
+    /// be stuffed.
+    pub async fn data_raw(&mut self, message: impl AsRef<[u8]>) -> crate::Result<Accepted> {
+        self.cmd(b"DATA\r\n").await?.assert_code(354)?;
+        let message = message.as_ref();
+        let terminator: &[u8] = if message.is_empty() || message.ends_with(b"\r\n") {
+            b".\r\n"
+        } else {
+            b"\r\n.\r\n"
+        };
+        let in_flight = self.begin_exchange();
+        let result = tokio::time::timeout(self.timeout, async {
+            self.write_bytes(message).await?;
+            self.write_bytes(terminator).await?;
+            self.stream.flush().await?;
+            self.read().await
+        })
+        .await
+        .unwrap_or_else(|_| Err(self.timeout_error(TimeoutPhase::Data)));
+        self.end_exchange(in_flight, result)?.try_into()
+    }
+
+    pub(crate) async fn write_data(&mut self, message: &[u8]) -> crate::Result<Response<String>> {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(size = message.len(), "SMTP message");
+        let in_flight = self.begin_exchange();
+        let result = tokio::time::timeout(self.timeout, async {
             // Write message
-            self.write_message(message.as_ref()).await?;
+            self.write_message(message).await?;
             self.read().await
         })
         .await
-        .map_err(|_| crate::Error::Timeout)??
-        .assert_positive_completion()
+        .unwrap_or_else(|_| Err(self.timeout_error(TimeoutPhase::Data)));
+        self.end_exchange(in_flight, result)
     }
 
     /// Sends a BDAT command to the server.
-    pub async fn bdat(&mut self, message: impl AsRef<[u8]>) -> crate::Result<()> {
-        let message = message.as_ref();
-        tokio::time::timeout(self.timeout, async {
-            self.stream
-                .write_all(format!("BDAT {} LAST\r\n", message.len()).as_bytes())
-                .await?;
-            self.stream.write_all(message).await?;
+    pub async fn bdat(&mut self, message: impl AsRef<[u8]>) -> crate::Result<Accepted> {
+        self.write_bdat(message.as_ref(), true).await?.try_into()
+    }
+
+    /// Sends a message chunk with BDAT, allowing the message to be streamed
+    /// without knowing its total length. The final chunk, which may be
+    /// empty, must be sent with `last` set. Fails with `UnsupportedExtension`
+    /// if the last EHLO response did not advertise CHUNKING (RFC 3030).
+    pub async fn bdat_chunk(&mut self, chunk: &[u8], last: bool) -> crate::Result<()> {
+        self.write_bdat(chunk, last)
+            .await?
+            .assert_positive_completion()
+    }
+
+    pub(crate) async fn write_bdat(
+        &mut self,
+        chunk: &[u8],
+        last: bool,
+    ) -> crate::Result<Response<String>> {
+        if !self.may_use(EXT_CHUNKING) {
+            return Err(crate::Error::UnsupportedExtension("CHUNKING"));
+        }
+        let cmd = if last {
+            format!("BDAT {} LAST\r\n", chunk.len())
+        } else {
+            format!("BDAT {}\r\n", chunk.len())
+        };
+        #[cfg(feature = "tracing")]
+        tracing::trace!(command = cmd.trim_end(), "SMTP command");
+        let in_flight = self.begin_exchange();
+        let result = tokio::time::timeout(self.timeout, async {
+            self.write_bytes(cmd.as_bytes()).await?;
+            self.write_chunked(chunk).await?;
             self.stream.flush().await?;
             self.read().await
         })
         .await
-        .map_err(|_| crate::Error::Timeout)??
-        .assert_positive_completion()
+        .unwrap_or_else(|_| Err(self.timeout_error(TimeoutPhase::Data)));
+        self.end_exchange(in_flight, result)
     }
 
     /// Sends a RSET command to the server.
@@ -68,8 +179,338 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         self.cmd(b"NOOP\r\n").await?.assert_positive_completion()
     }
 
-    /// Sends a QUIT command to the server.
+    /// Sends an ATRN command (RFC 2645) requesting the mail queued for
+    /// `domains`, or for all the domains the client is authorized for when
+    /// empty. Fails with `UnsupportedExtension` if the cached capabilities
+    /// of the server do not include ATRN.
+    ///
+    /// After a positive reply the roles are reversed and the server starts
+    /// the session by sending its greeting, which is left to the caller.
+    pub async fn atrn(&mut self, domains: &[&str]) -> crate::Result<Response<String>> {
+        if !self.may_use(EXT_ATRN) {
+            return Err(crate::Error::UnsupportedExtension("ATRN"));
+        }
+
+        let mut cmd = String::from("ATRN");
+        for (pos, domain) in domains.iter().enumerate() {
+            if domain.is_empty()
+                || domain
+                    .bytes()
+                    .any(|ch| ch.is_ascii_control() || ch == b' ' || ch == b',')
+            {
+                return Err(crate::Error::InvalidAddress);
+            }
+            cmd.push(if pos == 0 { ' ' } else { ',' });
+            cmd.push_str(domain);
+        }
+        cmd.push_str("\r\n");
+        self.cmd(cmd.as_bytes()).await
+    }
+
+    /// Sends a QUIT command to the server and shuts down the connection.
     pub async fn quit(mut self) -> crate::Result<()> {
+        let result = self.try_quit().await;
+        let _ = self.stream.shutdown().await;
+        result
+    }
+
+    /// Sends a QUIT command to the server without consuming the client,
+    /// which is left to the caller to inspect, retry or drop.
+    pub async fn try_quit(&mut self) -> crate::Result<()> {
         self.cmd(b"QUIT\r\n").await?.assert_positive_completion()
     }
 }
+
+/// Parses the final reply to a message, failing with `UnexpectedReply`
+/// unless it is a positive completion. Queue IDs are recognized in the
+/// `queued as <id>` form used by Postfix and Sendmail and the `id=<id>`
+/// form used by Exim.
+impl TryFrom<Response<String>> for Accepted {
+    type Error = crate::Error;
+
+    fn try_from(reply: Response<String>) -> crate::Result<Self> {
+        match reply.code() {
+            251 | 252 => Ok(Accepted::Forwarded),
+            200..=299 => {
+                Ok(queue_id(reply.message()).map_or(Accepted::Delivered, Accepted::Queued))
+            }
+            _ => Err(crate::Error::UnexpectedReply(reply)),
+        }
+    }
+}
+
+fn queue_id(message: &str) -> Option<String> {
+    let lowercase = message.to_ascii_lowercase();
+    let start = ["queued as ", " id="]
+        .iter()
+        .find_map(|prefix| lowercase.find(prefix).map(|pos| pos + prefix.len()))?;
+    let id = message[start..]
+        .split(|ch: char| ch.is_whitespace() || matches!(ch, ',' | ';' | ')'))
+        .next()?
+        .trim_end_matches('.');
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Rejects addresses containing characters that could be used to inject
+/// SMTP commands or break out of the `<...>` path delimiters.
+pub(crate) fn validate_address(addr: &str) -> crate::Result<()> {
+    if addr
+        .bytes()
+        .any(|ch| ch.is_ascii_control() || ch == b'<' || ch == b'>')
+    {
+        Err(crate::Error::InvalidAddress)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use smtp_proto::{EhloResponse, Response, EXT_ATRN};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{smtp::mock::MockServer, Accepted, SmtpClient};
+
+    use super::validate_address;
+
+    #[tokio::test]
+    async fn bdat_chunks() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for expected in [
+                &b"BDAT 13\r\nSubject: Hi\r\n"[..],
+                b"BDAT 7\r\n\r\nHello",
+                b"BDAT 0 LAST\r\n",
+            ] {
+                let mut buf = vec![0u8; expected.len()];
+                server.read_exact(&mut buf).await.unwrap();
+                received.push(buf);
+                server.write_all(b"250 OK\r\n").await.unwrap();
+            }
+            received
+        });
+
+        client.bdat_chunk(b"Subject: Hi\r\n", false).await.unwrap();
+        client.bdat_chunk(b"\r\nHello", false).await.unwrap();
+        client.bdat_chunk(b"", true).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                &b"BDAT 13\r\nSubject: Hi\r\n"[..],
+                b"BDAT 7\r\n\r\nHello",
+                b"BDAT 0 LAST\r\n",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn bdat_requires_chunking() {
+        let mut client = SmtpClient::new(tokio::io::duplex(1024).0, Duration::from_secs(30));
+        client.capabilities = Some(EhloResponse::new("mx.example.com".to_string()));
+        assert!(matches!(
+            client.bdat(b"Subject: Hi\r\n\r\nHello").await,
+            Err(crate::Error::UnsupportedExtension("CHUNKING"))
+        ));
+        assert!(!client.is_poisoned());
+    }
+
+    #[tokio::test]
+    async fn data_raw() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.normalize_line_endings = true;
+
+        let expected = b"DATA\r\nSubject: Hi\r\n\r\n..Hello\n\r\n.\r\n";
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0u8; 6];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(b"354 Go ahead\r\n").await.unwrap();
+            buf.resize(expected.len(), 0);
+            server.read_exact(&mut buf[6..]).await.unwrap();
+            server.write_all(b"250 Queued\r\n").await.unwrap();
+            buf
+        });
+
+        client
+            .data_raw(b"Subject: Hi\r\n\r\n..Hello\n")
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn data_raw_terminator() {
+        for (message, expected) in [
+            (
+                &b"Subject: Hi\r\n\r\nHello\r\n"[..],
+                &b"DATA\r\nSubject: Hi\r\n\r\nHello\r\n.\r\n"[..],
+            ),
+            (
+                b"Subject: Hi\r\n\r\nHello",
+                b"DATA\r\nSubject: Hi\r\n\r\nHello\r\n.\r\n",
+            ),
+            (b"", b"DATA\r\n.\r\n"),
+        ] {
+            let (stream, mut server) = tokio::io::duplex(4096);
+            let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+            let server = tokio::spawn(async move {
+                let mut buf = vec![0u8; 6];
+                server.read_exact(&mut buf).await.unwrap();
+                server.write_all(b"354 Go ahead\r\n").await.unwrap();
+                buf.resize(expected.len(), 0);
+                server.read_exact(&mut buf[6..]).await.unwrap();
+                server.write_all(b"250 Queued\r\n").await.unwrap();
+                // Nothing is written after the terminator
+                server.read_to_end(&mut buf).await.unwrap();
+                buf
+            });
+
+            client.data_raw(message).await.unwrap();
+            drop(client);
+            assert_eq!(server.await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn try_quit() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        let server = tokio::spawn(async move {
+            for reply in [&b"451 4.3.0 Try again\r\n"[..], b"221 Bye\r\n"] {
+                let mut buf = [0u8; 6];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"QUIT\r\n");
+                server.write_all(reply).await.unwrap();
+            }
+        });
+
+        assert!(matches!(
+            client.try_quit().await,
+            Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 451
+        ));
+        client.try_quit().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn atrn() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.capabilities = Some(EhloResponse::new("mx.example.com".to_string()));
+        assert!(matches!(
+            client.atrn(&["example.org"]).await,
+            Err(crate::Error::UnsupportedExtension("ATRN"))
+        ));
+
+        let mut capabilities = EhloResponse::new("mx.example.com".to_string());
+        capabilities.capabilities = EXT_ATRN;
+        client.capabilities = Some(capabilities);
+        assert!(matches!(
+            client.atrn(&["example.org\r\nQUIT"]).await,
+            Err(crate::Error::InvalidAddress)
+        ));
+
+        let server = tokio::spawn(async move {
+            let mut transcript = Vec::new();
+            for (expected, reply) in [
+                (
+                    &b"ATRN example.org,example.net\r\n"[..],
+                    &b"250 OK now reversing the connection\r\n"[..],
+                ),
+                (b"ATRN\r\n", b"453 You have no mail\r\n"),
+            ] {
+                let mut buf = vec![0u8; expected.len()];
+                server.read_exact(&mut buf).await.unwrap();
+                transcript.push(buf);
+                server.write_all(reply).await.unwrap();
+            }
+            transcript
+        });
+
+        let response = client.atrn(&["example.org", "example.net"]).await.unwrap();
+        assert_eq!(response.code(), 250);
+
+        // ATRN is also sent when the capabilities are unknown
+        client.capabilities = None;
+        assert_eq!(client.atrn(&[]).await.unwrap().code(), 453);
+        assert_eq!(
+            server.await.unwrap(),
+            [&b"ATRN example.org,example.net\r\n"[..], b"ATRN\r\n"]
+        );
+    }
+
+    #[test]
+    fn address_validation() {
+        for addr in ["john@example.com", "", "\"john doe\"@example.com"] {
+            assert!(validate_address(addr).is_ok(), "{addr:?}");
+        }
+
+        for addr in [
+            "foo@bar\r\nDATA",
+            "foo@bar\nRCPT TO:<evil@example.com>",
+            "foo@bar\r",
+            "foo@bar>\r\nRSET",
+            "<foo@bar",
+            "foo\0@bar",
+            "foo\t@bar",
+        ] {
+            assert!(
+                matches!(validate_address(addr), Err(crate::Error::InvalidAddress)),
+                "{addr:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn accepted() {
+        for (code, message, expected) in [
+            (
+                250,
+                "2.0.0 Ok: queued as 4B2A1C",
+                Accepted::Queued("4B2A1C".into()),
+            ),
+            (
+                250,
+                "OK id=1rAbCd-0001Xy-2Q",
+                Accepted::Queued("1rAbCd-0001Xy-2Q".into()),
+            ),
+            (
+                250,
+                "2.0.0 x9G1A2b3 Message accepted for delivery",
+                Accepted::Delivered,
+            ),
+            (250, "Queued as", Accepted::Delivered),
+            (251, "User not local; will forward", Accepted::Forwarded),
+            (
+                252,
+                "Cannot verify user, will attempt delivery",
+                Accepted::Forwarded,
+            ),
+        ] {
+            let reply = Response::new(code, 2, 0, 0, message.to_string());
+            assert_eq!(Accepted::try_from(reply).unwrap(), expected, "{message}");
+        }
+        assert!(matches!(
+            Accepted::try_from(Response::new(554, 5, 0, 0, "Rejected".to_string())),
+            Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 554
+        ));
+
+        let (mut client, server) = MockServer::new()
+            .expect("DATA", "354 Go ahead")
+            .expect_data("251 2.1.5 User not local; will forward")
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(
+            client.data(b"Subject: Hi\r\n\r\nHello").await.unwrap(),
+            Accepted::Forwarded
+        );
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -11,6 +11,8 @@
 use std::{
     borrow::Cow,
     fmt::{Debug, Display},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[cfg(feature = "builder")]
@@ -20,9 +22,16 @@ use mail_builder::{
 };
 #[cfg(feature = "parser")]
 use mail_parser::{HeaderName, HeaderValue};
+use smtp_proto::{
+    Response, EXT_8BIT_MIME, EXT_BINARY_MIME, EXT_CHUNKING, EXT_DELIVER_BY, EXT_FUTURE_RELEASE,
+    EXT_MT_PRIORITY, EXT_PIPELINING, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER,
+    RCPT_NOTIFY_SUCCESS,
+};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::SmtpClient;
+use crate::{Accepted, LineLengthPolicy, SmtpClient};
+
+use super::{client::is_session_closed, envelope::validate_address, AssertReply};
 
 #[derive(Debug, Default)]
 pub struct Message<'x> {
@@ -35,6 +44,7 @@ pub struct Message<'x> {
 pub struct Address<'x> {
     pub email: Cow<'x, str>,
     pub parameters: Parameters<'x>,
+    pub is_blind: bool,
 }
 
 #[derive(Debug, Default)]
@@ -42,6 +52,27 @@ pub struct Parameters<'x> {
     params: Vec<Parameter<'x>>,
 }
 
+/// Action to take when a message cannot be delivered within the DELIVERBY
+/// time (RFC 2852).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliverByMode {
+    /// Continue delivery and send a delay notification.
+    Notify,
+    /// Return the message as undeliverable.
+    Return,
+}
+
+/// Placement of the headers prepended to a signed message relative to its
+/// DKIM-Signature header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderPosition {
+    /// Below the signature, the headers can be covered by it.
+    #[default]
+    BelowSignature,
+    /// Above the signature, the headers are not signed.
+    AboveSignature,
+}
+
 #[derive(Debug, Default)]
 pub struct Parameter<'x> {
     key: Cow<'x, str>,
@@ -49,62 +80,668 @@ pub struct Parameter<'x> {
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
-    /// Sends a message to the server.
-    pub async fn send<'x>(&mut self, message: impl IntoMessage<'x>) -> crate::Result<()> {
-        // Send mail-from
-        let message = message.into_message()?;
-        self.mail_from(
-            message.mail_from.email.as_ref(),
-            &message.mail_from.parameters,
-        )
-        .await?;
+    /// Sends a message to the server, returning how it was accepted.
+    ///
+    /// If `reset_on_error` is enabled, a RSET is issued after a recoverable
+    /// failure so that the connection remains usable.
+    ///
+    /// This method is not cancellation safe: if the future is dropped before
+    /// completion the client is marked as poisoned and should be discarded.
+    ///
+    /// When the server advertises PIPELINING, the MAIL FROM, RCPT TO and DATA
+    /// commands are sent as a single group. If the server accepts DATA even
+    /// though the sender or a recipient was rejected, the connection is shut
+    /// down so that no message is delivered.
+    ///
+    /// When `max_rcpt_per_message` is set, the recipients are split into
+    /// several transactions and the first failed one is reported; use
+    /// `send_batched` to obtain the result of each recipient.
+    ///
+    /// When `probe_before_send` is enabled, a NOOP is issued first and a
+    /// connection closed by the server while idle is reported as
+    /// [`crate::Error::ConnectionClosed`], in which case a new connection
+    /// should be established.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, err(Display))
+    )]
+    pub async fn send<'x>(&mut self, message: impl IntoMessage<'x>) -> crate::Result<Accepted> {
+        self.send_tracked(message).await?.try_into()
+    }
 
-        // Send rcpt-to
-        for rcpt in &message.rcpt_to {
-            self.rcpt_to(rcpt.email.as_ref(), &rcpt.parameters).await?;
-        }
+    /// Sends a message like `send`, returning the server's final reply to
+    /// the message, which often includes the queue ID assigned to it (e.g.
+    /// `250 2.0.0 Ok: queued as 4B2A1C`). When the recipients are split
+    /// across several transactions, the reply to the last one is returned.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, err(Display))
+    )]
+    pub async fn send_tracked<'x>(
+        &mut self,
+        message: impl IntoMessage<'x>,
+    ) -> crate::Result<Response<String>> {
+        let (message, use_bdat) = self.prepare_message(message)?;
+        last_reply(
+            self.transmit_batches(&message, message.body.as_ref(), use_bdat)
+                .await,
+        )
+    }
 
-        // Send message
-        self.data(message.body.as_ref()).await
+    /// Sends a message with `headers`, such as `Received` trace headers,
+    /// prepended in the given order.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, err(Display))
+    )]
+    pub async fn send_with_headers<'x>(
+        &mut self,
+        message: impl IntoMessage<'x>,
+        headers: &[(&str, &str)],
+    ) -> crate::Result<Accepted> {
+        let (message, use_bdat) = self.prepare_message(message)?;
+        let body = prepend_headers(headers, message.body.as_ref())?;
+        last_reply(self.transmit_batches(&message, &body, use_bdat).await)?.try_into()
     }
 
-    /// Sends a message to the server.
+    /// Sends a message to the server, signed with DKIM.
+    ///
+    /// The signature timestamp (`t=`) and expiration (`x=`) are computed by
+    /// `mail-auth` from the system clock at the time of signing; it does not
+    /// accept a caller-provided time, so signatures are not reproducible.
     #[cfg(feature = "dkim")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, err(Display))
+    )]
     pub async fn send_signed<'x, V: mail_auth::common::crypto::SigningKey>(
         &mut self,
         message: impl IntoMessage<'x>,
         signer: &mail_auth::dkim::DkimSigner<V, mail_auth::dkim::Done>,
-    ) -> crate::Result<()> {
-        // Send mail-from
+    ) -> crate::Result<Accepted> {
+        self.send_signed_with_headers(message, &[], signer).await
+    }
 
-        use mail_auth::common::headers::HeaderWriter;
-        let message = message.into_message()?;
-        self.mail_from(
-            message.mail_from.email.as_ref(),
-            &message.mail_from.parameters,
-        )
-        .await?;
+    /// Sends a message with `headers` prepended as `send_with_headers` does,
+    /// signing the result. The DKIM-Signature header is placed topmost, so
+    /// the prepended headers are covered by the signature if the signer
+    /// lists them.
+    #[cfg(feature = "dkim")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, err(Display))
+    )]
+    pub async fn send_signed_with_headers<'x, V: mail_auth::common::crypto::SigningKey>(
+        &mut self,
+        message: impl IntoMessage<'x>,
+        headers: &[(&str, &str)],
+        signer: &mail_auth::dkim::DkimSigner<V, mail_auth::dkim::Done>,
+    ) -> crate::Result<Accepted> {
+        self.send_signed_with_headers_at(message, headers, signer, HeaderPosition::BelowSignature)
+            .await
+    }
 
-        // Send rcpt-to
-        for rcpt in &message.rcpt_to {
-            self.rcpt_to(rcpt.email.as_ref(), &rcpt.parameters).await?;
-        }
+    /// Sends a signed message with `headers` prepended either below the
+    /// DKIM-Signature header, as `send_signed_with_headers` does, or above
+    /// it, in which case they are not signed. Relays adding a `Received`
+    /// header to a message they sign usually place it above.
+    #[cfg(feature = "dkim")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, err(Display))
+    )]
+    pub async fn send_signed_with_headers_at<'x, V: mail_auth::common::crypto::SigningKey>(
+        &mut self,
+        message: impl IntoMessage<'x>,
+        headers: &[(&str, &str)],
+        signer: &mail_auth::dkim::DkimSigner<V, mail_auth::dkim::Done>,
+        position: HeaderPosition,
+    ) -> crate::Result<Accepted> {
+        use mail_auth::common::headers::HeaderWriter;
+        let (message, use_bdat) = self.prepare_message(message)?;
+        let body = match position {
+            HeaderPosition::BelowSignature => prepend_headers(headers, message.body.as_ref())?,
+            HeaderPosition::AboveSignature => {
+                // Fail on invalid headers before signing
+                prepend_headers(headers, b"")?;
+                message.body.as_ref().into()
+            }
+        };
 
         // Sign message
         let signature = signer
-            .sign(message.body.as_ref())
+            .sign(&body)
             .map_err(|_| crate::Error::MissingCredentials)?;
-        let mut signed_message = Vec::with_capacity(message.body.len() + 64);
+        let mut signed_message = Vec::with_capacity(body.len() + 64);
         signature.write_header(&mut signed_message);
-        signed_message.extend_from_slice(message.body.as_ref());
+        signed_message.extend_from_slice(&body);
+        let signed_message = match position {
+            HeaderPosition::BelowSignature => signed_message,
+            HeaderPosition::AboveSignature => {
+                prepend_headers(headers, &signed_message)?.into_owned()
+            }
+        };
+
+        last_reply(
+            self.transmit_batches(&message, &signed_message, use_bdat)
+                .await,
+        )?
+        .try_into()
+    }
 
-        // Send message
-        self.data(&signed_message).await
+    /// Sends `body` with MAIL FROM `from` and a RCPT TO for each address in
+    /// `to`, as a single transaction, returning the server's final reply.
+    /// The parameters are sent as given and the body is not altered other
+    /// than by `normalize_line_endings`; BDAT is used when `from_params`
+    /// declares `BODY=BINARYMIME`. Intended for relays that already hold
+    /// the envelope and the raw message.
+    pub async fn send_raw(
+        &mut self,
+        from: &str,
+        to: &[&str],
+        body: &[u8],
+        from_params: &Parameters<'_>,
+        rcpt_params: &Parameters<'_>,
+    ) -> crate::Result<Response<String>> {
+        if to.is_empty() {
+            return Err(crate::Error::MissingRcptTo);
+        }
+        let use_bdat = from_params
+            .value("BODY")
+            .is_some_and(|body| body.eq_ignore_ascii_case("BINARYMIME"));
+        let rcpt_to = to
+            .iter()
+            .map(|rcpt| (*rcpt, rcpt_params))
+            .collect::<Vec<_>>();
+        self.probe().await?;
+        self.transmit((from, from_params), &rcpt_to, body, use_bdat)
+            .await
     }
 
+    /// Sends a message in transactions of at most `max_rcpt_per_message`
+    /// recipients, issuing a RSET between them, and returns the result of
+    /// the transaction each recipient was part of. A recipient rejected by
+    /// the server fails its whole transaction, as with `send`. Once the
+    /// session is closed the remaining recipients are reported as
+    /// [`crate::Error::NotAttempted`].
+    pub async fn send_batched<'x>(
+        &mut self,
+        message: impl IntoMessage<'x>,
+    ) -> crate::Result<Vec<(Address<'x>, crate::Result<Accepted>)>> {
+        let (message, use_bdat) = self.prepare_message(message)?;
+        Ok(self.transmit_per_recipient(message, use_bdat).await)
+    }
+
+    /// Sends one body to many recipients over the same connection, splitting
+    /// them into transactions of at most `max_rcpt_per_message` recipients.
+    /// The body is kept in memory once and uploaded again with each DATA or
+    /// BDAT. Returns the result of each recipient, as with `send_batched`.
+    pub async fn send_bulk<'x>(
+        &mut self,
+        from: impl Into<Address<'x>>,
+        recipients: impl IntoIterator<Item = impl Into<Address<'x>>>,
+        body: impl Into<Cow<'x, [u8]>>,
+    ) -> Vec<(Address<'x>, crate::Result<Accepted>)> {
+        let mut message = Message {
+            mail_from: from.into(),
+            rcpt_to: recipients.into_iter().map(Into::into).collect(),
+            body: body.into(),
+        };
+        match self.apply_settings(&mut message) {
+            Ok(use_bdat) => self.transmit_per_recipient(message, use_bdat).await,
+            Err(err) => message
+                .rcpt_to
+                .into_iter()
+                .map(|rcpt| (rcpt, Err(err.clone())))
+                .collect(),
+        }
+    }
+
+    async fn transmit_per_recipient<'x>(
+        &mut self,
+        message: Message<'x>,
+        use_bdat: bool,
+    ) -> Vec<(Address<'x>, crate::Result<Accepted>)> {
+        let results = self
+            .transmit_batches(&message, message.body.as_ref(), use_bdat)
+            .await;
+        let batch_size = self.batch_size();
+
+        message
+            .rcpt_to
+            .into_iter()
+            .enumerate()
+            .map(|(idx, rcpt)| {
+                let result = match &results[idx / batch_size] {
+                    Ok(reply) => reply.clone().try_into(),
+                    Err(err) => Err(err.clone()),
+                };
+                (rcpt, result)
+            })
+            .collect()
+    }
+
+    /// Sends a message and closes the session with QUIT, for one-shot
+    /// deliveries that do not reuse the connection. QUIT is also sent when
+    /// the message is rejected, in which case the send error is returned.
+    pub async fn send_and_quit<'x>(
+        mut self,
+        message: impl IntoMessage<'x>,
+    ) -> crate::Result<Accepted> {
+        match self.send(message).await {
+            Ok(accepted) => self.quit().await.map(|_| accepted),
+            Err(err) => {
+                if !is_session_closed(&err) && !self.poisoned {
+                    let _ = self.quit().await;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the message as `send` would transmit it with the current
+    /// settings, including any added `Date` and `Message-ID` headers, line
+    /// wrapping and line ending normalization, suitable for writing to an
+    /// `.eml` file.
+    pub fn to_eml<'x>(&self, message: impl IntoMessage<'x>) -> crate::Result<Vec<u8>> {
+        let mut message = message.into_message()?;
+        if self.add_missing_headers {
+            message.add_missing_headers();
+        }
+        self.apply_line_length_policy(&mut message.body)?;
+        Ok(if self.normalize_line_endings {
+            normalize_line_endings(&message.body).into_owned()
+        } else {
+            message.to_eml()
+        })
+    }
+
+    /// Sends multiple messages over the same connection, issuing a RSET
+    /// between them, and returns one result per message. Sending stops once
+    /// the session can no longer be used: the server closed it (for example
+    /// with a 421 reply), the connection failed or the client was poisoned
+    /// by an aborted transaction. The remaining messages are then reported
+    /// as [`crate::Error::NotAttempted`].
+    pub async fn send_all<'x>(
+        &mut self,
+        messages: impl IntoIterator<Item = impl IntoMessage<'x>>,
+    ) -> Vec<crate::Result<Accepted>> {
+        let messages = messages.into_iter();
+        let mut results = Vec::with_capacity(messages.size_hint().0);
+        let mut is_closed = false;
+
+        for message in messages {
+            if is_closed {
+                results.push(Err(crate::Error::NotAttempted));
+                continue;
+            }
+
+            let result = if !results.is_empty() {
+                match self.rset().await {
+                    Ok(_) => self.send(message).await,
+                    Err(err) => Err(err),
+                }
+            } else {
+                self.send(message).await
+            };
+            is_closed = self.poisoned || result.as_ref().is_err_and(is_session_closed);
+            results.push(result);
+        }
+
+        results
+    }
+
+    /// Converts the message and applies the client settings to it, returning
+    /// whether it has to be sent using BDAT.
+    fn prepare_message<'x>(
+        &self,
+        message: impl IntoMessage<'x>,
+    ) -> crate::Result<(Message<'x>, bool)> {
+        let mut message = message.into_message()?;
+        let use_bdat = self.apply_settings(&mut message)?;
+        Ok((message, use_bdat))
+    }
+
+    fn apply_settings(&self, message: &mut Message<'_>) -> crate::Result<bool> {
+        if self.add_missing_headers {
+            message.add_missing_headers();
+        }
+        self.check_parameters(message)?;
+        let use_bdat = self.declare_body_type(message)?;
+        if !message
+            .mail_from
+            .parameters
+            .value("BODY")
+            .is_some_and(|body| body.eq_ignore_ascii_case("BINARYMIME"))
+        {
+            self.apply_line_length_policy(&mut message.body)?;
+        }
+        Ok(use_bdat)
+    }
+
+    /// Refuses or wraps bodies with lines longer than allowed by RFC 5321,
+    /// as configured by `line_length_policy`.
+    fn apply_line_length_policy(&self, body: &mut Cow<'_, [u8]>) -> crate::Result<()> {
+        match self.line_length_policy {
+            LineLengthPolicy::Ignore => Ok(()),
+            LineLengthPolicy::Reject if has_long_line(body) => Err(crate::Error::LineTooLong),
+            LineLengthPolicy::Reject => Ok(()),
+            LineLengthPolicy::Wrap => {
+                if let Cow::Owned(wrapped) = wrap_long_lines(body) {
+                    *body = Cow::Owned(wrapped);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn batch_size(&self) -> usize {
+        self.max_rcpt_per_message
+            .filter(|&max| max > 0)
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Sends the message in as many transactions as required by
+    /// `max_rcpt_per_message`, returning the result of each one.
+    async fn transmit_batches(
+        &mut self,
+        message: &Message<'_>,
+        body: &[u8],
+        use_bdat: bool,
+    ) -> Vec<crate::Result<Response<String>>> {
+        let mail_from = (
+            message.mail_from.email.as_ref(),
+            &message.mail_from.parameters,
+        );
+        let rcpt_to = message
+            .rcpt_to
+            .iter()
+            .map(|rcpt| (rcpt.email.as_ref(), &rcpt.parameters))
+            .collect::<Vec<_>>();
+        let batch_size = self.batch_size();
+        if let Err(err) = self.probe().await {
+            return vec![Err(err); rcpt_to.len().div_ceil(batch_size).max(1)];
+        }
+        if rcpt_to.len() <= batch_size {
+            return vec![self.transmit(mail_from, &rcpt_to, body, use_bdat).await];
+        }
+
+        let mut results = Vec::with_capacity(rcpt_to.len().div_ceil(batch_size));
+        let mut is_closed = false;
+        for rcpt_to in rcpt_to.chunks(batch_size) {
+            let result = if is_closed {
+                Err(crate::Error::NotAttempted)
+            } else if !results.is_empty() {
+                match self.rset().await {
+                    Ok(_) => self.transmit(mail_from, rcpt_to, body, use_bdat).await,
+                    Err(err) => Err(err),
+                }
+            } else {
+                self.transmit(mail_from, rcpt_to, body, use_bdat).await
+            };
+            is_closed = is_closed || self.poisoned || result.as_ref().is_err_and(is_session_closed);
+            results.push(result);
+        }
+
+        results
+    }
+
+    /// Refuses to start a transaction on a poisoned client or one that
+    /// violates the STARTTLS policy, and issues a NOOP when
+    /// `probe_before_send` is enabled, reporting a connection that was
+    /// closed by the server as `ConnectionClosed` rather than failing later
+    /// on MAIL FROM.
+    async fn probe(&mut self) -> crate::Result<()> {
+        self.check_starttls_policy()?;
+        if self.poisoned {
+            Err(crate::Error::ConnectionClosed)
+        } else if !self.probe_before_send {
+            Ok(())
+        } else {
+            match self.noop().await {
+                Err(err) if is_session_closed(&err) => Err(crate::Error::ConnectionClosed),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Sends the envelope followed by the message body.
+    async fn transmit(
+        &mut self,
+        mail_from: (&str, &Parameters<'_>),
+        rcpt_to: &[(&str, &Parameters<'_>)],
+        body: &[u8],
+        use_bdat: bool,
+    ) -> crate::Result<Response<String>> {
+        // The whole transaction is a single exchange, a client dropped
+        // halfway through it is left poisoned.
+        let in_flight = self.begin_exchange();
+        let result = async {
+            // Send mail-from and rcpt-to
+            self.send_envelope(mail_from, rcpt_to, use_bdat).await?;
+
+            // Send message
+            let response = if use_bdat {
+                self.write_bdat(body, true).await?
+            } else {
+                self.write_data(body).await?
+            };
+            if response.is_positive_completion() {
+                Ok(response)
+            } else {
+                Err(crate::Error::UnexpectedReply(response))
+            }
+        }
+        .await
+        .map_err(|err| self.check_size_exceeded(err));
+        let result = self.end_exchange(in_flight, result);
+
+        // A poisoned client, such as one that aborted a pipelined
+        // transaction by closing the connection, cannot be reset.
+        if self.reset_on_error
+            && !self.poisoned
+            && result.as_ref().is_err_and(|err| !is_session_closed(err))
+        {
+            let _ = self.rset().await;
+        }
+
+        #[cfg(feature = "metrics")]
+        super::metrics::send(&result);
+
+        result
+    }
+
+    /// Sends MAIL FROM, RCPT TO and, unless BDAT is used, the DATA command.
+    async fn send_envelope(
+        &mut self,
+        mail_from: (&str, &Parameters<'_>),
+        rcpt_to: &[(&str, &Parameters<'_>)],
+        use_bdat: bool,
+    ) -> crate::Result<()> {
+        if !self
+            .capabilities
+            .as_ref()
+            .is_some_and(|capabilities| capabilities.has_capability(EXT_PIPELINING))
+        {
+            self.mail_from(mail_from.0, mail_from.1).await?;
+            for (rcpt, params) in rcpt_to {
+                self.rcpt_to(rcpt, params).await?;
+            }
+            if !use_bdat {
+                self.cmd(b"DATA\r\n").await?.assert_code(354)?;
+            }
+            return Ok(());
+        }
+
+        // Pipeline the envelope
+        validate_address(mail_from.0)?;
+        let mut cmds = Vec::with_capacity(rcpt_to.len() + 2);
+        cmds.push(format!("MAIL FROM:<{}>{}\r\n", mail_from.0, mail_from.1));
+        for (rcpt, params) in rcpt_to {
+            validate_address(rcpt)?;
+            cmds.push(format!("RCPT TO:<{rcpt}>{params}\r\n"));
+        }
+        if !use_bdat {
+            cmds.push("DATA\r\n".to_string());
+        }
+
+        // DATA is the last command of the group, the message is only sent
+        // once its 354 reply is received. All replies are read even if a
+        // command fails, unless the server closes the session with 421.
+        let num_cmds = cmds.len();
+        let mut replies = self.cmds(cmds).await?;
+        if replies.len() < num_cmds {
+            if let Some(reply) = replies.pop() {
+                return Err(crate::Error::UnexpectedReply(reply));
+            }
+        }
+        let mut replies = replies.into_iter();
+        let data_reply = if !use_bdat { replies.next_back() } else { None };
+        let envelope_err = replies.find(|reply| !reply.is_positive_completion());
+
+        match (data_reply, envelope_err) {
+            (Some(data_reply), Some(envelope_err)) if data_reply.code() == 354 => {
+                // The server is waiting for the message, closing the
+                // connection is the only way to abort the transaction.
+                self.poisoned = true;
+                let _ = self.stream.shutdown().await;
+                Err(crate::Error::UnexpectedReply(envelope_err))
+            }
+            (_, Some(envelope_err)) => Err(crate::Error::UnexpectedReply(envelope_err)),
+            (Some(data_reply), None) => data_reply.assert_code(354),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Converts a 552 reply refusing the message for its size into
+    /// `MessageTooLarge`, using the limit stated in the reply text or, when
+    /// absent, the one advertised with the SIZE extension.
+    fn check_size_exceeded(&self, err: crate::Error) -> crate::Error {
+        match err {
+            crate::Error::UnexpectedReply(reply) if is_size_exceeded(&reply) => {
+                crate::Error::MessageTooLarge(
+                    reply
+                        .message()
+                        .split(|ch: char| !ch.is_ascii_digit())
+                        .filter_map(|value| value.parse::<usize>().ok())
+                        .max()
+                        .filter(|&limit| limit > 0)
+                        .or_else(|| self.max_size()),
+                )
+            }
+            err => err,
+        }
+    }
+
+    /// Verifies that the server supports the extensions required by the
+    /// MAIL FROM parameters and that their values are within its limits,
+    /// when its capabilities are cached. Advisory parameters the server does
+    /// not support are removed.
+    fn check_parameters(&self, message: &mut Message<'_>) -> crate::Result<()> {
+        if message.mail_from.parameters.has_key("MT-PRIORITY") && !self.may_use(EXT_MT_PRIORITY) {
+            message.mail_from.parameters.remove("MT-PRIORITY");
+        }
+
+        let params = &message.mail_from.parameters;
+        let hold_for = if let Some(value) = params.value("HOLDFOR") {
+            Some(("HOLDFOR", value.parse::<u64>().unwrap_or(u64::MAX)))
+        } else {
+            params.value("HOLDUNTIL").map(|value| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                (
+                    "HOLDUNTIL",
+                    parse_date_time(value).map_or(0, |time| time.saturating_sub(now)),
+                )
+            })
+        };
+
+        if let Some(by) = params.value("BY") {
+            if !self.may_use(EXT_DELIVER_BY) {
+                return Err(crate::Error::UnsupportedExtension("DELIVERBY"));
+            }
+            let by = by
+                .split_once(';')
+                .and_then(|(by, _)| by.parse::<u64>().ok())
+                .unwrap_or(0);
+            if self
+                .capabilities
+                .as_ref()
+                .is_some_and(|capabilities| by < capabilities.deliver_by)
+            {
+                return Err(crate::Error::ParameterOutOfRange("BY"));
+            }
+        }
+
+        if let Some((param, hold_for)) = hold_for {
+            if !self.may_use(EXT_FUTURE_RELEASE) {
+                return Err(crate::Error::UnsupportedExtension("FUTURERELEASE"));
+            }
+            if self.capabilities.as_ref().is_some_and(|capabilities| {
+                capabilities.future_release_interval > 0
+                    && hold_for > capabilities.future_release_interval
+            }) {
+                return Err(crate::Error::ParameterOutOfRange(param));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a `BODY` parameter to MAIL FROM when the message contains 8-bit or
+    /// binary content. Binary content, containing NUL bytes, requires
+    /// `BINARYMIME` and `CHUNKING`, and 8-bit content requires `8BITMIME`;
+    /// otherwise the message is refused with `UnsupportedExtension` before
+    /// the transaction is started. Returns `true` if the message has to be
+    /// sent using BDAT.
+    fn declare_body_type(&self, message: &mut Message<'_>) -> crate::Result<bool> {
+        let capabilities = match &self.capabilities {
+            Some(capabilities) if !message.mail_from.parameters.has_key("BODY") => capabilities,
+            _ => return Ok(false),
+        };
+
+        let mut is_8bit = false;
+        for &byte in message.body.iter() {
+            if byte == 0 {
+                return if capabilities.has_capability(EXT_BINARY_MIME)
+                    && capabilities.has_capability(EXT_CHUNKING)
+                {
+                    message.mail_from.parameters.body_binarymime();
+                    Ok(true)
+                } else {
+                    Err(crate::Error::UnsupportedExtension("BINARYMIME"))
+                };
+            } else if byte >= 0x80 {
+                is_8bit = true;
+            }
+        }
+
+        if is_8bit {
+            if capabilities.has_capability(EXT_8BIT_MIME) {
+                message.mail_from.parameters.body_8bitmime();
+            } else {
+                return Err(crate::Error::UnsupportedExtension("8BITMIME"));
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Writes the message applying the transparency procedure, followed by
+    /// the end-of-data marker. A CRLF is only added before the marker when
+    /// the message does not already end with one.
     pub async fn write_message(&mut self, message: &[u8]) -> tokio::io::Result<()> {
-        // Transparency procedure
-        let mut is_cr_or_lf = false;
+        let message = if self.normalize_line_endings {
+            normalize_line_endings(message)
+        } else {
+            Cow::Borrowed(message)
+        };
+        let message = message.as_ref();
+
+        // Transparency procedure, the beginning of the message is also
+        // the beginning of a line.
+        let mut is_cr_or_lf = true;
 
         // As per RFC 5322bis, section 2.3:
         // CR and LF MUST only occur together as CRLF; they MUST NOT appear
@@ -112,12 +749,15 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
         // For this reason, we apply the transparency procedure when there is
         // a CR or LF followed by a dot.
 
+        // Small segments are coalesced to avoid flushing the message in tiny
+        // writes (and, over TLS, tiny records).
+        let mut buf = Vec::with_capacity(WRITE_BUFFER_SIZE.min(message.len() + 5));
         let mut last_pos = 0;
         for (pos, byte) in message.iter().enumerate() {
             if *byte == b'.' && is_cr_or_lf {
                 if let Some(bytes) = message.get(last_pos..pos) {
-                    self.stream.write_all(bytes).await?;
-                    self.stream.write_all(b".").await?;
+                    self.write_buffered(&mut buf, bytes).await?;
+                    self.write_buffered(&mut buf, b".").await?;
                     last_pos = pos;
                 }
                 is_cr_or_lf = false;
@@ -126,11 +766,350 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
             }
         }
         if let Some(bytes) = message.get(last_pos..) {
-            self.stream.write_all(bytes).await?;
+            self.write_buffered(&mut buf, bytes).await?;
+        }
+        let terminator: &[u8] = if message.is_empty() || message.ends_with(b"\r\n") {
+            b".\r\n"
+        } else {
+            b"\r\n.\r\n"
+        };
+        self.write_buffered(&mut buf, terminator).await?;
+        if !buf.is_empty() {
+            self.write_bytes(&buf).await?;
         }
-        self.stream.write_all("\r\n.\r\n".as_bytes()).await?;
         self.stream.flush().await
     }
+
+    /// Appends bytes to the write buffer, writing it out once full. Segments
+    /// larger than the buffer are written directly.
+    async fn write_buffered(&mut self, buf: &mut Vec<u8>, bytes: &[u8]) -> tokio::io::Result<()> {
+        if buf.len() + bytes.len() > WRITE_BUFFER_SIZE {
+            if !buf.is_empty() {
+                self.write_chunked(buf).await?;
+                buf.clear();
+            }
+            if bytes.len() >= WRITE_BUFFER_SIZE {
+                return self.write_chunked(bytes).await;
+            }
+        }
+        buf.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Writes bytes in pieces of at most `WRITE_BUFFER_SIZE`, flushing after
+    /// each one so that large messages are not buffered by the stream (for
+    /// example in TLS records) ahead of what the connection can take.
+    pub(crate) async fn write_chunked(&mut self, bytes: &[u8]) -> tokio::io::Result<()> {
+        for chunk in bytes.chunks(WRITE_BUFFER_SIZE) {
+            self.write_bytes(chunk).await?;
+            self.stream.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Size of the buffer used to coalesce writes of message data.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Converts bare CR and LF line endings to CRLF, leaving existing CRLF
+/// sequences untouched.
+pub(crate) fn normalize_line_endings(message: &[u8]) -> Cow<'_, [u8]> {
+    let mut iter = message.iter().enumerate().peekable();
+    let mut normalized: Option<Vec<u8>> = None;
+    let mut last_pos = 0;
+
+    while let Some((pos, &byte)) = iter.next() {
+        match byte {
+            b'\r' if iter.peek().is_some_and(|(_, &next)| next == b'\n') => {
+                iter.next();
+            }
+            b'\r' | b'\n' => {
+                let normalized =
+                    normalized.get_or_insert_with(|| Vec::with_capacity(message.len() + 32));
+                normalized.extend_from_slice(&message[last_pos..pos]);
+                normalized.extend_from_slice(b"\r\n");
+                last_pos = pos + 1;
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(mut normalized) = normalized {
+        normalized.extend_from_slice(&message[last_pos..]);
+        Cow::Owned(normalized)
+    } else {
+        Cow::Borrowed(message)
+    }
+}
+
+/// Maximum length of a line excluding the CRLF (RFC 5321, section 4.5.3.1.6).
+const MAX_LINE_LENGTH: usize = 998;
+
+/// Returns `true` if any line of the message exceeds `MAX_LINE_LENGTH`.
+fn has_long_line(message: &[u8]) -> bool {
+    message
+        .split(|&ch| ch == b'\n')
+        .any(|line| line.strip_suffix(b"\r").unwrap_or(line).len() > MAX_LINE_LENGTH)
+}
+
+/// Breaks lines longer than `MAX_LINE_LENGTH` with a CRLF. Header lines are
+/// folded at the last whitespace that fits, or at the limit followed by a
+/// space, so that the following lines are parsed as continuations. Splits
+/// do not fall within a UTF-8 sequence.
+fn wrap_long_lines(message: &[u8]) -> Cow<'_, [u8]> {
+    if !has_long_line(message) {
+        return Cow::Borrowed(message);
+    }
+
+    let mut wrapped = Vec::with_capacity(message.len() + message.len() / MAX_LINE_LENGTH * 3);
+    let mut in_headers = true;
+    for line in message.split_inclusive(|&ch| ch == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        if content.is_empty() {
+            in_headers = false;
+        }
+
+        let mut rest = line;
+        let mut rest_len = content.len();
+        // Room left on the current output line, reduced by the space
+        // inserted when folding a header without whitespace.
+        let mut limit = MAX_LINE_LENGTH;
+        while rest_len > limit {
+            let mut split = limit;
+            let mut separator: &[u8] = b"\r\n";
+            if in_headers {
+                match rest[1..limit]
+                    .iter()
+                    .rposition(|&ch| ch == b' ' || ch == b'\t')
+                {
+                    Some(ws) => split = ws + 1,
+                    None => separator = b"\r\n ",
+                }
+            }
+            while split > 1 && (rest[split] & 0xC0) == 0x80 {
+                split -= 1;
+            }
+            wrapped.extend_from_slice(&rest[..split]);
+            wrapped.extend_from_slice(separator);
+            rest = &rest[split..];
+            rest_len -= split;
+            limit = MAX_LINE_LENGTH + 2 - separator.len();
+        }
+        wrapped.extend_from_slice(rest);
+    }
+
+    Cow::Owned(wrapped)
+}
+
+/// Returns `true` if the header block of a message contains the header.
+pub(crate) fn has_header(message: &[u8], name: &str) -> bool {
+    for line in message.split(|&ch| ch == b'\n') {
+        if line.is_empty() || line == b"\r" {
+            break;
+        } else if line.iter().position(|&ch| ch == b':').is_some_and(|colon| {
+            line[..colon]
+                .trim_ascii()
+                .eq_ignore_ascii_case(name.as_bytes())
+        }) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Appends the xtext encoding (RFC 3461) of `value` to `out`.
+pub(crate) fn xtext_encode(value: &str, out: &mut String) {
+    for ch in value.chars() {
+        if ('!'..='~').contains(&ch) && ch != '+' && ch != '=' {
+            out.push(ch);
+        } else {
+            let mut buf = [0u8; 4];
+            for byte in ch.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("+{byte:02X}"));
+            }
+        }
+    }
+}
+
+/// Returns `body` with `headers` prepended, rejecting header names and
+/// values that would break the header section.
+fn prepend_headers<'x>(headers: &[(&str, &str)], body: &'x [u8]) -> crate::Result<Cow<'x, [u8]>> {
+    if headers.is_empty() {
+        return Ok(body.into());
+    }
+
+    let mut message = Vec::with_capacity(body.len() + headers.len() * 64);
+    for (name, value) in headers {
+        if name.is_empty() || !name.bytes().all(|ch| ch.is_ascii_graphic() && ch != b':') {
+            return Err(crate::Error::InvalidHeader);
+        }
+
+        // Line breaks are only allowed when folding the value
+        let value = value.as_bytes();
+        for (pos, &ch) in value.iter().enumerate() {
+            let is_valid = match ch {
+                b'\r' => value.get(pos + 1) == Some(&b'\n'),
+                b'\n' => {
+                    pos > 0
+                        && value[pos - 1] == b'\r'
+                        && matches!(value.get(pos + 1), Some(b' ' | b'\t'))
+                }
+                b'\0' => false,
+                _ => true,
+            };
+            if !is_valid {
+                return Err(crate::Error::InvalidHeader);
+            }
+        }
+
+        message.extend_from_slice(name.as_bytes());
+        message.extend_from_slice(b": ");
+        message.extend_from_slice(value);
+        message.extend_from_slice(b"\r\n");
+    }
+    message.extend_from_slice(body);
+    Ok(message.into())
+}
+
+/// Returns the first failed transaction of a split message or, if all
+/// succeeded, the reply to the last one.
+fn last_reply(results: Vec<crate::Result<Response<String>>>) -> crate::Result<Response<String>> {
+    let mut last = Err(crate::Error::MissingRcptTo);
+    for result in results {
+        last = Ok(result?);
+    }
+    last
+}
+
+/// Returns `true` if the reply rejects the message for exceeding the size
+/// limit (RFC 1870), rather than for a full mailbox.
+fn is_size_exceeded(reply: &Response<String>) -> bool {
+    reply.code() == 552
+        && match reply.esc {
+            [5, 3, 4] | [5, 2, 3] => true,
+            [0, 0, 0] => {
+                let message = reply.message().to_ascii_lowercase();
+                ["size", "too big", "too large"]
+                    .iter()
+                    .any(|text| message.contains(text))
+            }
+            _ => false,
+        }
+}
+
+/// Removes all instances of a header, including folded continuation lines,
+/// from the header block of a message.
+pub(crate) fn remove_header<'x>(message: &'x [u8], name: &str) -> Cow<'x, [u8]> {
+    let mut stripped: Option<Vec<u8>> = None;
+    let mut last_pos = 0;
+    let mut pos = 0;
+    let mut is_removing = false;
+
+    while pos < message.len() {
+        let line_end = message[pos..]
+            .iter()
+            .position(|&ch| ch == b'\n')
+            .map_or(message.len(), |end| pos + end + 1);
+        let line = &message[pos..line_end];
+
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        } else if matches!(line.first(), Some(b' ' | b'\t')) {
+            // Continuation lines belong to the preceding header.
+        } else {
+            is_removing = line.iter().position(|&ch| ch == b':').is_some_and(|colon| {
+                line[..colon]
+                    .trim_ascii()
+                    .eq_ignore_ascii_case(name.as_bytes())
+            });
+        }
+
+        if is_removing {
+            let stripped = stripped.get_or_insert_with(|| Vec::with_capacity(message.len()));
+            stripped.extend_from_slice(&message[last_pos..pos]);
+            last_pos = line_end;
+        }
+        pos = line_end;
+    }
+
+    if let Some(mut stripped) = stripped {
+        stripped.extend_from_slice(&message[last_pos..]);
+        Cow::Owned(stripped)
+    } else {
+        Cow::Borrowed(message)
+    }
+}
+
+/// Converts days since the UNIX epoch to a (year, month, day) civil date,
+/// see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: u64) -> (i64, i64, i64) {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Formats a UNIX timestamp as an RFC 3339 UTC date-time.
+fn format_date_time(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
+}
+
+/// Formats a UNIX timestamp as an RFC 5322 date-time in UTC.
+pub(crate) fn format_rfc5322_date(timestamp: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {} {} {:04} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
+}
+
+/// Parses an RFC 3339 UTC date-time as produced by `format_date_time`.
+fn parse_date_time(value: &str) -> Option<u64> {
+    let value = value.strip_suffix('Z')?;
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|v| v.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.splitn(3, ':').map(|v| v.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+
+    // Days from civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = u64::try_from(era * 146097 + doe - 719468).ok()?;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
 }
 
 impl<'x> Message<'x> {
@@ -148,69 +1127,395 @@ impl<'x> Message<'x> {
         }
     }
 
-    /// Create a new empty message.
-    pub fn empty() -> Self {
-        Message {
-            mail_from: Address::default(),
-            rcpt_to: Vec::new(),
-            body: Default::default(),
-        }
+    /// Create a new message from a raw RFC 5322 message and its envelope.
+    pub fn from_raw<T, U, V>(from: impl Into<Address<'x>>, to: U, raw_message: V) -> Self
+    where
+        T: Into<Address<'x>>,
+        U: IntoIterator<Item = T>,
+        V: Into<Cow<'x, [u8]>>,
+    {
+        Message {
+            mail_from: from.into(),
+            rcpt_to: to.into_iter().map(Into::into).collect(),
+            body: raw_message.into(),
+        }
+    }
+
+    /// Create a new empty message.
+    pub fn empty() -> Self {
+        Message {
+            mail_from: Address::default(),
+            rcpt_to: Vec::new(),
+            body: Default::default(),
+        }
+    }
+
+    /// Set the sender of the message.
+    pub fn from(mut self, address: impl Into<Address<'x>>) -> Self {
+        self.mail_from = address.into();
+        self
+    }
+
+    /// Add a message recipient.
+    pub fn to(mut self, address: impl Into<Address<'x>>) -> Self {
+        self.rcpt_to.push(address.into());
+        self
+    }
+
+    /// Set the message body.
+    pub fn body(mut self, body: impl Into<Cow<'x, [u8]>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Sets the message transfer priority, from -9 (lowest) to 9 (highest).
+    /// The parameter is only sent when the server advertises the MT-PRIORITY
+    /// extension (RFC 6710).
+    pub fn priority(mut self, priority: i8) -> Self {
+        self.mail_from.parameters.mt_priority(priority);
+        self
+    }
+
+    /// Returns a mutable reference to the recipient at the provided index,
+    /// which can be used to attach RCPT TO parameters.
+    pub fn recipient_mut(&mut self, idx: usize) -> Option<&mut Address<'x>> {
+        self.rcpt_to.get_mut(idx)
+    }
+
+    /// Requests delivery within the provided time. Sending fails if the
+    /// server does not advertise the DELIVERBY extension (RFC 2852) or the
+    /// time is below its minimum.
+    pub fn deliver_by(mut self, time: Duration, mode: DeliverByMode) -> Self {
+        self.mail_from.parameters.deliver_by(time, mode);
+        self
+    }
+
+    /// Requests the server to hold the message for the provided duration
+    /// before delivery. Sending fails if the server does not advertise the
+    /// FUTURERELEASE extension (RFC 4865) or the delay exceeds its limit.
+    pub fn release_after(mut self, delay: Duration) -> Self {
+        self.mail_from.parameters.hold_for(delay);
+        self
+    }
+
+    /// Requests the server to hold the message until the provided time.
+    /// Sending fails if the server does not advertise the FUTURERELEASE
+    /// extension (RFC 4865) or the time exceeds its limit.
+    pub fn release_at(mut self, time: SystemTime) -> Self {
+        self.mail_from.parameters.hold_until(time);
+        self
+    }
+
+    /// Prepends a `Date` header and a `Message-ID` header generated from the
+    /// sender's domain when the message does not already include them.
+    pub fn add_missing_headers(&mut self) {
+        let has_date = has_header(&self.body, "Date");
+        let has_message_id = has_header(&self.body, "Message-ID");
+        if has_date && has_message_id {
+            return;
+        }
+
+        // Match the line endings used by the message.
+        let crlf = if self
+            .body
+            .iter()
+            .position(|&ch| ch == b'\n')
+            .is_some_and(|pos| pos == 0 || self.body[pos - 1] != b'\r')
+        {
+            "\n"
+        } else {
+            "\r\n"
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut headers = String::with_capacity(128);
+        if !has_date {
+            headers.push_str("Date: ");
+            headers.push_str(&format_rfc5322_date(now.as_secs()));
+            headers.push_str(crlf);
+        }
+        if !has_message_id {
+            static COUNTER: AtomicU64 = AtomicU64::new(0);
+            let domain = self
+                .mail_from
+                .email
+                .rsplit_once('@')
+                .map(|(_, domain)| domain)
+                .filter(|domain| !domain.is_empty())
+                .unwrap_or("localhost");
+            headers.push_str(&format!(
+                "Message-ID: <{:x}.{:x}.{:x}@{}>{}",
+                now.as_nanos(),
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed),
+                domain,
+                crlf
+            ));
+        }
+
+        let mut body = Vec::with_capacity(headers.len() + self.body.len());
+        body.extend_from_slice(headers.as_bytes());
+        body.extend_from_slice(&self.body);
+        self.body = body.into();
+    }
+
+    /// Returns a copy of the raw message body as stored, without the DATA
+    /// transparency dot-stuffing. The client settings applied by `send`, such
+    /// as added headers, line wrapping and line ending normalization, are not
+    /// applied; use `SmtpClient::to_eml` for the message as it is
+    /// transmitted.
+    pub fn to_eml(&self) -> Vec<u8> {
+        self.body.to_vec()
+    }
+
+    /// Removes any `Bcc` headers from the message header block, leaving the
+    /// rest of the message byte-for-byte intact. Blind recipients remain in
+    /// the envelope.
+    pub fn strip_bcc(mut self) -> Self {
+        if let Cow::Owned(body) = remove_header(&self.body, "Bcc") {
+            self.body = body.into();
+        }
+        self
+    }
+}
+
+impl<'x> From<&'x str> for Address<'x> {
+    fn from(email: &'x str) -> Self {
+        Address {
+            email: email.into(),
+            parameters: Parameters::default(),
+            is_blind: false,
+        }
+    }
+}
+
+impl<'x> From<String> for Address<'x> {
+    fn from(email: String) -> Self {
+        Address {
+            email: email.into(),
+            parameters: Parameters::default(),
+            is_blind: false,
+        }
+    }
+}
+
+impl<'x> Address<'x> {
+    pub fn new(email: impl Into<Cow<'x, str>>, parameters: Parameters<'x>) -> Self {
+        Address {
+            email: email.into(),
+            parameters,
+            is_blind: false,
+        }
+    }
+
+    /// Marks this address as a blind recipient, which is included in the
+    /// envelope but should not be disclosed in the message headers.
+    pub fn blind(mut self) -> Self {
+        self.is_blind = true;
+        self
+    }
+
+    /// Adds a parameter to this address.
+    pub fn add_param(&mut self, param: impl Into<Parameter<'x>>) -> &mut Self {
+        self.parameters.add(param);
+        self
+    }
+
+    /// Parses an address in `name-addr` (`"John Doe" <john@example.com>`) or
+    /// `addr-spec` form, keeping only the addr-spec for use in the envelope.
+    /// Display names, quoted strings and comments are discarded, only
+    /// whitespace and comments may follow the closing `>`.
+    pub fn parse(value: &'x str) -> crate::Result<Self> {
+        let mut in_quote = false;
+        let mut is_escaped = false;
+        let mut comment_depth = 0;
+        let mut addr_start = None;
+        let mut addr_end = None;
+        let mut has_comments = false;
+        let mut addr = String::with_capacity(value.len());
+
+        for (pos, ch) in value.char_indices() {
+            if is_escaped {
+                is_escaped = false;
+                if comment_depth == 0 {
+                    addr.push(ch);
+                }
+                continue;
+            }
+
+            match ch {
+                '\\' if in_quote || comment_depth > 0 => {
+                    is_escaped = true;
+                    if comment_depth == 0 {
+                        addr.push(ch);
+                    }
+                }
+                '(' if !in_quote => {
+                    comment_depth += 1;
+                    has_comments = true;
+                }
+                ')' if !in_quote && comment_depth > 0 => {
+                    comment_depth -= 1;
+                }
+                _ if comment_depth > 0 => (),
+                _ if addr_end.is_some() => {
+                    if !ch.is_whitespace() {
+                        return Err(crate::Error::InvalidAddress);
+                    }
+                }
+                '"' => {
+                    in_quote = !in_quote;
+                    addr.push(ch);
+                }
+                '<' if !in_quote && addr_start.is_none() => {
+                    // Only the contents of the angle-addr are kept
+                    addr_start = Some(pos + 1);
+                    has_comments = false;
+                    addr.clear();
+                }
+                '>' if !in_quote => {
+                    if addr_start.is_none() {
+                        return Err(crate::Error::InvalidAddress);
+                    }
+                    addr_end = Some(pos);
+                }
+                _ => {
+                    addr.push(ch);
+                }
+            }
+        }
+
+        if in_quote || comment_depth > 0 || addr_start.is_some() != addr_end.is_some() {
+            return Err(crate::Error::InvalidAddress);
+        }
+
+        let email: Cow<'x, str> = match (addr_start, addr_end) {
+            _ if has_comments => addr.trim().to_string().into(),
+            (Some(addr_start), Some(addr_end)) => value[addr_start..addr_end].trim().into(),
+            _ => value.trim().into(),
+        };
+        if email.is_empty() && addr_start.is_none() {
+            return Err(crate::Error::InvalidAddress);
+        }
+        validate_address(&email)?;
+
+        Ok(Address::new(email, Parameters::default()))
+    }
+}
+
+impl<'x> Parameters<'x> {
+    pub fn new() -> Self {
+        Self { params: Vec::new() }
+    }
+
+    pub fn add(&mut self, param: impl Into<Parameter<'x>>) -> &mut Self {
+        self.params.push(param.into());
+        self
+    }
+
+    /// Returns the value of the parameter with the provided key.
+    pub(crate) fn value(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|param| param.key.eq_ignore_ascii_case(key))
+            .and_then(|param| param.value.as_deref())
+    }
+
+    /// Removes all parameters with the provided key.
+    pub(crate) fn remove(&mut self, key: &str) {
+        self.params
+            .retain(|param| !param.key.eq_ignore_ascii_case(key));
     }
 
-    /// Set the sender of the message.
-    pub fn from(mut self, address: impl Into<Address<'x>>) -> Self {
-        self.mail_from = address.into();
-        self
+    /// Returns `true` if a parameter with the provided key is present.
+    pub fn has_key(&self, key: &str) -> bool {
+        self.params
+            .iter()
+            .any(|param| param.key.eq_ignore_ascii_case(key))
     }
 
-    /// Add a message recipient.
-    pub fn to(mut self, address: impl Into<Address<'x>>) -> Self {
-        self.rcpt_to.push(address.into());
-        self
+    /// Declares the message size (`SIZE=<n>`, RFC 1870).
+    pub fn size(&mut self, size: usize) -> &mut Self {
+        self.add(("SIZE", size.to_string()))
     }
 
-    /// Set the message body.
-    pub fn body(mut self, body: impl Into<Cow<'x, [u8]>>) -> Self {
-        self.body = body.into();
-        self
+    /// Declares an 8-bit MIME body (`BODY=8BITMIME`, RFC 6152).
+    pub fn body_8bitmime(&mut self) -> &mut Self {
+        self.add(("BODY", "8BITMIME"))
     }
-}
 
-impl<'x> From<&'x str> for Address<'x> {
-    fn from(email: &'x str) -> Self {
-        Address {
-            email: email.into(),
-            parameters: Parameters::default(),
-        }
+    /// Declares a binary MIME body (`BODY=BINARYMIME`, RFC 3030).
+    pub fn body_binarymime(&mut self) -> &mut Self {
+        self.add(("BODY", "BINARYMIME"))
     }
-}
 
-impl<'x> From<String> for Address<'x> {
-    fn from(email: String) -> Self {
-        Address {
-            email: email.into(),
-            parameters: Parameters::default(),
-        }
+    /// Requests the full message to be returned in DSNs (`RET=FULL`, RFC 3461).
+    pub fn ret_full(&mut self) -> &mut Self {
+        self.add(("RET", "FULL"))
     }
-}
 
-impl<'x> Address<'x> {
-    pub fn new(email: impl Into<Cow<'x, str>>, parameters: Parameters<'x>) -> Self {
-        Address {
-            email: email.into(),
-            parameters,
-        }
+    /// Requests only the headers to be returned in DSNs (`RET=HDRS`, RFC 3461).
+    pub fn ret_headers(&mut self) -> &mut Self {
+        self.add(("RET", "HDRS"))
     }
-}
 
-impl<'x> Parameters<'x> {
-    pub fn new() -> Self {
-        Self { params: Vec::new() }
+    /// Requests the message to be held for the provided duration before
+    /// delivery (`HOLDFOR=<seconds>`, RFC 4865).
+    pub fn hold_for(&mut self, delay: Duration) -> &mut Self {
+        self.add(("HOLDFOR", delay.as_secs().to_string()))
     }
 
-    pub fn add(&mut self, param: impl Into<Parameter<'x>>) -> &mut Self {
-        self.params.push(param.into());
-        self
+    /// Requests the message to be held until the provided time before
+    /// delivery (`HOLDUNTIL=<date-time>`, RFC 4865).
+    pub fn hold_until(&mut self, time: SystemTime) -> &mut Self {
+        let time = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.add(("HOLDUNTIL", format_date_time(time)))
+    }
+
+    /// Sets the original recipient address (`ORCPT=rfc822;<addr>`, RFC 3461).
+    pub fn orcpt(&mut self, addr: &str) -> &mut Self {
+        let mut value = String::with_capacity(addr.len() + 7);
+        value.push_str("rfc822;");
+        xtext_encode(addr, &mut value);
+        self.add(("ORCPT", value))
+    }
+
+    /// Sets the message transfer priority (`MT-PRIORITY=<n>`, RFC 6710).
+    /// Values are clamped to the -9 to 9 range.
+    pub fn mt_priority(&mut self, priority: i8) -> &mut Self {
+        self.add(("MT-PRIORITY", priority.clamp(-9, 9).to_string()))
+    }
+
+    /// Requests delivery within the provided time (`BY=<seconds>;<mode>`,
+    /// RFC 2852).
+    pub fn deliver_by(&mut self, time: Duration, mode: DeliverByMode) -> &mut Self {
+        let mode = match mode {
+            DeliverByMode::Notify => 'N',
+            DeliverByMode::Return => 'R',
+        };
+        self.add(("BY", format!("{};{}", time.as_secs(), mode)))
+    }
+
+    /// Requests DSNs for the provided `RCPT_NOTIFY_*` conditions
+    /// (`NOTIFY=<flags>`, RFC 3461).
+    pub fn notify(&mut self, flags: u64) -> &mut Self {
+        let value = if flags & RCPT_NOTIFY_NEVER != 0 {
+            "NEVER".to_string()
+        } else {
+            [
+                (RCPT_NOTIFY_SUCCESS, "SUCCESS"),
+                (RCPT_NOTIFY_FAILURE, "FAILURE"),
+                (RCPT_NOTIFY_DELAY, "DELAY"),
+            ]
+            .iter()
+            .filter(|(flag, _)| flags & flag != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(",")
+        };
+        self.add(("NOTIFY", value))
     }
 }
 
@@ -232,6 +1537,15 @@ impl<'x> From<(&'x str, &'x str)> for Parameter<'x> {
     }
 }
 
+impl<'x> From<(&'x str, String)> for Parameter<'x> {
+    fn from(value: (&'x str, String)) -> Self {
+        Parameter {
+            key: value.0.into(),
+            value: Some(value.1.into()),
+        }
+    }
+}
+
 impl<'x> From<(String, String)> for Parameter<'x> {
     fn from(value: (String, String)) -> Self {
         Parameter {
@@ -282,15 +1596,37 @@ impl<'x> IntoMessage<'x> for Message<'x> {
     }
 }
 
+/// Converts a `(from, recipients, raw_message)` tuple into a message.
+impl<'x, T, U, V, W> IntoMessage<'x> for (T, U, V)
+where
+    T: Into<Address<'x>>,
+    U: IntoIterator<Item = W>,
+    V: Into<Cow<'x, [u8]>>,
+    W: Into<Address<'x>>,
+{
+    fn into_message(self) -> crate::Result<Message<'x>> {
+        Ok(Message::from_raw(self.0, self.1, self.2))
+    }
+}
+
 #[cfg(feature = "builder")]
 impl<'x, 'y> IntoMessage<'x> for MessageBuilder<'y> {
     fn into_message(self) -> crate::Result<Message<'x>> {
         let mut mail_from = None;
         let mut rcpt_to = std::collections::HashSet::new();
+        let mut add_rcpt = |addr: &address::EmailAddress| -> crate::Result<()> {
+            validate_address(&addr.email)?;
+            let email = addr.email.trim();
+            if !email.is_empty() {
+                rcpt_to.insert(email.to_string());
+            }
+            Ok(())
+        };
 
         for (key, value) in self.headers.iter() {
             if key.eq_ignore_ascii_case("from") {
                 if let HeaderType::Address(address::Address::Address(addr)) = value {
+                    validate_address(&addr.email)?;
                     let email = addr.email.trim();
                     if !email.is_empty() {
                         mail_from = email.to_string().into();
@@ -303,28 +1639,19 @@ impl<'x, 'y> IntoMessage<'x> for MessageBuilder<'y> {
                 if let HeaderType::Address(addr) = value {
                     match addr {
                         address::Address::Address(addr) => {
-                            let email = addr.email.trim();
-                            if !email.is_empty() {
-                                rcpt_to.insert(email.to_string());
-                            }
+                            add_rcpt(addr)?;
                         }
                         address::Address::Group(group) => {
                             for addr in &group.addresses {
                                 if let address::Address::Address(addr) = addr {
-                                    let email = addr.email.trim();
-                                    if !email.is_empty() {
-                                        rcpt_to.insert(email.to_string());
-                                    }
+                                    add_rcpt(addr)?;
                                 }
                             }
                         }
                         address::Address::List(list) => {
                             for addr in list {
                                 if let address::Address::Address(addr) = addr {
-                                    let email = addr.email.trim();
-                                    if !email.is_empty() {
-                                        rcpt_to.insert(email.to_string());
-                                    }
+                                    add_rcpt(addr)?;
                                 }
                             }
                         }
@@ -344,6 +1671,7 @@ impl<'x, 'y> IntoMessage<'x> for MessageBuilder<'y> {
                 .map(|email| Address {
                     email: email.into(),
                     parameters: Parameters::default(),
+                    is_blind: false,
                 })
                 .collect(),
             body: self.write_to_vec()?.into(),
@@ -351,11 +1679,15 @@ impl<'x, 'y> IntoMessage<'x> for MessageBuilder<'y> {
     }
 }
 
+/// The raw message is sent as-is, with Bcc recipients marked as blind.
+/// Use [`Message::strip_bcc`] to remove the Bcc headers before relaying and
+/// [`SmtpClient::send_signed`] to prepend a DKIM signature.
 #[cfg(feature = "parser")]
 impl<'x> IntoMessage<'x> for mail_parser::Message<'x> {
     fn into_message(self) -> crate::Result<Message<'x>> {
         let mut mail_from = None;
         let mut rcpt_to = std::collections::HashSet::new();
+        let mut bcc = std::collections::HashSet::new();
 
         let find_address = |addr: &mail_parser::Addr| -> Option<String> {
             addr.address
@@ -381,37 +1713,1643 @@ impl<'x> IntoMessage<'x> for mail_parser::Message<'x> {
                     }
                     _ => (),
                 },
-                HeaderName::To | HeaderName::Cc | HeaderName::Bcc => match header.value() {
-                    HeaderValue::Address(mail_parser::Address::List(addrs)) => {
-                        rcpt_to.extend(addrs.iter().filter_map(find_address));
-                    }
-                    HeaderValue::Address(mail_parser::Address::Group(grps)) => {
-                        rcpt_to.extend(
-                            grps.iter()
-                                .flat_map(|grp| grp.addresses.iter())
-                                .filter_map(find_address),
-                        );
+                HeaderName::To | HeaderName::Cc | HeaderName::Bcc => {
+                    let rcpt_to = if header.name == HeaderName::Bcc {
+                        &mut bcc
+                    } else {
+                        &mut rcpt_to
+                    };
+                    match header.value() {
+                        HeaderValue::Address(mail_parser::Address::List(addrs)) => {
+                            rcpt_to.extend(addrs.iter().filter_map(find_address));
+                        }
+                        HeaderValue::Address(mail_parser::Address::Group(grps)) => {
+                            rcpt_to.extend(
+                                grps.iter()
+                                    .flat_map(|grp| grp.addresses.iter())
+                                    .filter_map(find_address),
+                            );
+                        }
+                        _ => (),
                     }
-                    _ => (),
-                },
+                }
                 _ => (),
             };
         }
 
-        if rcpt_to.is_empty() {
+        if rcpt_to.is_empty() && bcc.is_empty() {
             return Err(crate::Error::MissingRcptTo);
         }
 
+        bcc.retain(|email| !rcpt_to.contains(email));
+        for email in mail_from.iter().chain(rcpt_to.iter()).chain(bcc.iter()) {
+            validate_address(email)?;
+        }
+
         Ok(Message {
             mail_from: mail_from.ok_or(crate::Error::MissingMailFrom)?.into(),
             rcpt_to: rcpt_to
                 .into_iter()
-                .map(|email| Address {
+                .map(|email| (email, false))
+                .chain(bcc.into_iter().map(|email| (email, true)))
+                .map(|(email, is_blind)| Address {
                     email: email.into(),
                     parameters: Parameters::default(),
+                    is_blind,
                 })
                 .collect(),
             body: self.raw_message,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        borrow::Cow,
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use smtp_proto::{
+        EhloResponse, EXT_8BIT_MIME, EXT_BINARY_MIME, EXT_CHUNKING, EXT_DELIVER_BY,
+        EXT_FUTURE_RELEASE, EXT_MT_PRIORITY, EXT_PIPELINING, RCPT_NOTIFY_DELAY,
+        RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS,
+    };
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    use crate::{smtp::mock::MockServer, ErrorKind, LineLengthPolicy, SmtpClient};
+
+    use super::{normalize_line_endings, Address, DeliverByMode, IntoMessage, Message, Parameters};
+
+    #[test]
+    fn body_type_declaration() {
+        let mut client = SmtpClient::new(tokio::io::duplex(1).0, Duration::from_secs(30));
+
+        for (capabilities, body, expected_param, expected_bdat) in [
+            (0, &b"Subject: Hi\r\n\r\nHello"[..], "", false),
+            (EXT_8BIT_MIME, b"Subject: Hi\r\n\r\nHello", "", false),
+            (
+                EXT_8BIT_MIME,
+                "Subject: Hi\r\n\r\n¡Hola!".as_bytes(),
+                " BODY=8BITMIME",
+                false,
+            ),
+            (
+                EXT_8BIT_MIME | EXT_BINARY_MIME | EXT_CHUNKING,
+                b"Subject: Hi\r\n\r\n\x00\x01",
+                " BODY=BINARYMIME",
+                true,
+            ),
+        ] {
+            let mut response = EhloResponse::new("mx.example.com".to_string());
+            response.capabilities = capabilities;
+            client.capabilities = Some(response);
+
+            let mut message = Message::new("john@example.com", ["jane@example.com"], body);
+            assert_eq!(
+                client.declare_body_type(&mut message).unwrap(),
+                expected_bdat
+            );
+            assert_eq!(message.mail_from.parameters.to_string(), expected_param);
+        }
+
+        // Content the server cannot receive is refused
+        for (capabilities, body, expected) in [
+            (0, "Subject: Hi\r\n\r\n¡Hola!".as_bytes(), "8BITMIME"),
+            (
+                EXT_BINARY_MIME,
+                "Subject: Hi\r\n\r\n¡Hola!".as_bytes(),
+                "8BITMIME",
+            ),
+            (
+                EXT_8BIT_MIME | EXT_BINARY_MIME,
+                b"Subject: Hi\r\n\r\n\x00\x01",
+                "BINARYMIME",
+            ),
+            (
+                EXT_8BIT_MIME | EXT_CHUNKING,
+                b"Subject: Hi\r\n\r\n\x00\x01",
+                "BINARYMIME",
+            ),
+        ] {
+            let mut response = EhloResponse::new("mx.example.com".to_string());
+            response.capabilities = capabilities;
+            client.capabilities = Some(response);
+
+            let mut message = Message::new("john@example.com", ["jane@example.com"], body);
+            assert!(
+                matches!(
+                    client.declare_body_type(&mut message),
+                    Err(crate::Error::UnsupportedExtension(extension)) if extension == expected
+                ),
+                "{expected}"
+            );
+            assert_eq!(message.mail_from.parameters.to_string(), "");
+        }
+    }
+
+    #[test]
+    fn typed_parameters() {
+        let mut params = Parameters::new();
+        params.size(1024).body_8bitmime().ret_full();
+        assert_eq!(params.to_string(), " SIZE=1024 BODY=8BITMIME RET=FULL");
+
+        let mut params = Parameters::new();
+        params
+            .body_binarymime()
+            .ret_headers()
+            .add(("ENVID", "abc123"));
+        assert_eq!(params.to_string(), " BODY=BINARYMIME RET=HDRS ENVID=abc123");
+
+        let mut params = Parameters::new();
+        params.notify(RCPT_NOTIFY_FAILURE | RCPT_NOTIFY_DELAY);
+        assert_eq!(params.to_string(), " NOTIFY=FAILURE,DELAY");
+
+        let mut params = Parameters::new();
+        params.notify(RCPT_NOTIFY_NEVER | RCPT_NOTIFY_FAILURE);
+        assert_eq!(params.to_string(), " NOTIFY=NEVER");
+    }
+
+    #[test]
+    fn line_ending_normalization() {
+        for (test, result) in [
+            ("A: b\r\n\r\nc\r\n", "A: b\r\n\r\nc\r\n"),
+            ("A: b\n\nc\n", "A: b\r\n\r\nc\r\n"),
+            ("A: b\r\rc\r", "A: b\r\n\r\nc\r\n"),
+            ("A: b\n\r\nc\r\n\nd", "A: b\r\n\r\nc\r\n\r\nd"),
+            ("A: b\n\rc", "A: b\r\n\r\nc"),
+            ("\r\n\n\r", "\r\n\r\n\r\n"),
+            ("", ""),
+        ] {
+            assert_eq!(
+                normalize_line_endings(test.as_bytes()).as_ref(),
+                result.as_bytes(),
+                "{test:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_address() {
+        for (value, expected) in [
+            ("john@example.com", "john@example.com"),
+            ("  john@example.com ", "john@example.com"),
+            ("John Doe <john@example.com>", "john@example.com"),
+            ("<john@example.com>", "john@example.com"),
+            ("<>", ""),
+            ("\"Doe, John\" <john@example.com>", "john@example.com"),
+            ("\"John <Doe>\" <john@example.com>", "john@example.com"),
+            ("\"John \\\" Doe\" <john@example.com>", "john@example.com"),
+            ("John (the man) Doe <john@example.com>", "john@example.com"),
+            (
+                "(Johnny <jd@x.org>) John <john@example.com>",
+                "john@example.com",
+            ),
+            ("john@example.com (John Doe)", "john@example.com"),
+            ("<\"john doe\"@example.com>", "\"john doe\"@example.com"),
+            ("<john(x)@example.com>", "john@example.com"),
+            ("John <(x) john@example.com (y)>", "john@example.com"),
+            ("John <john@example.com> (Doe) ", "john@example.com"),
+        ] {
+            assert_eq!(Address::parse(value).unwrap().email, expected, "{value:?}");
+        }
+
+        for value in [
+            "",
+            "   ",
+            "John Doe <john@example.com",
+            "\"John Doe <john@example.com>",
+            "(John Doe john@example.com",
+            "John Doe <john@example.com\r\nDATA>",
+            "John Doe john@example.com>",
+            "John <a@b> junk",
+            "<a@b> <c@d>",
+            "<a@b> \"junk\"",
+        ] {
+            assert!(
+                matches!(Address::parse(value), Err(crate::Error::InvalidAddress)),
+                "{value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn add_missing_headers() {
+        assert_eq!(
+            super::format_rfc5322_date(1_709_164_800),
+            "Thu, 29 Feb 2024 00:00:00 +0000"
+        );
+        assert_eq!(
+            super::format_rfc5322_date(0),
+            "Thu, 1 Jan 1970 00:00:00 +0000"
+        );
+
+        let mut message = Message::new(
+            "john@example.com",
+            ["jane@example.com"],
+            "From: john@example.com\r\nSubject: Hi\r\n\r\nHello\r\n".as_bytes(),
+        );
+        message.add_missing_headers();
+        let body = std::str::from_utf8(&message.body).unwrap();
+        let (date, rest) = body.split_once("\r\n").unwrap();
+        let (message_id, rest) = rest.split_once("\r\n").unwrap();
+        assert!(date.starts_with("Date: "), "{date}");
+        assert!(
+            message_id.starts_with("Message-ID: <") && message_id.ends_with("@example.com>"),
+            "{message_id}"
+        );
+        assert_eq!(
+            rest,
+            "From: john@example.com\r\nSubject: Hi\r\n\r\nHello\r\n"
+        );
+
+        // Existing headers are detected regardless of case.
+        for body in [
+            "date: Thu, 1 Jan 1970 00:00:00 +0000\nMESSAGE-ID: <1@example.com>\n\nHi\n",
+            "Message-Id: <1@example.com>\r\nDATE: Thu, 1 Jan 1970 00:00:00 +0000\r\n\r\n",
+        ] {
+            let mut message =
+                Message::new("john@example.com", ["jane@example.com"], body.as_bytes());
+            message.add_missing_headers();
+            assert_eq!(message.body.as_ref(), body.as_bytes());
+        }
+
+        // Headers in the body are ignored and line endings are preserved.
+        let mut message = Message::new(
+            "john@example.com",
+            ["jane@example.com"],
+            "Message-ID: <1@example.com>\n\nDate: today\n".as_bytes(),
+        );
+        message.add_missing_headers();
+        let body = std::str::from_utf8(&message.body).unwrap();
+        assert!(body.starts_with("Date: "), "{body}");
+        assert!(
+            body.ends_with("\nMessage-ID: <1@example.com>\n\nDate: today\n"),
+            "{body}"
+        );
+        assert!(!body.contains('\r'), "{body}");
+    }
+
+    #[test]
+    fn strip_bcc() {
+        let message = Message::new(
+            "john@example.com",
+            ["jane@example.com", "james@example.com"],
+            concat!(
+                "From: john@example.com\r\n",
+                "To: jane@example.com\r\n",
+                "BCC: james@example.com,\r\n",
+                "\tjim@example.com\r\n",
+                "Subject: Hi\r\n",
+                "\r\n",
+                "Bcc: this line is part of the body\r\n",
+            )
+            .as_bytes(),
+        )
+        .strip_bcc();
+
+        assert_eq!(message.rcpt_to.len(), 2);
+        assert_eq!(
+            std::str::from_utf8(&message.body).unwrap(),
+            concat!(
+                "From: john@example.com\r\n",
+                "To: jane@example.com\r\n",
+                "Subject: Hi\r\n",
+                "\r\n",
+                "Bcc: this line is part of the body\r\n",
+            )
+        );
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn parsed_message_bcc() {
+        let raw_message = concat!(
+            "From: John Doe <john@example.com>\n",
+            "To: Jane Doe <jane@example.com>\n",
+            "Bcc: james@example.com\n",
+            "Subject: Hi\n",
+            "\n",
+            "Hello\n",
+        );
+        let message = mail_parser::MessageParser::new()
+            .parse(raw_message.as_bytes())
+            .unwrap()
+            .into_message()
+            .unwrap();
+
+        let mut rcpt_to = message
+            .rcpt_to
+            .iter()
+            .map(|rcpt| (rcpt.email.as_ref(), rcpt.is_blind))
+            .collect::<Vec<_>>();
+        rcpt_to.sort_unstable();
+        assert_eq!(
+            rcpt_to,
+            [("james@example.com", true), ("jane@example.com", false)]
+        );
+        assert_eq!(message.body.as_ref(), raw_message.as_bytes());
+
+        let message = message.strip_bcc();
+        assert_eq!(
+            std::str::from_utf8(&message.body).unwrap(),
+            concat!(
+                "From: John Doe <john@example.com>\n",
+                "To: Jane Doe <jane@example.com>\n",
+                "Subject: Hi\n",
+                "\n",
+                "Hello\n",
+            )
+        );
+    }
+
+    #[test]
+    fn raw_message_tuple() {
+        let message = (
+            "john@example.com",
+            ["jane@example.com", "james@example.com"],
+            &b"Subject: Hi!\r\n\r\nHello, world!"[..],
+        )
+            .into_message()
+            .unwrap();
+        assert_eq!(message.mail_from.email, "john@example.com");
+        assert_eq!(
+            message
+                .rcpt_to
+                .iter()
+                .map(|rcpt| rcpt.email.as_ref())
+                .collect::<Vec<_>>(),
+            ["jane@example.com", "james@example.com"]
+        );
+        assert_eq!(message.body.as_ref(), b"Subject: Hi!\r\n\r\nHello, world!");
+
+        let message = (
+            String::from("john@example.com"),
+            vec![Address::parse("Jane <jane@example.com>").unwrap()],
+            b"Subject: Hi!\r\n\r\nHello, world!".to_vec(),
+        )
+            .into_message()
+            .unwrap();
+        assert_eq!(message.rcpt_to[0].email, "jane@example.com");
+    }
+
+    #[cfg(feature = "builder")]
+    #[test]
+    fn builder_rejects_injected_addresses() {
+        use mail_builder::MessageBuilder;
+
+        for (from, to) in [
+            (
+                "john@example.com\r\nBcc: evil@example.com",
+                "jane@example.com",
+            ),
+            (
+                "john@example.com",
+                "jane@example.com\r\nRCPT TO:<evil@example.com>",
+            ),
+            ("john@example.com", "jane@example.com>\nX-Injected: yes"),
+        ] {
+            assert!(matches!(
+                MessageBuilder::new()
+                    .from(from)
+                    .to(to)
+                    .subject("Hi!")
+                    .text_body("Hello")
+                    .into_message(),
+                Err(crate::Error::InvalidAddress)
+            ));
+        }
+
+        let message = MessageBuilder::new()
+            .from(("John Doe", "john@example.com"))
+            .to(vec![("Jane Doe", "jane@example.com")])
+            .bcc("james@example.com")
+            .subject("Hi!")
+            .text_body("Hello")
+            .into_message()
+            .unwrap();
+        assert_eq!(message.mail_from.email, "john@example.com");
+        assert_eq!(message.rcpt_to.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn pipelined_envelope_errors() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut capabilities = EhloResponse::new("mx.example.com".to_string());
+        capabilities.capabilities = EXT_PIPELINING;
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.capabilities = Some(capabilities);
+
+        // The first recipient is rejected while the following ones, pipelined
+        // behind it, are accepted: all replies must be consumed.
+        let server_task = tokio::spawn(async move {
+            let expected = concat!(
+                "MAIL FROM:<john@example.com>\r\n",
+                "RCPT TO:<jane@example.com>\r\n",
+                "RCPT TO:<bill@example.com>\r\n",
+                "RCPT TO:<jim@example.com>\r\n",
+                "DATA\r\n",
+            );
+            let mut buf = vec![0u8; expected.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+            server
+                .write_all(
+                    concat!(
+                        "250 OK\r\n",
+                        "550 No such user\r\n",
+                        "250 OK\r\n",
+                        "250 OK\r\n",
+                        "554 No valid recipients\r\n",
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let mut buf = vec![0u8; 6];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, b"RSET\r\n");
+            server.write_all(b"250 Reset\r\n").await.unwrap();
+            server
+        });
+        let result = client
+            .send(Message::new(
+                "john@example.com",
+                ["jane@example.com", "bill@example.com", "jim@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await;
+        assert!(matches!(result, Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 550));
+        assert!(!client.is_poisoned());
+        client.rset().await.unwrap();
+        let mut server = server_task.await.unwrap();
+
+        // The server closes the session halfway through the group
+        let server_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"250 OK\r\n421 Too many errors\r\n")
+                .await
+                .unwrap();
+        });
+        let result = client
+            .send(Message::new(
+                "john@example.com",
+                ["jane@example.com", "bill@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await;
+        server_task.await.unwrap();
+        assert!(matches!(result, Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 421));
+    }
+
+    #[tokio::test]
+    async fn pipelined_envelope() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut capabilities = EhloResponse::new("mx.example.com".to_string());
+        capabilities.capabilities = EXT_PIPELINING;
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.capabilities = Some(capabilities);
+
+        // Accepted transaction
+        let server_task = tokio::spawn(async move {
+            let expected = concat!(
+                "MAIL FROM:<john@example.com>\r\n",
+                "RCPT TO:<jane@example.com>\r\n",
+                "RCPT TO:<bill@example.com>\r\n",
+                "DATA\r\n"
+            );
+            let mut buf = vec![0u8; expected.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+            server
+                .write_all(b"250 OK\r\n250 OK\r\n250 OK\r\n354 Go ahead\r\n")
+                .await
+                .unwrap();
+            let expected = "Subject: Hi\r\n\r\nHello\r\n.\r\n";
+            let mut buf = vec![0u8; expected.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+            server.write_all(b"250 Queued\r\n").await.unwrap();
+            server
+        });
+        client
+            .send(Message::new(
+                "john@example.com",
+                ["jane@example.com", "bill@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await
+            .unwrap();
+        let mut server = server_task.await.unwrap();
+
+        // Rejected recipient while DATA is accepted
+        let server_task = tokio::spawn(async move {
+            let expected = concat!(
+                "MAIL FROM:<john@example.com>\r\n",
+                "RCPT TO:<jane@example.com>\r\n",
+                "DATA\r\n"
+            );
+            let mut buf = vec![0u8; expected.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+            server
+                .write_all(b"250 OK\r\n550 No such user\r\n354 Go ahead\r\n")
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        match client
+            .send(Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await
+        {
+            Err(crate::Error::UnexpectedReply(reply)) => assert_eq!(reply.code(), 550),
+            other => panic!("Unexpected result {other:?}"),
+        }
+        assert!(client.is_poisoned());
+        assert!(matches!(
+            client
+                .send(Message::new(
+                    "john@example.com",
+                    ["jane@example.com"],
+                    &b"Subject: Hi\r\n\r\nHello"[..],
+                ))
+                .await,
+            Err(crate::Error::ConnectionClosed)
+        ));
+        drop(client);
+        assert_eq!(server_task.await.unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn pipelined_abort_skips_reset() {
+        let (stream, mut server) = tokio::io::duplex(4096);
+        let mut capabilities = EhloResponse::new("mx.example.com".to_string());
+        capabilities.capabilities = EXT_PIPELINING;
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.capabilities = Some(capabilities);
+        client.reset_on_error = true;
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let written_ = written.clone();
+        client.set_on_wire(Some(Arc::new(move |direction, bytes| {
+            if direction == crate::Direction::Write {
+                written_.lock().unwrap().extend_from_slice(bytes);
+            }
+        })));
+
+        // The transaction is aborted by closing the connection, no RSET
+        // may follow it and later transactions are refused.
+        let server_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"250 OK\r\n550 No such user\r\n354 Go ahead\r\n")
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+        let message = || {
+            Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            )
+        };
+        assert!(matches!(
+            client.send(message()).await,
+            Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 550
+        ));
+        assert!(matches!(
+            client.send(message()).await,
+            Err(crate::Error::ConnectionClosed)
+        ));
+        assert_eq!(
+            String::from_utf8(written.lock().unwrap().clone()).unwrap(),
+            "MAIL FROM:<john@example.com>\r\nRCPT TO:<jane@example.com>\r\nDATA\r\n"
+        );
+        drop(client);
+        assert_eq!(server_task.await.unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn pipelined_abort_stops_sending() {
+        let message = |rcpt_to: &'static [&'static str]| {
+            Message::new(
+                "john@example.com",
+                rcpt_to.iter().copied(),
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            )
+        };
+        let abort_server = |mut server: tokio::io::DuplexStream| async move {
+            let mut buf = vec![0u8; 1024];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"250 OK\r\n550 No such user\r\n354 Go ahead\r\n")
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let mut capabilities = EhloResponse::new("mx.example.com".to_string());
+        capabilities.capabilities = EXT_PIPELINING;
+
+        // Remaining messages
+        let (stream, server) = tokio::io::duplex(4096);
+        let server_task = tokio::spawn(abort_server(server));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.capabilities = Some(capabilities.clone());
+        let results = client
+            .send_all([
+                message(&["jane@example.com"]),
+                message(&["bill@example.com"]),
+            ])
+            .await;
+        assert!(
+            matches!(&results[0], Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 550)
+        );
+        assert!(matches!(results[1], Err(crate::Error::NotAttempted)));
+        drop(client);
+        assert_eq!(server_task.await.unwrap(), b"");
+
+        // Remaining batches
+        let (stream, server) = tokio::io::duplex(4096);
+        let server_task = tokio::spawn(abort_server(server));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.capabilities = Some(capabilities);
+        client.max_rcpt_per_message = Some(1);
+        let results = client
+            .send_batched(message(&["jane@example.com", "bill@example.com"]))
+            .await
+            .unwrap();
+        assert!(
+            matches!(&results[0].1, Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 550)
+        );
+        assert!(matches!(results[1].1, Err(crate::Error::NotAttempted)));
+        drop(client);
+        assert_eq!(server_task.await.unwrap(), b"");
+    }
+
+    /// Replays a scripted SMTP conversation, where each entry holds the
+    /// expected command and the reply to send. A `.` command consumes the
+    /// message body up to the end-of-data marker.
+    async fn scripted_server(
+        stream: tokio::io::DuplexStream,
+        script: Vec<(&'static str, &'static str)>,
+    ) -> BufReader<tokio::io::DuplexStream> {
+        let mut stream = BufReader::new(stream);
+        for (expected, reply) in script {
+            let mut line = String::new();
+            if expected == "." {
+                while line != ".\r\n" {
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                }
+            } else {
+                stream.read_line(&mut line).await.unwrap();
+                assert_eq!(line.trim_end(), expected);
+            }
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        }
+        stream
+    }
+
+    #[tokio::test]
+    async fn message_too_large() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                (
+                    "MAIL FROM:<john@example.com>",
+                    "552 5.3.4 Message size exceeds fixed limit of 1048576 bytes\r\n",
+                ),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<jane@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "552 Message size exceeds maximum\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<jane@example.com>", "552 5.2.2 Mailbox full\r\n"),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.capabilities = Some(
+            EhloResponse::parse(&mut b"250-mx.example.com\r\n250 SIZE 2048\r\n".iter()).unwrap(),
+        );
+
+        let results = client
+            .send_all((0..3).map(|_| {
+                Message::new(
+                    "john@example.com",
+                    ["jane@example.com"],
+                    &b"Subject: Hi\r\n\r\nHello"[..],
+                )
+            }))
+            .await;
+        server.await.unwrap();
+
+        assert!(matches!(
+            results[0],
+            Err(crate::Error::MessageTooLarge(Some(1048576)))
+        ));
+        assert!(matches!(
+            results[1],
+            Err(crate::Error::MessageTooLarge(Some(2048)))
+        ));
+        assert!(matches!(
+            &results[2],
+            Err(crate::Error::UnexpectedReply(reply)) if reply.code() == 552
+        ));
+    }
+
+    #[test]
+    fn to_eml() {
+        let message = Message::new(
+            "john@example.com",
+            ["jane@example.com"],
+            &b"Subject: Hi\n\n.Hello\n"[..],
+        );
+        assert_eq!(message.to_eml(), b"Subject: Hi\n\n.Hello\n");
+
+        let mut client = SmtpClient::new(tokio::io::duplex(1).0, Duration::from_secs(30));
+        client.normalize_line_endings = true;
+        assert_eq!(
+            client.to_eml(message).unwrap(),
+            b"Subject: Hi\r\n\r\n.Hello\r\n"
+        );
+
+        client.add_missing_headers = true;
+        let eml = client
+            .to_eml(Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .unwrap();
+        let eml = std::str::from_utf8(&eml).unwrap();
+        assert!(eml.starts_with("Date: "), "{eml}");
+        assert!(eml.contains("\r\nMessage-ID: <"), "{eml}");
+        assert!(eml.ends_with("\r\nSubject: Hi\r\n\r\nHello"), "{eml}");
+    }
+
+    #[tokio::test]
+    async fn send_and_quit() {
+        for (rcpt_reply, expected) in [("250 OK\r\n", None), ("550 No such user\r\n", Some(550))] {
+            let (stream, server) = tokio::io::duplex(4096);
+            let script = if expected.is_none() {
+                vec![
+                    ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                    ("RCPT TO:<jane@example.com>", rcpt_reply),
+                    ("DATA", "354 Go ahead\r\n"),
+                    (".", "250 Queued\r\n"),
+                    ("QUIT", "221 Bye\r\n"),
+                ]
+            } else {
+                vec![
+                    ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                    ("RCPT TO:<jane@example.com>", rcpt_reply),
+                    ("QUIT", "221 Bye\r\n"),
+                ]
+            };
+            let server = tokio::spawn(async move {
+                let mut stream = scripted_server(server, script).await;
+                // The connection is shut down after QUIT
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap()
+            });
+            let client = SmtpClient::new(stream, Duration::from_secs(30));
+
+            let result = client
+                .send_and_quit(Message::new(
+                    "john@example.com",
+                    ["jane@example.com"],
+                    &b"Subject: Hi\r\n\r\nHello"[..],
+                ))
+                .await;
+            assert_eq!(
+                result.err().map(|err| match err {
+                    crate::Error::UnexpectedReply(reply) => reply.code(),
+                    _ => 0,
+                }),
+                expected
+            );
+            assert_eq!(server.await.unwrap(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_all_stops_on_421() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<jane@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<bill@example.com>", "550 No such user\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "421 Shutting down\r\n"),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+
+        let results = client
+            .send_all(
+                [
+                    "jane@example.com",
+                    "bill@example.com",
+                    "jane@example.com",
+                    "jane@example.com",
+                ]
+                .map(|rcpt| {
+                    Message::new("john@example.com", [rcpt], &b"Subject: Hi\r\n\r\nHello"[..])
+                }),
+            )
+            .await;
+        server.await.unwrap();
+
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| result.as_ref().err().map(|err| err.kind()))
+                .collect::<Vec<_>>(),
+            [
+                None,
+                Some(ErrorKind::UnexpectedReply),
+                Some(ErrorKind::UnexpectedReply),
+                Some(ErrorKind::NotAttempted)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn max_rcpt_per_message() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<a@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<b@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<c@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<d@example.com>", "452 Too many recipients\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<e@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<a@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<b@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<c@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.max_rcpt_per_message = Some(2);
+
+        let results = client
+            .send_batched(Message::new(
+                "john@example.com",
+                [
+                    "a@example.com",
+                    "b@example.com",
+                    "c@example.com",
+                    "d@example.com",
+                    "e@example.com",
+                ],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .map(|(rcpt, result)| (
+                    rcpt.email.as_ref(),
+                    result.as_ref().err().map(|err| err.kind())
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("a@example.com", None),
+                ("b@example.com", None),
+                ("c@example.com", Some(ErrorKind::UnexpectedReply)),
+                ("d@example.com", Some(ErrorKind::UnexpectedReply)),
+                ("e@example.com", None),
+            ]
+        );
+
+        // Messages within the limit are sent in a single transaction
+        client.rset().await.unwrap();
+        client.max_rcpt_per_message = Some(3);
+        client
+            .send(Message::new(
+                "john@example.com",
+                ["a@example.com", "b@example.com", "c@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await
+            .unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_bulk() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<a@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<b@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<c@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<d@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "421 Shutting down\r\n"),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.max_rcpt_per_message = Some(2);
+
+        let recipients = (b'a'..=b'e').map(|ch| format!("{}@example.com", ch as char));
+        let results = client
+            .send_bulk(
+                "john@example.com",
+                recipients,
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            )
+            .await;
+        server.await.unwrap();
+
+        assert_eq!(
+            results
+                .iter()
+                .map(|(rcpt, result)| (
+                    rcpt.email.as_ref(),
+                    result.as_ref().err().map(|err| err.kind())
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("a@example.com", None),
+                ("b@example.com", None),
+                ("c@example.com", Some(ErrorKind::UnexpectedReply)),
+                ("d@example.com", Some(ErrorKind::UnexpectedReply)),
+                ("e@example.com", Some(ErrorKind::NotAttempted)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_tracked() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<a@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 2.0.0 Ok: queued as 4B2A1C\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<a@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 2.0.0 Ok: queued as 6D1B44\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<b@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 2.0.0 Ok: queued as 9F3E07\r\n"),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+
+        let response = client
+            .send_tracked(Message::new(
+                "john@example.com",
+                ["a@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.code(), 250);
+        assert_eq!(response.message(), "Ok: queued as 4B2A1C");
+
+        // The reply to the last transaction is returned
+        client.rset().await.unwrap();
+        client.max_rcpt_per_message = Some(1);
+        let response = client
+            .send_tracked(Message::new(
+                "john@example.com",
+                ["a@example.com", "b@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.message(), "Ok: queued as 9F3E07");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_with_headers() {
+        let (mut client, server) = MockServer::new()
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 Queued")
+            .connect()
+            .await
+            .unwrap();
+        let message = || {
+            Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            )
+        };
+
+        // Header injection is rejected before starting a transaction
+        for headers in [
+            &[("X-Mailer", "Test\r\nBcc: evil@example.com")][..],
+            &[("X-Mailer", "Test\nBcc: evil@example.com")],
+            &[("Bcc: evil@example.com\r\nX-Mailer", "Test")],
+            &[("X Mailer", "Test")],
+            &[("", "Test")],
+        ] {
+            assert!(matches!(
+                client.send_with_headers(message(), headers).await,
+                Err(crate::Error::InvalidHeader)
+            ));
+        }
+
+        client
+            .send_with_headers(
+                message(),
+                &[
+                    ("Received", "from relay.example.com\r\n\tby mx.example.com"),
+                    ("X-Mailer", "mail-send"),
+                ],
+            )
+            .await
+            .unwrap();
+        client.quit().await.unwrap();
+        assert_eq!(
+            String::from_utf8(server.await.unwrap().unwrap().messages.remove(0)).unwrap(),
+            concat!(
+                "Received: from relay.example.com\r\n\tby mx.example.com\r\n",
+                "X-Mailer: mail-send\r\n",
+                "Subject: Hi\r\n\r\nHello\r\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn send_raw() {
+        let (mut client, server) = MockServer::new()
+            .expect("MAIL FROM:<john@example.com> SIZE=21", "250 OK")
+            .expect("RCPT TO:<jane@example.com> NOTIFY=FAILURE", "250 OK")
+            .expect("RCPT TO:<bill@example.com> NOTIFY=FAILURE", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 2.0.0 Ok: queued as 4B2A1C")
+            .connect()
+            .await
+            .unwrap();
+        let mut from_params = Parameters::new();
+        from_params.size(21);
+        let mut rcpt_params = Parameters::new();
+        rcpt_params.notify(RCPT_NOTIFY_FAILURE);
+
+        assert!(matches!(
+            client
+                .send_raw("john@example.com", &[], b"", &from_params, &rcpt_params)
+                .await,
+            Err(crate::Error::MissingRcptTo)
+        ));
+        let response = client
+            .send_raw(
+                "john@example.com",
+                &["jane@example.com", "bill@example.com"],
+                b"Subject: Hi\r\n\r\nHello",
+                &from_params,
+                &rcpt_params,
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.message(), "Ok: queued as 4B2A1C");
+        client.quit().await.unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap().messages,
+            [b"Subject: Hi\r\n\r\nHello\r\n".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_before_send() {
+        let (mut client, server) = MockServer::new()
+            .expect("NOOP", "250 OK")
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 Queued")
+            .connect()
+            .await
+            .unwrap();
+        client.probe_before_send = true;
+        let message = || {
+            Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            )
+        };
+        client.send(message()).await.unwrap();
+        client.quit().await.unwrap();
+        server.await.unwrap().unwrap();
+
+        // The server closed the connection while idle
+        let (stream, server) = tokio::io::duplex(4096);
+        drop(server);
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        assert!(matches!(
+            client.send(message()).await,
+            Err(crate::Error::Io(_))
+        ));
+        let (stream, server) = tokio::io::duplex(4096);
+        drop(server);
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.probe_before_send = true;
+        assert!(matches!(
+            client.send(message()).await,
+            Err(crate::Error::ConnectionClosed)
+        ));
+        assert!(client.is_poisoned());
+        assert!(matches!(
+            client
+                .send_raw(
+                    "john@example.com",
+                    &["jane@example.com"],
+                    b"Hello",
+                    &Parameters::new(),
+                    &Parameters::new()
+                )
+                .await,
+            Err(crate::Error::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn line_length_policy() {
+        let (mut client, server) = MockServer::new()
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 Queued")
+            .connect()
+            .await
+            .unwrap();
+        let long_line = "0123456789".repeat(200);
+        let body = format!("Subject: Hi\r\n\r\n{long_line}\r\nBye");
+        let message = || Message::new("john@example.com", ["jane@example.com"], body.as_bytes());
+
+        // Overlong lines are refused before starting a transaction
+        client.line_length_policy = LineLengthPolicy::Reject;
+        assert!(matches!(
+            client.send(message()).await,
+            Err(crate::Error::LineTooLong)
+        ));
+        assert!(matches!(
+            client.to_eml(message()),
+            Err(crate::Error::LineTooLong)
+        ));
+
+        client.line_length_policy = LineLengthPolicy::Wrap;
+        client.send(message()).await.unwrap();
+        client.quit().await.unwrap();
+        assert_eq!(
+            String::from_utf8(server.await.unwrap().unwrap().messages.remove(0)).unwrap(),
+            format!(
+                "Subject: Hi\r\n\r\n{}\r\n{}\r\n{}\r\nBye\r\n",
+                &long_line[..998],
+                &long_line[998..1996],
+                &long_line[1996..]
+            )
+        );
+    }
+
+    #[test]
+    fn wrap_long_lines() {
+        let short = b"Subject: Hi\r\n\r\nHello\r\n";
+        assert!(matches!(super::wrap_long_lines(short), Cow::Borrowed(_)));
+
+        // Headers are folded at whitespace, or at the limit with a space
+        let words = "word ".repeat(300);
+        let token = "x".repeat(2000);
+        let message = format!("To: {words}\r\nX-Token: {token}\r\n\r\n{token}");
+        let wrapped = super::wrap_long_lines(message.as_bytes());
+        let wrapped = std::str::from_utf8(&wrapped).unwrap();
+        let lines = wrapped.split("\r\n").collect::<Vec<_>>();
+        assert!(lines.iter().all(|line| line.len() <= 998), "{lines:?}");
+        assert_eq!(
+            lines[..8].iter().map(|line| line.len()).collect::<Vec<_>>(),
+            [993, 511, 8, 998, 998, 7, 0, 998]
+        );
+        assert!(lines[1].starts_with(" word"));
+        assert!(lines[3].starts_with(" xxx") && lines[4].starts_with(" xxx"));
+        assert_eq!(
+            wrapped.replace("\r\n ", " ").replace("\r\n", ""),
+            format!(
+                "To: {words}X-Token: {} {} {}{token}",
+                &token[..997],
+                &token[997..1994],
+                &token[1994..]
+            )
+        );
+
+        // Multi-byte characters are not split
+        let message = format!("\r\n{}", "ñ".repeat(600));
+        let wrapped = super::wrap_long_lines(message.as_bytes());
+        let wrapped = std::str::from_utf8(&wrapped).unwrap();
+        assert_eq!(wrapped.replace("\r\n", ""), message.replace("\r\n", ""));
+        assert!(wrapped.split("\r\n").all(|line| line.len() <= 998));
+    }
+
+    #[cfg(feature = "dkim")]
+    #[tokio::test]
+    async fn send_signed_with_headers() {
+        use mail_auth::{common::crypto::Ed25519Key, dkim::DkimSigner};
+
+        let pk = Ed25519Key::from_pkcs8_der(&Ed25519Key::generate_pkcs8().unwrap()).unwrap();
+        let signer = DkimSigner::from_key(pk)
+            .domain("example.com")
+            .selector("default")
+            .headers(["From", "Subject", "Received"]);
+        let (mut client, server) = MockServer::new()
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 Queued")
+            .connect()
+            .await
+            .unwrap();
+        client
+            .send_signed_with_headers(
+                Message::new(
+                    "john@example.com",
+                    ["jane@example.com"],
+                    &b"From: john@example.com\r\nSubject: Hi\r\n\r\nHello"[..],
+                ),
+                &[("Received", "from relay.example.com by mx.example.com")],
+                &signer,
+            )
+            .await
+            .unwrap();
+        client.quit().await.unwrap();
+
+        // The signature is topmost and covers the prepended header
+        let message = String::from_utf8(server.await.unwrap().unwrap().messages.remove(0)).unwrap();
+        let (signature, rest) = message.split_once("\r\nReceived: ").unwrap();
+        assert!(signature.starts_with("DKIM-Signature: "), "{signature}");
+        assert!(signature.contains(":Received;"), "{signature}");
+        assert!(rest.starts_with("from relay.example.com by mx.example.com\r\nFrom: "));
+    }
+
+    #[cfg(feature = "dkim")]
+    #[tokio::test]
+    async fn send_signed_with_headers_above() {
+        use crate::smtp::{message::HeaderPosition, received::Received};
+        use mail_auth::{common::crypto::Ed25519Key, dkim::DkimSigner};
+
+        let pk = Ed25519Key::from_pkcs8_der(&Ed25519Key::generate_pkcs8().unwrap()).unwrap();
+        let signer = DkimSigner::from_key(pk)
+            .domain("example.com")
+            .selector("default")
+            .headers(["From", "Subject"]);
+        let (mut client, server) = MockServer::new()
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 Queued")
+            .connect()
+            .await
+            .unwrap();
+        let received = Received::build(
+            "relay.example.com",
+            None,
+            "mx.example.com",
+            "ESMTP",
+            Some("4B2A1C"),
+            UNIX_EPOCH,
+        );
+        client
+            .send_signed_with_headers_at(
+                Message::new(
+                    "john@example.com",
+                    ["jane@example.com"],
+                    &b"From: john@example.com\r\nSubject: Hi\r\n\r\nHello"[..],
+                ),
+                &[("Received", &received)],
+                &signer,
+                HeaderPosition::AboveSignature,
+            )
+            .await
+            .unwrap();
+        client.quit().await.unwrap();
+
+        // The trace header is topmost, followed by the signature
+        let message = String::from_utf8(server.await.unwrap().unwrap().messages.remove(0)).unwrap();
+        let rest = message
+            .strip_prefix(&format!("Received: {received}\r\n"))
+            .unwrap();
+        assert!(rest.starts_with("DKIM-Signature: "), "{rest}");
+        assert!(rest.ends_with("\r\nFrom: john@example.com\r\nSubject: Hi\r\n\r\nHello\r\n"));
+    }
+
+    #[cfg(feature = "dkim")]
+    #[tokio::test]
+    async fn send_signed_oversigned() {
+        use mail_auth::{common::crypto::Ed25519Key, dkim::DkimSigner};
+
+        // Subject is listed twice to protect against an added duplicate
+        let pk = Ed25519Key::from_pkcs8_der(&Ed25519Key::generate_pkcs8().unwrap()).unwrap();
+        let signer = DkimSigner::from_key(pk)
+            .domain("example.com")
+            .selector("default")
+            .headers(["From", "Subject", "Subject"]);
+        let (mut client, server) = MockServer::new()
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 Queued")
+            .connect()
+            .await
+            .unwrap();
+        client
+            .send_signed(
+                Message::new(
+                    "john@example.com",
+                    ["jane@example.com"],
+                    &b"From: john@example.com\r\nSubject: Hi\r\n\r\nHello"[..],
+                ),
+                &signer,
+            )
+            .await
+            .unwrap();
+        client.quit().await.unwrap();
+
+        let message = String::from_utf8(server.await.unwrap().unwrap().messages.remove(0)).unwrap();
+        let (signature, _) = message.split_once("\r\nFrom: ").unwrap();
+        let signature = signature.replace("\r\n\t", " ");
+        assert!(
+            signature.contains(" h=Subject:From:Subject;"),
+            "{signature}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_on_error() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<bill@example.com>", "550 No such user\r\n"),
+                ("RSET", "250 OK\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<jane@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.reset_on_error = true;
+
+        for (rcpt, is_ok) in [("bill@example.com", false), ("jane@example.com", true)] {
+            assert_eq!(
+                client
+                    .send(Message::new(
+                        "john@example.com",
+                        [rcpt],
+                        &b"Subject: Hi\r\n\r\nHello"[..],
+                    ))
+                    .await
+                    .is_ok(),
+                is_ok
+            );
+        }
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn future_release() {
+        let mut client = SmtpClient::new(tokio::io::duplex(1).0, Duration::from_secs(30));
+        let release_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let message = Message::new("john@example.com", ["jane@example.com"], &b""[..])
+            .release_after(Duration::from_secs(3600));
+        assert_eq!(message.mail_from.parameters.to_string(), " HOLDFOR=3600");
+        let message =
+            Message::new("john@example.com", ["jane@example.com"], &b""[..]).release_at(release_at);
+        assert_eq!(
+            message.mail_from.parameters.to_string(),
+            " HOLDUNTIL=2023-11-14T22:13:20Z"
+        );
+        assert_eq!(
+            super::parse_date_time("2023-11-14T22:13:20Z"),
+            Some(1_700_000_000)
+        );
+        assert_eq!(
+            super::parse_date_time("2024-02-29T00:00:00Z"),
+            Some(1_709_164_800)
+        );
+        assert_eq!(
+            super::format_date_time(1_709_164_800),
+            "2024-02-29T00:00:00Z"
+        );
+
+        // Capabilities unknown
+        let mut message = Message::new("john@example.com", ["jane@example.com"], &b""[..])
+            .release_after(Duration::from_secs(3600));
+        assert!(client.check_parameters(&mut message).is_ok());
+
+        // Extension not advertised
+        client.capabilities = Some(EhloResponse::new("mx.example.com".to_string()));
+        assert!(matches!(
+            client.check_parameters(&mut message),
+            Err(crate::Error::UnsupportedExtension("FUTURERELEASE"))
+        ));
+
+        // Limit exceeded
+        let mut capabilities = EhloResponse::new("mx.example.com".to_string());
+        capabilities.capabilities = EXT_FUTURE_RELEASE;
+        capabilities.future_release_interval = 1800;
+        client.capabilities = Some(capabilities);
+        assert!(matches!(
+            client.check_parameters(&mut message),
+            Err(crate::Error::ParameterOutOfRange("HOLDFOR"))
+        ));
+        let mut message = Message::new("john@example.com", ["jane@example.com"], &b""[..])
+            .release_after(Duration::from_secs(600));
+        assert!(client.check_parameters(&mut message).is_ok());
+    }
+
+    #[test]
+    fn mt_priority() {
+        let mut client = SmtpClient::new(tokio::io::duplex(1).0, Duration::from_secs(30));
+
+        for (capabilities, priority, expected) in [
+            (EXT_MT_PRIORITY, 3, " MT-PRIORITY=3"),
+            (EXT_MT_PRIORITY, -20, " MT-PRIORITY=-9"),
+            (EXT_MT_PRIORITY, 100, " MT-PRIORITY=9"),
+            (0, 3, ""),
+        ] {
+            let mut response = EhloResponse::new("mx.example.com".to_string());
+            response.capabilities = capabilities;
+            client.capabilities = Some(response);
+
+            let mut message =
+                Message::new("john@example.com", ["jane@example.com"], &b""[..]).priority(priority);
+            client.check_parameters(&mut message).unwrap();
+            assert_eq!(message.mail_from.parameters.to_string(), expected);
+        }
+
+        // Capabilities unknown
+        client.capabilities = None;
+        let mut message =
+            Message::new("john@example.com", ["jane@example.com"], &b""[..]).priority(3);
+        client.check_parameters(&mut message).unwrap();
+        assert_eq!(message.mail_from.parameters.to_string(), " MT-PRIORITY=3");
+    }
+
+    #[test]
+    fn deliver_by() {
+        let mut client = SmtpClient::new(tokio::io::duplex(1).0, Duration::from_secs(30));
+
+        let mut message = Message::new("john@example.com", ["jane@example.com"], &b""[..])
+            .deliver_by(Duration::from_secs(120), DeliverByMode::Return);
+        assert_eq!(message.mail_from.parameters.to_string(), " BY=120;R");
+        assert!(client.check_parameters(&mut message).is_ok());
+        client.capabilities = Some(EhloResponse::new("mx.example.com".to_string()));
+        assert!(matches!(
+            client.check_parameters(&mut message),
+            Err(crate::Error::UnsupportedExtension("DELIVERBY"))
+        ));
+
+        for (min, is_ok) in [(0, true), (60, true), (120, true), (240, false)] {
+            let mut response = EhloResponse::new("mx.example.com".to_string());
+            response.capabilities = EXT_DELIVER_BY;
+            response.deliver_by = min;
+            client.capabilities = Some(response);
+
+            let mut message = Message::new("john@example.com", ["jane@example.com"], &b""[..])
+                .deliver_by(Duration::from_secs(120), DeliverByMode::Notify);
+            assert_eq!(message.mail_from.parameters.to_string(), " BY=120;N");
+            assert_eq!(
+                client.check_parameters(&mut message).is_ok(),
+                is_ok,
+                "{min}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn per_recipient_parameters() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                ("MAIL FROM:<john@example.com> RET=HDRS", "250 OK\r\n"),
+                (
+                    "RCPT TO:<jane@example.com> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;jane+2Bx@example.com",
+                    "250 OK\r\n",
+                ),
+                ("RCPT TO:<bill@example.com> NOTIFY=NEVER", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+
+        let mut message = Message::new(
+            "john@example.com",
+            ["jane@example.com", "bill@example.com"],
+            &b"Subject: Hi\r\n\r\nHello"[..],
+        );
+        message.mail_from.parameters.ret_headers();
+        message
+            .recipient_mut(0)
+            .unwrap()
+            .parameters
+            .notify(RCPT_NOTIFY_SUCCESS | RCPT_NOTIFY_FAILURE)
+            .orcpt("jane+x@example.com");
+        message
+            .recipient_mut(1)
+            .unwrap()
+            .add_param(("NOTIFY", "NEVER"));
+        assert!(message.recipient_mut(2).is_none());
+
+        client.send(message).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_send_poisons_client() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(scripted_server(
+            server,
+            vec![
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<jane@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", "250 Queued\r\n"),
+                ("MAIL FROM:<john@example.com>", "250 OK\r\n"),
+                ("RCPT TO:<jane@example.com>", "250 OK\r\n"),
+                ("DATA", "354 Go ahead\r\n"),
+                (".", ""),
+            ],
+        ));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        let message = || {
+            Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\nHello"[..],
+            )
+        };
+
+        client.send(message()).await.unwrap();
+        assert!(!client.is_poisoned());
+
+        // The server never replies to the message, drop the future
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), client.send(message()))
+                .await
+                .is_err()
+        );
+        assert!(client.is_poisoned());
+        let _server = server.await.unwrap();
+    }
+}
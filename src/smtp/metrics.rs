@@ -0,0 +1,33 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Counters published through the `metrics` facade.
+
+use metrics::counter;
+
+pub(crate) fn connection() {
+    counter!("mail_send.connections").increment(1);
+}
+
+pub(crate) fn bytes_sent(bytes: usize) {
+    counter!("mail_send.bytes_sent").increment(bytes as u64);
+}
+
+pub(crate) fn bytes_received(bytes: usize) {
+    counter!("mail_send.bytes_received").increment(bytes as u64);
+}
+
+pub(crate) fn send<T>(result: &crate::Result<T>) {
+    match result {
+        Ok(_) => counter!("mail_send.messages_sent").increment(1),
+        Err(err) => counter!("mail_send.messages_failed", "kind" => format!("{:?}", err.kind()))
+            .increment(1),
+    }
+}
@@ -0,0 +1,424 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! Scripted SMTP server for testing code built on the client without
+//! connecting to a real server.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+        DuplexStream,
+    },
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+use crate::SmtpClient;
+
+use super::AssertReply;
+
+/// Size of the in-memory buffers used by `MockServer::connect`.
+const MAX_DUPLEX_BUFFER: usize = 64 * 1024;
+
+/// A scripted SMTP server.
+///
+/// The server sends `greeting`, answers EHLO, HELO and LHLO with `hostname`
+/// and `capabilities`, and QUIT with a 221 reply. Any other command line,
+/// including the lines of a multi-step AUTH exchange, is matched against the
+/// next scripted exchange. After a 354 reply to DATA the message body is
+/// read up to the end-of-data marker and answered with the next exchange
+/// added with `expect_data`. BDAT chunks are read before replying.
+///
+/// A command that does not match the script is answered with a 500 reply,
+/// and the session ends with an error.
+#[derive(Debug, Clone)]
+pub struct MockServer {
+    pub greeting: String,
+    pub hostname: String,
+    pub capabilities: Vec<String>,
+    pub script: Vec<Exchange>,
+}
+
+/// A scripted command and the reply sent to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    /// The expected command line, compared without the trailing CRLF and
+    /// ignoring ASCII case. `None` matches the end of a DATA message body.
+    pub command: Option<String>,
+    /// The reply sent to the command, lines are terminated with CRLF.
+    pub reply: String,
+}
+
+/// What the server received during a session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    /// The command lines received, without the trailing CRLF.
+    pub commands: Vec<String>,
+    /// The messages received with DATA or BDAT, with dot-stuffing removed.
+    pub messages: Vec<Vec<u8>>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        MockServer {
+            greeting: "220 mock.example.com ESMTP".to_string(),
+            hostname: "mock.example.com".to_string(),
+            capabilities: Vec::new(),
+            script: Vec::new(),
+        }
+    }
+}
+
+impl MockServer {
+    /// Creates a server with no capabilities and an empty script.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the greeting sent when the session starts.
+    pub fn greeting(mut self, greeting: impl Into<String>) -> Self {
+        self.greeting = greeting.into();
+        self
+    }
+
+    /// Sets the EHLO capabilities, such as `PIPELINING` or `AUTH PLAIN LOGIN`.
+    pub fn capabilities<I>(mut self, capabilities: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.capabilities = capabilities.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Expects `command` and replies with `reply`.
+    pub fn expect(mut self, command: impl Into<String>, reply: impl Into<String>) -> Self {
+        self.script.push(Exchange {
+            command: Some(command.into()),
+            reply: reply.into(),
+        });
+        self
+    }
+
+    /// Expects the body of a message sent with DATA and replies with `reply`.
+    pub fn expect_data(mut self, reply: impl Into<String>) -> Self {
+        self.script.push(Exchange {
+            command: None,
+            reply: reply.into(),
+        });
+        self
+    }
+
+    /// Runs a session on `stream`, returning what was received once the
+    /// client sends QUIT or closes the connection. Fails if the client
+    /// deviated from the script or did not complete it.
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        self,
+        stream: S,
+    ) -> io::Result<Transcript> {
+        let mut stream = BufReader::new(stream);
+        let mut transcript = Transcript::default();
+        let mut script = self.script.into_iter();
+        let mut error = None;
+
+        write_reply(&mut stream, &self.greeting).await?;
+
+        loop {
+            let mut line = Vec::new();
+            if stream.read_until(b'\n', &mut line).await? == 0 {
+                break;
+            }
+            let command = String::from_utf8_lossy(&line).trim_end().to_string();
+            transcript.commands.push(command.clone());
+            let verb = command
+                .split_once(' ')
+                .map_or(command.as_str(), |(verb, _)| verb)
+                .to_ascii_uppercase();
+
+            match verb.as_str() {
+                "EHLO" | "LHLO" => {
+                    let mut reply = self.hostname.clone();
+                    for capability in &self.capabilities {
+                        reply.push_str("\r\n");
+                        reply.push_str(capability);
+                    }
+                    write_reply(&mut stream, &multiline(250, &reply)).await?;
+                    continue;
+                }
+                "HELO" => {
+                    write_reply(&mut stream, &format!("250 {}", self.hostname)).await?;
+                    continue;
+                }
+                "QUIT" => {
+                    write_reply(&mut stream, "221 Bye").await?;
+                    break;
+                }
+                "BDAT" => {
+                    let size = command
+                        .split_ascii_whitespace()
+                        .nth(1)
+                        .and_then(|size| size.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    let mut chunk = vec![0u8; size];
+                    stream.read_exact(&mut chunk).await?;
+                    match transcript.messages.last_mut() {
+                        Some(message) if !is_first_chunk(&transcript.commands) => {
+                            message.extend_from_slice(&chunk)
+                        }
+                        _ => transcript.messages.push(chunk),
+                    }
+                }
+                _ => (),
+            }
+
+            let reply = match script.next() {
+                Some(Exchange {
+                    command: Some(expected),
+                    reply,
+                }) if expected.eq_ignore_ascii_case(&command) => reply,
+                exchange => {
+                    error.get_or_insert_with(|| match exchange {
+                        Some(exchange) => format!(
+                            "expected {:?}, received {command:?}",
+                            exchange.command.as_deref().unwrap_or("message body")
+                        ),
+                        None => format!("unexpected command {command:?}"),
+                    });
+                    write_reply(&mut stream, "500 5.5.1 Unexpected command").await?;
+                    continue;
+                }
+            };
+            write_reply(&mut stream, &reply).await?;
+
+            if verb == "DATA" && reply.starts_with("354") {
+                let mut message = Vec::new();
+                loop {
+                    let mut line = Vec::new();
+                    if stream.read_until(b'\n', &mut line).await? == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed during DATA",
+                        ));
+                    } else if line == b".\r\n" {
+                        break;
+                    }
+                    let line = line.strip_prefix(b".").unwrap_or(&line);
+                    message.extend_from_slice(line);
+                }
+                transcript.messages.push(message);
+
+                match script.next() {
+                    Some(Exchange {
+                        command: None,
+                        reply,
+                    }) => write_reply(&mut stream, &reply).await?,
+                    exchange => {
+                        error.get_or_insert_with(|| match exchange.and_then(|e| e.command) {
+                            Some(expected) => {
+                                format!("expected {expected:?}, received message body")
+                            }
+                            None => "unexpected message body".to_string(),
+                        });
+                        write_reply(&mut stream, "554 5.5.1 Unexpected message").await?;
+                    }
+                }
+            }
+        }
+
+        if let Some(exchange) = script.next() {
+            error.get_or_insert_with(|| {
+                format!(
+                    "session ended before {:?}",
+                    exchange.command.as_deref().unwrap_or("message body")
+                )
+            });
+        }
+        match error {
+            Some(error) => Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+            None => Ok(transcript),
+        }
+    }
+
+    /// Runs a session on an in-memory stream and returns a client connected
+    /// to it, with the greeting already read, along with the session task.
+    pub async fn connect(
+        self,
+    ) -> crate::Result<(SmtpClient<DuplexStream>, JoinHandle<io::Result<Transcript>>)> {
+        let (stream, server) = tokio::io::duplex(MAX_DUPLEX_BUFFER);
+        let server = tokio::spawn(self.serve(server));
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.read().await?.assert_positive_completion()?;
+        Ok((client, server))
+    }
+
+    /// Listens on a local port and runs a session on the first connection
+    /// accepted, returning the address to connect to and the session task.
+    pub async fn listen(self) -> io::Result<(SocketAddr, JoinHandle<io::Result<Transcript>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        Ok((
+            addr,
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await?;
+                self.serve(stream).await
+            }),
+        ))
+    }
+}
+
+/// Formats a possibly multi-line reply, one line per `\r\n` separated item.
+fn multiline(code: u16, text: &str) -> String {
+    let lines = text.split("\r\n").collect::<Vec<_>>();
+    let mut reply = String::new();
+    for (pos, line) in lines.iter().enumerate() {
+        if pos > 0 {
+            reply.push_str("\r\n");
+        }
+        let separator = if pos + 1 < lines.len() { '-' } else { ' ' };
+        reply.push_str(&format!("{code}{separator}{line}"));
+    }
+    reply
+}
+
+/// Returns `true` if the last command starts a new BDAT transfer.
+fn is_first_chunk(commands: &[String]) -> bool {
+    !commands
+        .iter()
+        .rev()
+        .nth(1)
+        .is_some_and(|prev| prev.to_ascii_uppercase().starts_with("BDAT"))
+}
+
+async fn write_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: &str) -> io::Result<()> {
+    stream.write_all(reply.trim_end().as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use smtp_proto::AUTH_LOGIN;
+
+    use crate::{
+        smtp::message::{Message, Parameters},
+        Credentials, SmtpClientBuilder,
+    };
+
+    use super::MockServer;
+
+    #[tokio::test]
+    async fn mock_session() {
+        let (addr, server) = MockServer::new()
+            .capabilities(["PIPELINING", "AUTH PLAIN LOGIN", "8BITMIME"])
+            .expect("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0 Accepted")
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .expect("DATA", "354 Go ahead")
+            .expect_data("250 2.0.0 Queued as 1A2B3C")
+            .listen()
+            .await
+            .unwrap();
+
+        let mut builder = SmtpClientBuilder::new("mock.example.com", addr.port())
+            .implicit_tls(false)
+            .require_tls_for_auth(false)
+            .helo_host("client.example.com")
+            .credentials(Credentials::new("john", "secret"))
+            .timeout(Duration::from_secs(5));
+        builder.addr = addr.to_string();
+        let mut client = builder.connect_plain().await.unwrap();
+        client
+            .send(Message::new(
+                "john@example.com",
+                ["jane@example.com"],
+                &b"Subject: Hi\r\n\r\n.Hello"[..],
+            ))
+            .await
+            .unwrap();
+        client.quit().await.unwrap();
+
+        let transcript = server.await.unwrap().unwrap();
+        assert_eq!(transcript.commands[0], "EHLO client.example.com");
+        assert_eq!(transcript.commands.last().unwrap(), "QUIT");
+        assert_eq!(
+            transcript.messages,
+            [b"Subject: Hi\r\n\r\n.Hello\r\n".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_mismatch() {
+        let (mut client, server) = MockServer::new()
+            .expect("MAIL FROM:<john@example.com>", "250 OK")
+            .expect("RCPT TO:<jane@example.com>", "250 OK")
+            .connect()
+            .await
+            .unwrap();
+        client
+            .mail_from("bill@example.com", &Parameters::default())
+            .await
+            .unwrap_err();
+        client.quit().await.unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap_err().to_string(),
+            "expected \"MAIL FROM:<john@example.com>\", received \"MAIL FROM:<bill@example.com>\""
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_authenticate() {
+        // LOGIN is attempted after PLAIN fails with a temporary error
+        let (mut client, server) = MockServer::new()
+            .capabilities(["AUTH PLAIN LOGIN"])
+            .expect(
+                "AUTH PLAIN AGpvaG4Ac2VjcmV0",
+                "454 4.7.0 Temporary authentication failure",
+            )
+            .expect("AUTH LOGIN", "334 VXNlcm5hbWU6")
+            .expect("am9obg==", "334 UGFzc3dvcmQ6")
+            .expect("c2VjcmV0", "235 2.7.0 Accepted")
+            .connect()
+            .await
+            .unwrap();
+        let capabilities = client.ehlo("client.example.com").await.unwrap();
+        assert_eq!(capabilities.hostname, "mock.example.com");
+        client
+            .authenticate(Credentials::new("john", "secret"), &capabilities)
+            .await
+            .unwrap();
+        assert_eq!(client.auth_mechanism(), Some(AUTH_LOGIN));
+        client.quit().await.unwrap();
+        server.await.unwrap().unwrap();
+
+        // Rejected credentials are not retried with another mechanism
+        let (mut client, server) = MockServer::new()
+            .capabilities(["AUTH PLAIN LOGIN"])
+            .expect(
+                "AUTH PLAIN AGpvaG4Ac2VjcmV0",
+                "535 5.7.8 Authentication credentials invalid",
+            )
+            .connect()
+            .await
+            .unwrap();
+        let capabilities = client.ehlo("client.example.com").await.unwrap();
+        assert!(matches!(
+            client
+                .authenticate(Credentials::new("john", "secret"), &capabilities)
+                .await,
+            Err(crate::Error::AuthenticationFailed(reply)) if reply.code() == 535
+        ));
+        client.quit().await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -13,10 +13,22 @@ use smtp_proto::{Response, Severity};
 pub mod auth;
 pub mod builder;
 pub mod client;
+#[cfg(feature = "dane")]
+pub mod dane;
 pub mod ehlo;
 pub mod envelope;
 pub mod message;
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod mta_sts;
+#[cfg(feature = "ntlm")]
+pub(crate) mod ntlm;
+pub mod proxy;
+pub mod received;
 pub mod tls;
+pub mod xclient;
 
 impl From<auth::Error> for crate::Error {
     fn from(err: auth::Error) -> Self {
@@ -29,6 +41,8 @@ pub trait AssertReply: Sized {
     fn assert_positive_completion(self) -> crate::Result<()>;
     fn assert_severity(self, severity: Severity) -> crate::Result<()>;
     fn assert_code(self, code: u16) -> crate::Result<()>;
+    fn assert_code_in(self, codes: &[u16]) -> crate::Result<()>;
+    fn assert_any_positive(self) -> crate::Result<()>;
 }
 
 impl AssertReply for Response<String> {
@@ -67,4 +81,47 @@ impl AssertReply for Response<String> {
             Err(crate::Error::UnexpectedReply(self))
         }
     }
+
+    /// Returns Ok if the reply has one of the specified status codes.
+    #[inline(always)]
+    fn assert_code_in(self, codes: &[u16]) -> crate::Result<()> {
+        if codes.contains(&self.code()) {
+            Ok(())
+        } else {
+            Err(crate::Error::UnexpectedReply(self))
+        }
+    }
+
+    /// Returns Ok if the reply returned a 2xx or 3xx code.
+    #[inline(always)]
+    fn assert_any_positive(self) -> crate::Result<()> {
+        if (200..=399).contains(&self.code) {
+            Ok(())
+        } else {
+            Err(crate::Error::UnexpectedReply(self))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use smtp_proto::Response;
+
+    use super::AssertReply;
+
+    #[test]
+    fn assert_reply() {
+        let reply = |code| Response::new(code, 0, 0, 0, String::new());
+
+        assert!(reply(250).assert_code_in(&[250, 251]).is_ok());
+        assert!(reply(251).assert_code_in(&[250, 251]).is_ok());
+        assert!(reply(252).assert_code_in(&[250, 251]).is_err());
+        assert!(reply(250).assert_code_in(&[]).is_err());
+
+        assert!(reply(250).assert_any_positive().is_ok());
+        assert!(reply(354).assert_any_positive().is_ok());
+        assert!(reply(199).assert_any_positive().is_err());
+        assert!(reply(421).assert_any_positive().is_err());
+        assert!(reply(550).assert_any_positive().is_err());
+    }
 }
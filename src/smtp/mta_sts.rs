@@ -0,0 +1,490 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! MTA Strict Transport Security (RFC 8461).
+
+use std::{fmt::Display, time::Duration};
+
+/// Longest `max_age` accepted in a policy, about one year.
+const MAX_AGE_LIMIT: u64 = 31_557_600;
+
+/// An MTA-STS policy, as published by a domain at
+/// `https://mta-sts.<domain>/.well-known/mta-sts.txt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub mode: Mode,
+    pub mx: Vec<String>,
+    pub max_age: Duration,
+}
+
+/// How sending MTAs should handle policy failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Delivery to hosts that fail the policy must not take place.
+    Enforce,
+    /// Failures are reported but delivery proceeds.
+    Testing,
+    /// The domain does not have an active policy.
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The `_mta-sts` TXT record could not be resolved.
+    Dns(String),
+    /// The policy could not be retrieved over HTTPS.
+    Fetch(String),
+    /// The policy is malformed.
+    InvalidPolicy,
+    /// The MX host is not listed in the policy.
+    MxMismatch,
+    /// The policy requires a validated TLS connection.
+    TlsRequired,
+}
+
+impl Policy {
+    /// Parses a policy file.
+    pub fn parse(text: &str) -> crate::Result<Self> {
+        let mut version = None;
+        let mut mode = None;
+        let mut max_age = None;
+        let mut mx = Vec::new();
+
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "version" => version = Some(value),
+                "mode" => {
+                    mode = match value {
+                        "enforce" => Mode::Enforce,
+                        "testing" => Mode::Testing,
+                        "none" => Mode::None,
+                        _ => return Err(Error::InvalidPolicy.into()),
+                    }
+                    .into()
+                }
+                "max_age" => {
+                    max_age = value
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|&max_age| max_age <= MAX_AGE_LIMIT)
+                        .ok_or(Error::InvalidPolicy)?
+                        .into()
+                }
+                "mx" => mx.push(value.to_ascii_lowercase()),
+                _ => (),
+            }
+        }
+
+        match (version, mode, max_age) {
+            (Some("STSv1"), Some(mode), Some(max_age)) if mode == Mode::None || !mx.is_empty() => {
+                Ok(Policy {
+                    mode,
+                    mx,
+                    max_age: Duration::from_secs(max_age),
+                })
+            }
+            _ => Err(Error::InvalidPolicy.into()),
+        }
+    }
+
+    /// Returns `true` if the MX host is listed in the policy. Wildcard
+    /// patterns such as `*.example.org` match a single leftmost label.
+    pub fn matches(&self, mx_host: &str) -> bool {
+        let mx_host = mx_host.trim_end_matches('.').to_ascii_lowercase();
+        self.mx.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches('.');
+            if let Some(domain) = pattern.strip_prefix("*.") {
+                mx_host
+                    .split_once('.')
+                    .is_some_and(|(label, parent)| !label.is_empty() && parent == domain)
+            } else {
+                mx_host == pattern
+            }
+        })
+    }
+
+    /// Checks a connection to `mx_host` against the policy, `is_tls`
+    /// indicating whether it is made over TLS with a validated certificate.
+    pub fn verify(&self, mx_host: &str, is_tls: bool) -> Result<(), Error> {
+        if self.mode == Mode::None {
+            Ok(())
+        } else if !self.matches(mx_host) {
+            Err(Error::MxMismatch)
+        } else if !is_tls {
+            Err(Error::TlsRequired)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(err: Error) -> Self {
+        crate::Error::MtaSts(err)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Dns(err) => write!(f, "Failed to resolve MTA-STS record: {err}"),
+            Error::Fetch(err) => write!(f, "Failed to fetch MTA-STS policy: {err}"),
+            Error::InvalidPolicy => write!(f, "Invalid MTA-STS policy"),
+            Error::MxMismatch => write!(f, "MX host not listed in the MTA-STS policy"),
+            Error::TlsRequired => write!(f, "MTA-STS policy requires a validated TLS connection"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(feature = "mta-sts")]
+pub use cache::PolicyCache;
+
+#[cfg(feature = "mta-sts")]
+mod cache {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use mail_auth::{mta_sts::MtaSts, Resolver};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_rustls::TlsConnector;
+
+    use crate::smtp::tls::{server_name, shared_tls_config};
+
+    use super::{Error, Policy};
+
+    /// Largest policy file accepted, as recommended by RFC 8461.
+    const MAX_POLICY_SIZE: usize = 64 * 1024;
+
+    /// Discovers and caches the MTA-STS policies of recipient domains.
+    ///
+    /// Policies are kept for their `max_age` and are only fetched again when
+    /// the `id` published in the `_mta-sts` TXT record changes.
+    pub struct PolicyCache {
+        resolver: Resolver,
+        tls_connector: TlsConnector,
+        timeout: Duration,
+        policies: Mutex<HashMap<String, CachedPolicy>>,
+    }
+
+    struct CachedPolicy {
+        id: String,
+        policy: Arc<Policy>,
+        expires: Instant,
+    }
+
+    impl PolicyCache {
+        pub fn new(resolver: Resolver) -> Self {
+            PolicyCache {
+                resolver,
+                tls_connector: TlsConnector::from(shared_tls_config(false)),
+                timeout: Duration::from_secs(60),
+                policies: Mutex::new(HashMap::new()),
+            }
+        }
+
+        /// Sets the timeout for fetching a policy.
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// Returns the policy of `domain`, or `None` if the domain does not
+        /// publish one. When the TXT record is missing, an unexpired cached
+        /// policy is still returned.
+        pub async fn policy(&self, domain: &str) -> crate::Result<Option<Arc<Policy>>> {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            let id = match self
+                .resolver
+                .txt_lookup::<MtaSts>(format!("_mta-sts.{domain}."))
+                .await
+            {
+                Ok(record) => record.id.clone(),
+                Err(
+                    mail_auth::Error::DnsRecordNotFound(_) | mail_auth::Error::InvalidRecordType,
+                ) => {
+                    return Ok(self.cached(&domain, None));
+                }
+                Err(err) => return Err(Error::Dns(err.to_string()).into()),
+            };
+
+            if let Some(policy) = self.cached(&domain, Some(&id)) {
+                return Ok(Some(policy));
+            }
+
+            let policy = Arc::new(Policy::parse(
+                &tokio::time::timeout(self.timeout, self.fetch(&domain))
+                    .await
+                    .map_err(|_| Error::Fetch("Timeout".to_string()))??,
+            )?);
+            self.policies.lock().unwrap().insert(
+                domain,
+                CachedPolicy {
+                    id,
+                    policy: policy.clone(),
+                    expires: Instant::now() + policy.max_age,
+                },
+            );
+
+            Ok(Some(policy))
+        }
+
+        /// Returns the cached policy if it has not expired and, when
+        /// provided, its id matches.
+        fn cached(&self, domain: &str, id: Option<&str>) -> Option<Arc<Policy>> {
+            let mut policies = self.policies.lock().unwrap();
+            match policies.get(domain) {
+                Some(cached) if cached.expires > Instant::now() => match id {
+                    Some(id) if id != cached.id => None,
+                    _ => Some(cached.policy.clone()),
+                },
+                Some(_) => {
+                    policies.remove(domain);
+                    None
+                }
+                None => None,
+            }
+        }
+
+        /// Retrieves the policy file over HTTPS. Redirects are not followed.
+        async fn fetch(&self, domain: &str) -> crate::Result<String> {
+            let host = format!("mta-sts.{domain}");
+            let stream = TcpStream::connect((host.as_str(), 443)).await?;
+            let mut stream = self
+                .tls_connector
+                .connect(server_name(&host)?, stream)
+                .await?;
+            stream
+                .write_all(
+                    format!(
+                        "GET /.well-known/mta-sts.txt HTTP/1.0\r\nHost: {host}\r\nConnection: close\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            stream.flush().await?;
+
+            let mut response = Vec::with_capacity(1024);
+            (&mut stream)
+                .take(MAX_POLICY_SIZE as u64 + 1024)
+                .read_to_end(&mut response)
+                .await?;
+            parse_http_response(&response).map_err(Into::into)
+        }
+
+        #[cfg(test)]
+        pub(crate) fn insert(&self, domain: &str, id: &str, policy: Policy) {
+            self.policies.lock().unwrap().insert(
+                domain.to_string(),
+                CachedPolicy {
+                    id: id.to_string(),
+                    expires: Instant::now() + policy.max_age,
+                    policy: Arc::new(policy),
+                },
+            );
+        }
+    }
+
+    /// Extracts the body of a successful `text/plain` HTTP response.
+    pub(crate) fn parse_http_response(response: &[u8]) -> Result<String, Error> {
+        let response = std::str::from_utf8(response)
+            .map_err(|_| Error::Fetch("Invalid response".to_string()))?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| Error::Fetch("Truncated response".to_string()))?;
+        let mut lines = head.split("\r\n");
+
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .unwrap_or_default();
+        if status != "200" {
+            return Err(Error::Fetch(format!("Unexpected HTTP status {status:?}")));
+        }
+        let is_text = lines.any(|line| {
+            line.split_once(':').is_some_and(|(name, value)| {
+                name.eq_ignore_ascii_case("content-type")
+                    && value.trim().to_ascii_lowercase().starts_with("text/plain")
+            })
+        });
+        if !is_text {
+            return Err(Error::Fetch("Policy is not text/plain".to_string()));
+        }
+        if body.len() > MAX_POLICY_SIZE {
+            return Err(Error::Fetch("Policy too large".to_string()));
+        }
+
+        Ok(body.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Error, Mode, Policy};
+
+    #[test]
+    fn parse_policy() {
+        let policy = Policy::parse(concat!(
+            "version: STSv1\r\n",
+            "mode: enforce\r\n",
+            "mx: mail.example.com\r\n",
+            "mx: *.Example.net\r\n",
+            "mx: backupmx.example.com\r\n",
+            "max_age: 604800\r\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            policy,
+            Policy {
+                mode: Mode::Enforce,
+                mx: vec![
+                    "mail.example.com".to_string(),
+                    "*.example.net".to_string(),
+                    "backupmx.example.com".to_string()
+                ],
+                max_age: Duration::from_secs(604800),
+            }
+        );
+
+        // LF line endings and unknown keys are accepted
+        assert_eq!(
+            Policy::parse("version: STSv1\nmode: none\nmax_age: 86400\nextension: value\n")
+                .unwrap()
+                .mode,
+            Mode::None
+        );
+
+        for invalid in [
+            "mode: enforce\nmx: mail.example.com\nmax_age: 86400\n",
+            "version: STSv2\nmode: enforce\nmx: mail.example.com\nmax_age: 86400\n",
+            "version: STSv1\nmode: strict\nmx: mail.example.com\nmax_age: 86400\n",
+            "version: STSv1\nmode: enforce\nmax_age: 86400\n",
+            "version: STSv1\nmode: enforce\nmx: mail.example.com\n",
+            "version: STSv1\nmode: enforce\nmx: mail.example.com\nmax_age: 99999999999\n",
+        ] {
+            assert!(
+                matches!(
+                    Policy::parse(invalid),
+                    Err(crate::Error::MtaSts(Error::InvalidPolicy))
+                ),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn match_mx() {
+        let policy = Policy {
+            mode: Mode::Enforce,
+            mx: vec!["mail.example.com".to_string(), "*.example.net".to_string()],
+            max_age: Duration::from_secs(86400),
+        };
+        for (host, expected) in [
+            ("mail.example.com", true),
+            ("MAIL.example.com.", true),
+            ("mx.example.com", false),
+            ("mx1.example.net", true),
+            ("example.net", false),
+            ("a.b.example.net", false),
+            (".example.net", false),
+        ] {
+            assert_eq!(policy.matches(host), expected, "{host}");
+        }
+
+        assert_eq!(policy.verify("mail.example.com", true), Ok(()));
+        assert_eq!(
+            policy.verify("mail.example.org", true),
+            Err(Error::MxMismatch)
+        );
+        assert_eq!(
+            policy.verify("mail.example.com", false),
+            Err(Error::TlsRequired)
+        );
+        let policy = Policy {
+            mode: Mode::None,
+            ..policy
+        };
+        assert_eq!(policy.verify("mail.example.org", false), Ok(()));
+    }
+
+    #[cfg(feature = "mta-sts")]
+    #[test]
+    fn http_response() {
+        use super::cache::parse_http_response;
+
+        assert_eq!(
+            parse_http_response(
+                b"HTTP/1.0 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nversion: STSv1\r\n"
+            ),
+            Ok("version: STSv1\r\n".to_string())
+        );
+        assert!(matches!(
+            parse_http_response(
+                b"HTTP/1.0 301 Moved\r\nLocation: /\r\nContent-Type: text/plain\r\n\r\n"
+            ),
+            Err(Error::Fetch(_))
+        ));
+        assert!(matches!(
+            parse_http_response(b"HTTP/1.0 200 OK\r\nContent-Type: text/html\r\n\r\n<html>"),
+            Err(Error::Fetch(_))
+        ));
+    }
+
+    #[cfg(feature = "mta-sts")]
+    #[tokio::test]
+    async fn cached_policy() {
+        use mail_auth::{mta_sts::MtaSts, Resolver};
+
+        let resolver = Resolver::new_cloudflare().unwrap();
+        resolver.txt_add(
+            "_mta-sts.example.com.",
+            MtaSts {
+                id: "20240101".to_string(),
+            },
+            std::time::Instant::now() + Duration::from_secs(60),
+        );
+        let cache = super::PolicyCache::new(resolver);
+        let policy = Policy {
+            mode: Mode::Testing,
+            mx: vec!["mail.example.com".to_string()],
+            max_age: Duration::from_secs(86400),
+        };
+        cache.insert("example.com", "20240101", policy.clone());
+        cache.insert("example.org", "20240101", policy.clone());
+
+        // The TXT id matches the cached policy, no fetch is needed
+        assert_eq!(
+            cache.policy("Example.com.").await.unwrap().as_deref(),
+            Some(&policy)
+        );
+
+        // Without a TXT record the cached policy is still used
+        assert_eq!(
+            cache.policy("example.org").await.unwrap().as_deref(),
+            Some(&policy)
+        );
+
+        // Domains without a policy
+        assert_eq!(cache.policy("example.net").await.unwrap(), None);
+    }
+}
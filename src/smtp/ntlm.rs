@@ -0,0 +1,422 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+//! NTLMv2 message exchange as described in MS-NLMP.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::auth::Error;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x00000001;
+const REQUEST_TARGET: u32 = 0x00000004;
+const NEGOTIATE_NTLM: u32 = 0x00000200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x00008000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x00080000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x00800000;
+const NEGOTIATE_128: u32 = 0x20000000;
+const NEGOTIATE_56: u32 = 0x80000000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+const AV_EOL: u16 = 0;
+const AV_TIMESTAMP: u16 = 7;
+
+/// Seconds between 1601-01-01 (Windows FILETIME epoch) and 1970-01-01.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// Builds the Type 1 (NEGOTIATE) message.
+pub(crate) fn negotiate_message() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+    // Empty domain and workstation fields
+    message.extend_from_slice(&[0u8; 16]);
+    message
+}
+
+/// Builds the Type 3 (AUTHENTICATE) message in response to the server's
+/// Type 2 (CHALLENGE) message.
+pub(crate) fn authenticate_message(
+    username: &str,
+    secret: &str,
+    domain: &str,
+    workstation: &str,
+    challenge: &[u8],
+) -> Result<Vec<u8>, Error> {
+    use rand::RngCore;
+
+    let mut client_challenge = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut client_challenge);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| (d.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000);
+
+    build_authenticate_message(
+        username,
+        secret,
+        domain,
+        workstation,
+        challenge,
+        client_challenge,
+        timestamp,
+    )
+}
+
+fn build_authenticate_message(
+    username: &str,
+    secret: &str,
+    domain: &str,
+    workstation: &str,
+    challenge: &[u8],
+    client_challenge: [u8; 8],
+    timestamp: u64,
+) -> Result<Vec<u8>, Error> {
+    let challenge = Challenge::parse(challenge).ok_or(Error::InvalidChallenge)?;
+
+    // Prefer the server's clock, when provided.
+    let timestamp = challenge.timestamp().unwrap_or(timestamp);
+
+    let response_key = nt_owf_v2(username, secret, domain);
+
+    let mut temp = Vec::with_capacity(32 + challenge.target_info.len());
+    temp.extend_from_slice(&[1, 1, 0, 0, 0, 0, 0, 0]);
+    temp.extend_from_slice(&timestamp.to_le_bytes());
+    temp.extend_from_slice(&client_challenge);
+    temp.extend_from_slice(&[0u8; 4]);
+    temp.extend_from_slice(challenge.target_info);
+    temp.extend_from_slice(&[0u8; 4]);
+
+    let mut nt_response = hmac_md5(&response_key, &[&challenge.server_challenge, &temp]).to_vec();
+    nt_response.extend_from_slice(&temp);
+
+    let mut lm_response = hmac_md5(
+        &response_key,
+        &[&challenge.server_challenge, &client_challenge],
+    )
+    .to_vec();
+    lm_response.extend_from_slice(&client_challenge);
+
+    let domain = utf16le(domain);
+    let username = utf16le(username);
+    let workstation = utf16le(workstation);
+
+    let mut message = Vec::with_capacity(
+        64 + lm_response.len()
+            + nt_response.len()
+            + domain.len()
+            + username.len()
+            + workstation.len(),
+    );
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&3u32.to_le_bytes());
+
+    let mut offset = 64;
+    for field in [
+        &lm_response,
+        &nt_response,
+        &domain,
+        &username,
+        &workstation,
+        &Vec::new(),
+    ] {
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    message.extend_from_slice(&(challenge.flags & NEGOTIATE_FLAGS).to_le_bytes());
+
+    for field in [lm_response, nt_response, domain, username, workstation] {
+        message.extend_from_slice(&field);
+    }
+
+    Ok(message)
+}
+
+struct Challenge<'x> {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: &'x [u8],
+}
+
+impl<'x> Challenge<'x> {
+    fn parse(message: &'x [u8]) -> Option<Self> {
+        if message.get(0..8)? != SIGNATURE || read_u32(message, 8)? != 2 {
+            return None;
+        }
+
+        let flags = read_u32(message, 20)?;
+        let server_challenge = message.get(24..32)?.try_into().ok()?;
+        let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 {
+            let len = read_u16(message, 40)? as usize;
+            let offset = read_u32(message, 44)? as usize;
+            message.get(offset..offset.checked_add(len)?)?
+        } else {
+            &[]
+        };
+
+        Some(Challenge {
+            flags,
+            server_challenge,
+            target_info,
+        })
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        let mut pairs = self.target_info;
+        while pairs.len() >= 4 {
+            let id = read_u16(pairs, 0)?;
+            let len = read_u16(pairs, 2)? as usize;
+            let value = pairs.get(4..4 + len)?;
+            match id {
+                AV_EOL => break,
+                AV_TIMESTAMP => return Some(u64::from_le_bytes(value.try_into().ok()?)),
+                _ => pairs = &pairs[4 + len..],
+            }
+        }
+        None
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn utf16le(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn nt_owf_v2(username: &str, secret: &str, domain: &str) -> [u8; 16] {
+    let nt_hash = md4(&utf16le(secret));
+    let mut identity = utf16le(&username.to_uppercase());
+    identity.extend_from_slice(&utf16le(domain));
+    hmac_md5(&nt_hash, &[&identity])
+}
+
+fn hmac_md5(key: &[u8], data: &[&[u8]]) -> [u8; 16] {
+    let mut ipad = [0x36u8; 64];
+    let mut opad = [0x5cu8; 64];
+    for (pos, byte) in key.iter().enumerate() {
+        ipad[pos] ^= byte;
+        opad[pos] ^= byte;
+    }
+
+    let mut inner = md5::Context::new();
+    inner.consume(ipad);
+    for data in data {
+        inner.consume(data);
+    }
+
+    let mut outer = md5::Context::new();
+    outer.consume(opad);
+    outer.consume(inner.compute().0);
+    outer.compute().0
+}
+
+/// MD4 message digest (RFC 1320), only used to derive the NT hash.
+fn md4(data: &[u8]) -> [u8; 16] {
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut x = [0u32; 16];
+        for (word, bytes) in x.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let [mut a, mut b, mut c, mut d] = state;
+
+        let f = |x: u32, y: u32, z: u32| (x & y) | (!x & z);
+        let g = |x: u32, y: u32, z: u32| (x & y) | (x & z) | (y & z);
+        let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+
+        for &i in &[0, 4, 8, 12] {
+            a = a.wrapping_add(f(b, c, d)).wrapping_add(x[i]).rotate_left(3);
+            d = d
+                .wrapping_add(f(a, b, c))
+                .wrapping_add(x[i + 1])
+                .rotate_left(7);
+            c = c
+                .wrapping_add(f(d, a, b))
+                .wrapping_add(x[i + 2])
+                .rotate_left(11);
+            b = b
+                .wrapping_add(f(c, d, a))
+                .wrapping_add(x[i + 3])
+                .rotate_left(19);
+        }
+        for &i in &[0, 1, 2, 3] {
+            let k = 0x5a827999u32;
+            a = a
+                .wrapping_add(g(b, c, d))
+                .wrapping_add(x[i])
+                .wrapping_add(k)
+                .rotate_left(3);
+            d = d
+                .wrapping_add(g(a, b, c))
+                .wrapping_add(x[i + 4])
+                .wrapping_add(k)
+                .rotate_left(5);
+            c = c
+                .wrapping_add(g(d, a, b))
+                .wrapping_add(x[i + 8])
+                .wrapping_add(k)
+                .rotate_left(9);
+            b = b
+                .wrapping_add(g(c, d, a))
+                .wrapping_add(x[i + 12])
+                .wrapping_add(k)
+                .rotate_left(13);
+        }
+        for &i in &[0, 2, 1, 3] {
+            let k = 0x6ed9eba1u32;
+            a = a
+                .wrapping_add(h(b, c, d))
+                .wrapping_add(x[i])
+                .wrapping_add(k)
+                .rotate_left(3);
+            d = d
+                .wrapping_add(h(a, b, c))
+                .wrapping_add(x[i + 8])
+                .wrapping_add(k)
+                .rotate_left(9);
+            c = c
+                .wrapping_add(h(d, a, b))
+                .wrapping_add(x[i + 4])
+                .wrapping_add(k)
+                .rotate_left(11);
+            b = b
+                .wrapping_add(h(c, d, a))
+                .wrapping_add(x[i + 12])
+                .wrapping_add(k)
+                .rotate_left(15);
+        }
+
+        for (state, value) in state.iter_mut().zip([a, b, c, d]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_authenticate_message, md4, negotiate_message, nt_owf_v2, SIGNATURE};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn ntlm_v2() {
+        // RFC 1320 test suite
+        assert_eq!(hex(&md4(b"")), "31d6cfe0d16ae931b73c59d7e0c089c0");
+        assert_eq!(hex(&md4(b"abc")), "a448017aaf21d8525fc10ae87aa6729d");
+        assert_eq!(
+            hex(&md4(
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"
+            )),
+            "e33b4ddc9c38f2199c3e7b164fcc0536"
+        );
+
+        // MS-NLMP 4.2.4 NTLMv2 authentication
+        assert_eq!(
+            hex(&nt_owf_v2("User", "Password", "Domain")),
+            "0c868a403bfd7a93a3001ef22ef02e3f"
+        );
+
+        let target_info = [
+            0x02, 0x00, 0x0c, 0x00, b'D', 0, b'o', 0, b'm', 0, b'a', 0, b'i', 0, b'n', 0, 0x01,
+            0x00, 0x0c, 0x00, b'S', 0, b'e', 0, b'r', 0, b'v', 0, b'e', 0, b'r', 0, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        let mut challenge = Vec::new();
+        challenge.extend_from_slice(SIGNATURE);
+        challenge.extend_from_slice(&2u32.to_le_bytes());
+        challenge.extend_from_slice(&[12, 0, 12, 0, 56, 0, 0, 0]);
+        challenge.extend_from_slice(&0xe28a8233u32.to_le_bytes());
+        challenge.extend_from_slice(&[0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        challenge.extend_from_slice(&[0u8; 8]);
+        challenge.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        challenge.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        challenge.extend_from_slice(&68u32.to_le_bytes());
+        challenge.extend_from_slice(&[0u8; 8]);
+        challenge.extend_from_slice(&[b'S', 0, b'e', 0, b'r', 0, b'v', 0, b'e', 0, b'r', 0]);
+        challenge.extend_from_slice(&target_info);
+
+        let message = build_authenticate_message(
+            "User", "Password", "Domain", "COMPUTER", &challenge, [0xaa; 8], 0,
+        )
+        .unwrap();
+
+        let field = |offset: usize| {
+            let len = u16::from_le_bytes([message[offset], message[offset + 1]]) as usize;
+            let start = u32::from_le_bytes(message[offset + 4..offset + 8].try_into().unwrap());
+            &message[start as usize..start as usize + len]
+        };
+        assert_eq!(
+            hex(field(12)),
+            "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(hex(&field(20)[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+        assert_eq!(field(28), b"D\0o\0m\0a\0i\0n\0");
+        assert_eq!(field(36), b"U\0s\0e\0r\0");
+
+        // Malformed challenges are rejected
+        assert!(build_authenticate_message(
+            "User",
+            "Password",
+            "",
+            "",
+            &challenge[..30],
+            [0; 8],
+            0
+        )
+        .is_err());
+        assert!(build_authenticate_message(
+            "User",
+            "Password",
+            "",
+            "",
+            &negotiate_message(),
+            [0; 8],
+            0
+        )
+        .is_err());
+    }
+}
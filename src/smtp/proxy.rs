@@ -0,0 +1,157 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::net::{IpAddr, SocketAddr};
+
+/// PROXY protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyVersion {
+    /// Human-readable header.
+    V1,
+    /// Binary header.
+    V2,
+}
+
+/// PROXY protocol header sent at the start of a connection, used by load
+/// balancers such as HAProxy or Envoy to convey the original client address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub version: ProxyVersion,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub after_tls: bool,
+}
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+impl ProxyHeader {
+    /// Creates a PROXY protocol v1 header.
+    pub fn v1(source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader {
+            version: ProxyVersion::V1,
+            source,
+            destination,
+            after_tls: false,
+        }
+    }
+
+    /// Creates a PROXY protocol v2 header.
+    pub fn v2(source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader {
+            version: ProxyVersion::V2,
+            source,
+            destination,
+            after_tls: false,
+        }
+    }
+
+    /// Send the header inside the TLS session rather than before the TLS
+    /// handshake. Only applies to implicit TLS connections, as the header is
+    /// always the first data sent on clear text connections.
+    pub fn after_tls(mut self, after_tls: bool) -> Self {
+        self.after_tls = after_tls;
+        self
+    }
+
+    /// Encodes the header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.version {
+            ProxyVersion::V1 => match (self.source.ip(), self.destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => format!(
+                    "PROXY TCP4 {} {} {} {}\r\n",
+                    src,
+                    dst,
+                    self.source.port(),
+                    self.destination.port()
+                ),
+                (IpAddr::V6(src), IpAddr::V6(dst)) => format!(
+                    "PROXY TCP6 {} {} {} {}\r\n",
+                    src,
+                    dst,
+                    self.source.port(),
+                    self.destination.port()
+                ),
+                _ => "PROXY UNKNOWN\r\n".to_string(),
+            }
+            .into_bytes(),
+            ProxyVersion::V2 => {
+                let mut bytes = Vec::with_capacity(16 + 36);
+                bytes.extend_from_slice(V2_SIGNATURE);
+                // Version 2, PROXY command
+                bytes.push(0x21);
+
+                match (self.source.ip(), self.destination.ip()) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                        // AF_INET, STREAM
+                        bytes.push(0x11);
+                        bytes.extend_from_slice(&12u16.to_be_bytes());
+                        bytes.extend_from_slice(&src.octets());
+                        bytes.extend_from_slice(&dst.octets());
+                    }
+                    (src, dst) => {
+                        // AF_INET6, STREAM
+                        let to_v6 = |ip: IpAddr| match ip {
+                            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                            IpAddr::V6(ip) => ip,
+                        };
+                        bytes.push(0x21);
+                        bytes.extend_from_slice(&36u16.to_be_bytes());
+                        bytes.extend_from_slice(&to_v6(src).octets());
+                        bytes.extend_from_slice(&to_v6(dst).octets());
+                    }
+                }
+                bytes.extend_from_slice(&self.source.port().to_be_bytes());
+                bytes.extend_from_slice(&self.destination.port().to_be_bytes());
+                bytes
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProxyHeader;
+
+    #[test]
+    fn proxy_header_encoding() {
+        let src = "192.0.2.1:4321".parse().unwrap();
+        let dst = "198.51.100.7:25".parse().unwrap();
+        assert_eq!(
+            ProxyHeader::v1(src, dst).to_bytes(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 4321 25\r\n"
+        );
+        assert_eq!(
+            ProxyHeader::v1(
+                "[2001:db8::1]:4321".parse().unwrap(),
+                "[2001:db8::2]:25".parse().unwrap()
+            )
+            .to_bytes(),
+            b"PROXY TCP6 2001:db8::1 2001:db8::2 4321 25\r\n"
+        );
+        assert_eq!(
+            ProxyHeader::v1(src, "[2001:db8::2]:25".parse().unwrap()).to_bytes(),
+            b"PROXY UNKNOWN\r\n"
+        );
+        assert_eq!(
+            ProxyHeader::v2(src, dst).to_bytes(),
+            [
+                &b"\r\n\r\n\0\r\nQUIT\n"[..],
+                &[0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 7, 0x10, 0xe1, 0, 25]
+            ]
+            .concat()
+        );
+        assert_eq!(
+            ProxyHeader::v2("[2001:db8::1]:4321".parse().unwrap(), dst)
+                .to_bytes()
+                .len(),
+            16 + 36
+        );
+    }
+}
@@ -0,0 +1,137 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::message::format_rfc5322_date;
+
+/// `Received` trace header (RFC 5321, section 4.4) documenting a hop.
+pub struct Received;
+
+impl Received {
+    /// Returns the value of a `Received` header for a message received from
+    /// the client that introduced itself as `from_helo` at `from_ip`, by
+    /// `by_host`, using `with_protocol` (such as `ESMTP`, `ESMTPS` or
+    /// `ESMTPSA`), optionally with the queue `id` assigned to it.
+    ///
+    /// The value is folded over several lines and is meant to be prepended
+    /// with `send_with_headers`, e.g. `&[("Received", &value)]`. Characters
+    /// in the HELO name that would break the header are replaced with `_`.
+    pub fn build(
+        from_helo: &str,
+        from_ip: Option<IpAddr>,
+        by_host: &str,
+        with_protocol: &str,
+        id: Option<&str>,
+        date: SystemTime,
+    ) -> String {
+        let mut value = String::with_capacity(128);
+        value.push_str("from ");
+        let literal = from_ip.map(|ip| match ip {
+            IpAddr::V4(ip) => format!("[{ip}]"),
+            IpAddr::V6(ip) => format!("[IPv6:{ip}]"),
+        });
+        match (sanitize(from_helo), &literal) {
+            (helo, Some(literal)) if !helo.is_empty() => {
+                value.push_str(&helo);
+                value.push_str(" (");
+                value.push_str(literal);
+                value.push(')');
+            }
+            (_, Some(literal)) => value.push_str(literal),
+            (helo, None) if !helo.is_empty() => value.push_str(&helo),
+            _ => value.push_str("unknown"),
+        }
+
+        value.push_str("\r\n\tby ");
+        value.push_str(&sanitize(by_host));
+        value.push_str(" with ");
+        value.push_str(&sanitize(with_protocol));
+        if let Some(id) = id.map(sanitize).filter(|id| !id.is_empty()) {
+            value.push_str(" id ");
+            value.push_str(&id);
+        }
+        value.push_str(";\r\n\t");
+        value.push_str(&format_rfc5322_date(
+            date.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        ));
+        value
+    }
+}
+
+/// Replaces the characters that are not allowed in a `Received` clause.
+fn sanitize(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_graphic() && !matches!(ch, '(' | ')' | ';') {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::Received;
+
+    #[test]
+    fn received_header() {
+        let date = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (helo, ip, id, expected) in [
+            (
+                "client.example.org",
+                Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+                Some("4B2A1C"),
+                concat!(
+                    "from client.example.org ([192.0.2.1])\r\n",
+                    "\tby mx.example.com with ESMTPS id 4B2A1C;\r\n",
+                    "\tTue, 14 Nov 2023 22:13:20 +0000"
+                ),
+            ),
+            (
+                "",
+                Some(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+                None,
+                concat!(
+                    "from [IPv6:2001:db8::1]\r\n",
+                    "\tby mx.example.com with ESMTPS;\r\n",
+                    "\tTue, 14 Nov 2023 22:13:20 +0000"
+                ),
+            ),
+            (
+                "evil\r\nBcc: x (y);",
+                None,
+                Some(""),
+                concat!(
+                    "from evil__Bcc:_x__y__\r\n",
+                    "\tby mx.example.com with ESMTPS;\r\n",
+                    "\tTue, 14 Nov 2023 22:13:20 +0000"
+                ),
+            ),
+        ] {
+            assert_eq!(
+                Received::build(helo, ip, "mx.example.com", "ESMTPS", id, date),
+                expected
+            );
+        }
+    }
+}
@@ -8,19 +8,23 @@
  * except according to those terms.
  */
 
-use std::{convert::TryFrom, io, sync::Arc};
+use std::{
+    convert::TryFrom,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 use rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
-    ClientConfig, ClientConnection, RootCertStore, SignatureScheme,
+    ClientConfig, ClientConnection, InvalidMessage, RootCertStore, SignatureScheme,
 };
 use rustls_pki_types::{ServerName, TrustAnchor};
 use tokio::net::TcpStream;
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
-use crate::{Error, SmtpClient};
-
-use super::AssertReply;
+use crate::{Error, SmtpClient, TimeoutPhase, TlsKind};
 
 impl SmtpClient<TcpStream> {
     /// Upgrade the connection to TLS.
@@ -30,32 +34,58 @@ impl SmtpClient<TcpStream> {
         hostname: &str,
     ) -> crate::Result<SmtpClient<TlsStream<TcpStream>>> {
         // Send STARTTLS command
-        self.cmd(b"STARTTLS\r\n")
-            .await?
-            .assert_positive_completion()?;
+        let response = self.cmd(b"STARTTLS\r\n").await?;
+        if !response.is_positive_completion() {
+            return Err(crate::Error::StartTlsRejected(response));
+        }
 
-        self.into_tls(tls_connector, hostname).await
+        let timeout = self.timeout;
+        self.into_tls_with_timeout(tls_connector, hostname, timeout, TlsKind::StartTls)
+            .await
     }
 
+    /// Performs the TLS handshake as done with implicit TLS. Connections
+    /// upgraded with `start_tls` are reported as `TlsKind::StartTls`.
     pub async fn into_tls(
         self,
         tls_connector: &TlsConnector,
         hostname: &str,
     ) -> crate::Result<SmtpClient<TlsStream<TcpStream>>> {
-        tokio::time::timeout(self.timeout, async {
+        let timeout = self.timeout;
+        self.into_tls_with_timeout(tls_connector, hostname, timeout, TlsKind::Implicit)
+            .await
+    }
+
+    /// Performs the TLS handshake, giving up after `handshake_timeout`.
+    pub(crate) async fn into_tls_with_timeout(
+        self,
+        tls_connector: &TlsConnector,
+        hostname: &str,
+        handshake_timeout: Duration,
+        kind: TlsKind,
+    ) -> crate::Result<SmtpClient<TlsStream<TcpStream>>> {
+        tokio::time::timeout(handshake_timeout, async {
             Ok(SmtpClient {
                 stream: tls_connector
-                    .connect(
-                        ServerName::try_from(hostname)
-                            .map_err(|_| crate::Error::InvalidTLSName)?
-                            .to_owned(),
-                        self.stream,
-                    )
+                    .connect(server_name(hostname)?, self.stream)
                     .await
                     .map_err(|err| {
                         let kind = err.kind();
                         if let Some(inner) = err.into_inner() {
                             match inner.downcast::<rustls::Error>() {
+                                // A plain text reply such as "220 ..." is not a
+                                // valid TLS record.
+                                Ok(error)
+                                    if matches!(
+                                        *error,
+                                        rustls::Error::InvalidMessage(
+                                            InvalidMessage::InvalidContentType
+                                                | InvalidMessage::UnknownProtocolVersion
+                                        )
+                                    ) =>
+                                {
+                                    Error::NotTls
+                                }
                                 Ok(error) => Error::Tls(error),
                                 Err(error) => Error::Io(io::Error::new(kind, error)),
                             }
@@ -64,10 +94,32 @@ impl SmtpClient<TcpStream> {
                         }
                     })?,
                 timeout: self.timeout,
+                normalize_line_endings: self.normalize_line_endings,
+                auth_mechanism: self.auth_mechanism,
+                capabilities: None,
+                on_wire: self.on_wire,
+                in_flight: false,
+                poisoned: false,
+                extensions: Vec::new(),
+                greeting_delay: self.greeting_delay,
+                partial_reply: Vec::new(),
+                tls_kind: kind,
+                reset_on_error: self.reset_on_error,
+                login_initial_response: self.login_initial_response,
+                max_auth_rounds: self.max_auth_rounds,
+                add_missing_headers: self.add_missing_headers,
+                max_rcpt_per_message: self.max_rcpt_per_message,
+                line_length_policy: self.line_length_policy,
+                probe_before_send: self.probe_before_send,
+                require_tls_for_auth: self.require_tls_for_auth,
+                starttls_policy: self.starttls_policy,
             })
         })
         .await
-        .map_err(|_| crate::Error::Timeout)?
+        .map_err(|_| crate::Error::Timeout {
+            phase: TimeoutPhase::TlsHandshake,
+            received: Vec::new(),
+        })?
     }
 }
 
@@ -75,29 +127,85 @@ impl SmtpClient<TlsStream<TcpStream>> {
     pub fn tls_connection(&self) -> &ClientConnection {
         self.stream.get_ref().1
     }
+
+    /// Returns the address of the remote server.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().0.peer_addr()
+    }
+
+    /// Returns the local address of the connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().0.local_addr()
+    }
+}
+
+/// Converts a hostname into a TLS server name. IP addresses, including
+/// bracketed IPv6 literals, are validated against the IP SANs of the server
+/// certificate.
+pub(crate) fn server_name(hostname: &str) -> crate::Result<ServerName<'static>> {
+    if let Some(ip) = hostname
+        .strip_prefix('[')
+        .and_then(|hostname| hostname.strip_suffix(']'))
+    {
+        ip.parse::<IpAddr>()
+            .map(|ip| ServerName::IpAddress(ip.into()))
+            .map_err(|_| crate::Error::InvalidTLSName)
+    } else {
+        ServerName::try_from(hostname)
+            .map(|name| name.to_owned())
+            .map_err(|_| crate::Error::InvalidTLSName)
+    }
 }
 
 pub fn build_tls_connector(allow_invalid_certs: bool) -> TlsConnector {
-    let config = if !allow_invalid_certs {
-        let mut root_cert_store = RootCertStore::empty();
+    TlsConnector::from(Arc::new(build_tls_config(allow_invalid_certs)))
+}
+
+/// Returns the default TLS configuration, which is built once and shared,
+/// along with its in-memory TLS session cache, by all the builders that do
+/// not override it.
+pub(crate) fn shared_tls_config(allow_invalid_certs: bool) -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static INSECURE_CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
 
-        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| TrustAnchor {
-            subject: ta.subject.clone(),
-            subject_public_key_info: ta.subject_public_key_info.clone(),
-            name_constraints: ta.name_constraints.clone(),
-        }));
+    let config = if !allow_invalid_certs {
+        &CONFIG
+    } else {
+        &INSECURE_CONFIG
+    };
+    config
+        .get_or_init(|| Arc::new(build_tls_config(allow_invalid_certs)))
+        .clone()
+}
 
+pub(crate) fn build_tls_config(allow_invalid_certs: bool) -> ClientConfig {
+    if !allow_invalid_certs {
         ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
+            .with_root_certificates(root_cert_store())
             .with_no_client_auth()
     } else {
         ClientConfig::builder()
             .dangerous()
             .with_custom_certificate_verifier(Arc::new(DummyVerifier {}))
             .with_no_client_auth()
-    };
+    }
+}
+
+/// Returns the webpki root certificates, which are only loaded once.
+fn root_cert_store() -> Arc<RootCertStore> {
+    static ROOT_CERT_STORE: OnceLock<Arc<RootCertStore>> = OnceLock::new();
 
-    TlsConnector::from(Arc::new(config))
+    ROOT_CERT_STORE
+        .get_or_init(|| {
+            let mut root_cert_store = RootCertStore::empty();
+            root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| TrustAnchor {
+                subject: ta.subject.clone(),
+                subject_public_key_info: ta.subject_public_key_info.clone(),
+                name_constraints: ta.name_constraints.clone(),
+            }));
+            Arc::new(root_cert_store)
+        })
+        .clone()
 }
 
 #[doc(hidden)]
@@ -152,3 +260,413 @@ impl ServerCertVerifier for DummyVerifier {
         ]
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    use base64::{engine, Engine};
+    use rustls::{
+        client::ClientSessionMemoryCache,
+        server::{ServerSessionMemoryCache, StoresServerSessions},
+        ClientConfig, RootCertStore, ServerConfig,
+    };
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use crate::{SmtpClientBuilder, TlsKind};
+
+    use super::{root_cert_store, server_name, shared_tls_config};
+
+    // Test CA and a certificate issued to the IP addresses 127.0.0.1 and ::1.
+    pub(crate) const CA_CERT: &[&str] = &[
+        "MIIBoDCCAUWgAwIBAgIUSlyZyGhMe6kTYHChKFCHK8ferGQwCgYIKoZIzj0EAwIwHDEaMBgGA1UE",
+        "AwwRbWFpbC1zZW5kIHRlc3QgQ0EwIBcNMjYxMDE2MTgyNjMyWhgPMjEyNjA5MjIxODI2MzJaMBwx",
+        "GjAYBgNVBAMMEW1haWwtc2VuZCB0ZXN0IENBMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEx7jt",
+        "owkJ1EPkx9enLI+QaSZEePjHyu7EwVtfI1koBwb42/ZOWB+VtYNIz3f4Mg8asixbzZrszI/tUj5i",
+        "oypI4qNjMGEwHQYDVR0OBBYEFBnsrs77QwgGr27ZhHBof5czUpO5MB8GA1UdIwQYMBaAFBnsrs77",
+        "QwgGr27ZhHBof5czUpO5MA8GA1UdEwEB/wQFMAMBAf8wDgYDVR0PAQH/BAQDAgIEMAoGCCqGSM49",
+        "BAMCA0kAMEYCIQDmZUx33TB6k2g0kL++mp6SdJmAJj9NtwX0ZWrQYCRzbgIhALXXxFZwRmrvLgiS",
+        "xT34iCVzArnHIQocZi5tPS3m4UHR",
+    ];
+    pub(crate) const SERVER_CERT: &[&str] = &[
+        "MIIBzjCCAXSgAwIBAgIUQH/BSvp0gEVaG/3keH1hODq/hpowCgYIKoZIzj0EAwIwHDEaMBgGA1UE",
+        "AwwRbWFpbC1zZW5kIHRlc3QgQ0EwIBcNMjYxMDE2MTgyNjMyWhgPMjEyNjA5MjIxODI2MzJaMBQx",
+        "EjAQBgNVBAMMCTEyNy4wLjAuMTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABHAVjZ1oE4Ql/FYo",
+        "2jG20eTIQpcfrlKtl++LDsXavOIELc4YstGDSDJcdcL/bZBVLzoSHdn7vmh+Njiuk3bXGU6jgZkw",
+        "gZYwDAYDVR0TAQH/BAIwADAOBgNVHQ8BAf8EBAMCB4AwEwYDVR0lBAwwCgYIKwYBBQUHAwEwIQYD",
+        "VR0RBBowGIcEfwAAAYcQAAAAAAAAAAAAAAAAAAAAATAdBgNVHQ4EFgQUSP+TF4rp6wSZxQxMPaNT",
+        "zJttsNgwHwYDVR0jBBgwFoAUGeyuzvtDCAavbtmEcGh/lzNSk7kwCgYIKoZIzj0EAwIDSAAwRQIg",
+        "PQBsylWk9aaM3Kre9LjLnxblgJbQ4xhe84HVMnwdb5MCIQCp1D5BxsY5+fvYmuKT1dFE4hCWpogC",
+        "rDLSJ5VIyREQCQ==",
+    ];
+    pub(crate) const SERVER_KEY: &[&str] = &[
+        "MIGHAgEAMBMGByqGSM49AgEGCCqGSM49AwEHBG0wawIBAQQgPs9f4lXeHOtVHJFt1i9DxO9vhLj/",
+        "gBuYmXRZsNPQAGqhRANCAARwFY2daBOEJfxWKNoxttHkyEKXH65SrZfviw7F2rziBC3OGLLRg0gy",
+        "XHXC/22QVS86Eh3Z+75ofjY4rpN21xlO",
+    ];
+
+    pub(crate) fn decode(value: &[&str]) -> Vec<u8> {
+        engine::general_purpose::STANDARD
+            .decode(value.concat())
+            .unwrap()
+    }
+
+    fn test_configs() -> (TlsAcceptor, Arc<ClientConfig>) {
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(decode(SERVER_CERT))],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(decode(SERVER_KEY))),
+            )
+            .unwrap();
+        let mut root_store = RootCertStore::empty();
+        root_store
+            .add(CertificateDer::from(decode(CA_CERT)))
+            .unwrap();
+        (
+            TlsAcceptor::from(Arc::new(server_config)),
+            Arc::new(
+                ClientConfig::builder()
+                    .with_root_certificates(root_store)
+                    .with_no_client_auth(),
+            ),
+        )
+    }
+
+    #[test]
+    fn ip_server_names() {
+        for (hostname, expected) in [("127.0.0.1", "127.0.0.1"), ("::1", "::1"), ("[::1]", "::1")] {
+            assert!(
+                matches!(server_name(hostname).unwrap(), ServerName::IpAddress(ip) if std::net::IpAddr::from(ip).to_string() == expected),
+                "{hostname}"
+            );
+        }
+        assert!(matches!(
+            server_name("mx.example.com").unwrap(),
+            ServerName::DnsName(_)
+        ));
+        assert!(server_name("[mx.example.com]").is_err());
+    }
+
+    #[test]
+    fn shared_tls_configs() {
+        assert!(Arc::ptr_eq(
+            &shared_tls_config(false),
+            &shared_tls_config(false)
+        ));
+        assert!(Arc::ptr_eq(
+            &shared_tls_config(true),
+            &shared_tls_config(true)
+        ));
+        assert!(!Arc::ptr_eq(
+            &shared_tls_config(false),
+            &shared_tls_config(true)
+        ));
+        assert!(Arc::ptr_eq(&root_cert_store(), &root_cert_store()));
+    }
+
+    #[tokio::test]
+    async fn ip_address_san() {
+        let (acceptor, client_config) = test_configs();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    stream
+                        .write_all(b"220 mx.example.com ESMTP\r\n")
+                        .await
+                        .unwrap();
+                    stream.flush().await.unwrap();
+                }
+            }
+        });
+
+        // The certificate is validated against the IP SAN
+        let client = SmtpClientBuilder::new("127.0.0.1", port)
+            .tls_config(client_config.clone())
+            .connect_bare()
+            .await
+            .unwrap();
+        assert_eq!(client.tls_kind(), TlsKind::Implicit);
+
+        // A certificate not issued to the address is rejected
+        let result = SmtpClientBuilder::new("127.0.0.1", port)
+            .tls_config(client_config)
+            .tls_sni("127.0.0.2")
+            .connect_bare()
+            .await;
+        assert!(matches!(result, Err(crate::Error::Tls(_))));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tls_settings_order() {
+        let (acceptor, client_config) = test_configs();
+        let no_roots = Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth(),
+        );
+        let cache = || Arc::new(ClientSessionMemoryCache::new(32));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    stream
+                        .write_all(b"220 mx.example.com ESMTP\r\n")
+                        .await
+                        .unwrap();
+                    stream.flush().await.unwrap();
+                }
+            }
+        });
+
+        // The custom configuration is used whatever the order of the setters
+        for (builder, expect_ok) in [
+            (
+                SmtpClientBuilder::new("127.0.0.1", port).allow_invalid_certs(),
+                true,
+            ),
+            (
+                SmtpClientBuilder::new("127.0.0.1", port)
+                    .tls_config(no_roots.clone())
+                    .allow_invalid_certs(),
+                false,
+            ),
+            (
+                SmtpClientBuilder::new("127.0.0.1", port)
+                    .allow_invalid_certs()
+                    .tls_config(no_roots.clone()),
+                false,
+            ),
+            (
+                SmtpClientBuilder::new("127.0.0.1", port)
+                    .allow_invalid_certs()
+                    .tls_config(no_roots.clone())
+                    .tls_session_cache(cache()),
+                false,
+            ),
+            (
+                SmtpClientBuilder::new("127.0.0.1", port)
+                    .tls_config(client_config.clone())
+                    .tls_session_cache(cache()),
+                true,
+            ),
+            (
+                SmtpClientBuilder::new("127.0.0.1", port)
+                    .tls_session_cache(cache())
+                    .tls_config(client_config.clone()),
+                true,
+            ),
+        ] {
+            let result = builder.connect_bare().await;
+            assert_eq!(result.is_ok(), expect_ok, "{builder:?}");
+        }
+        server.abort();
+    }
+
+    /// Server session storage counting the sessions that were resumed.
+    #[derive(Debug)]
+    struct ResumptionCounter {
+        sessions: Arc<ServerSessionMemoryCache>,
+        resumed: AtomicUsize,
+    }
+
+    impl StoresServerSessions for ResumptionCounter {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            self.sessions.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            let session = self.sessions.get(key);
+            if session.is_some() {
+                self.resumed.fetch_add(1, Ordering::Relaxed);
+            }
+            session
+        }
+
+        fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+            let session = self.sessions.take(key);
+            if session.is_some() {
+                self.resumed.fetch_add(1, Ordering::Relaxed);
+            }
+            session
+        }
+
+        fn can_cache(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_session_resumption() {
+        let (_, client_config) = test_configs();
+        let counter = Arc::new(ResumptionCounter {
+            sessions: ServerSessionMemoryCache::new(8),
+            resumed: AtomicUsize::new(0),
+        });
+        let mut server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(decode(SERVER_CERT))],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(decode(SERVER_KEY))),
+            )
+            .unwrap();
+        server_config.session_storage = counter.clone();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                stream.set_nodelay(true).unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await.unwrap();
+                    stream
+                        .write_all(b"220 mx.example.com ESMTP\r\n")
+                        .await
+                        .unwrap();
+                    stream.flush().await.unwrap();
+                    // The greeting is sent before the client finishes the
+                    // handshake, tickets are only issued after that
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        stream.write_all(b"250 OK\r\n").await.unwrap();
+                        stream.flush().await.unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        // Builders sharing a cache resume the sessions of each other, the
+        // connector of each builder is only built once
+        let cache = Arc::new(ClientSessionMemoryCache::new(32));
+        let builder = || {
+            SmtpClientBuilder::new("127.0.0.1", port)
+                .tls_config(client_config.clone())
+                .tls_session_cache(cache.clone())
+        };
+        let first = builder();
+        let started = Instant::now();
+        let mut client = first.connect_bare().await.unwrap();
+        let full_handshake = started.elapsed();
+        client.noop().await.unwrap();
+        assert_eq!(counter.resumed.load(Ordering::Relaxed), 0);
+        assert!(first.tls_connector.get().is_some());
+        first.connect_bare().await.unwrap().noop().await.unwrap();
+        assert_eq!(counter.resumed.load(Ordering::Relaxed), 1);
+
+        let started = Instant::now();
+        let mut client = builder().connect_bare().await.unwrap();
+        let resumed_handshake = started.elapsed();
+        client.noop().await.unwrap();
+        assert_eq!(counter.resumed.load(Ordering::Relaxed), 2);
+        println!("Full handshake: {full_handshake:?}, resumed: {resumed_handshake:?}");
+
+        // Without a shared cache the session is not resumed
+        SmtpClientBuilder::new("127.0.0.1", port)
+            .tls_config(client_config.clone())
+            .tls_session_cache(Arc::new(ClientSessionMemoryCache::new(32)))
+            .connect_bare()
+            .await
+            .unwrap()
+            .noop()
+            .await
+            .unwrap();
+        assert_eq!(counter.resumed.load(Ordering::Relaxed), 2);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn starttls_kind() {
+        let (acceptor, client_config) = test_configs();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            stream.write_all(b"220 mx.example.com\r\n").await.unwrap();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "STARTTLS\r\n");
+            stream.write_all(b"220 Ready\r\n").await.unwrap();
+            acceptor.accept(stream.into_inner()).await.unwrap()
+        });
+
+        let client = SmtpClientBuilder::new("127.0.0.1", port)
+            .implicit_tls(false)
+            .connect_bare_plain()
+            .await
+            .unwrap();
+        assert_eq!(client.tls_kind(), TlsKind::None);
+        let client = client
+            .start_tls(&TlsConnector::from(client_config), "127.0.0.1")
+            .await
+            .unwrap();
+        assert_eq!(client.tls_kind(), TlsKind::StartTls);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn starttls_errors() {
+        let (acceptor, client_config) = test_configs();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            for starttls_reply in ["454 4.7.0 TLS not available\r\n", "220 Ready\r\n"] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.write_all(b"220 mx.example.com\r\n").await.unwrap();
+                stream.read_line(&mut line).await.unwrap();
+                stream
+                    .write_all(b"250-mx.example.com\r\n250 STARTTLS\r\n")
+                    .await
+                    .unwrap();
+                stream.read_line(&mut line).await.unwrap();
+                stream.write_all(starttls_reply.as_bytes()).await.unwrap();
+                if starttls_reply.starts_with("220") {
+                    let mut stream =
+                        BufReader::new(acceptor.accept(stream.into_inner()).await.unwrap());
+                    stream.read_line(&mut line).await.unwrap();
+                    stream
+                        .write_all(b"503 5.5.1 Bad sequence of commands\r\n")
+                        .await
+                        .unwrap();
+                    stream.flush().await.unwrap();
+                }
+            }
+        });
+
+        let builder = SmtpClientBuilder::new("127.0.0.1", port)
+            .implicit_tls(false)
+            .tls_config(client_config);
+        assert!(matches!(
+            builder.connect().await,
+            Err(crate::Error::StartTlsRejected(reply)) if reply.code() == 454
+        ));
+        assert!(matches!(
+            builder.connect().await,
+            Err(crate::Error::EhloAfterTlsRejected(reply)) if reply.code() == 503
+        ));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * Copyright Stalwart Labs Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms.
+ */
+
+use smtp_proto::Response;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::SmtpClient;
+
+use super::message::xtext_encode;
+
+impl<T: AsyncRead + AsyncWrite + Unpin> SmtpClient<T> {
+    /// Sends an XCLIENT command overriding the client information the
+    /// server associates with the session, such as `ADDR`, `NAME` or `HELO`.
+    /// Values are xtext encoded. Fails with `UnsupportedExtension` if the
+    /// server did not advertise XCLIENT, or with `InvalidAttribute` if an
+    /// attribute name contains characters other than letters, digits and
+    /// hyphens.
+    ///
+    /// The server replies to a successful XCLIENT with a new greeting and
+    /// expects EHLO to be sent again, the cached capabilities are cleared.
+    pub async fn xclient(&mut self, attrs: &[(&str, &str)]) -> crate::Result<Response<String>> {
+        let response = self.x_command("XCLIENT", attrs).await?;
+        if response.is_positive_completion() {
+            self.capabilities = None;
+            self.extensions.clear();
+        }
+        Ok(response)
+    }
+
+    /// Sends an XFORWARD command passing the original client information,
+    /// such as `ADDR`, `NAME` or `HELO`, for logging by the server. Values are
+    /// xtext encoded. Fails with `UnsupportedExtension` if the server did not
+    /// advertise XFORWARD, or with `InvalidAttribute` if an attribute name
+    /// contains characters other than letters, digits and hyphens.
+    pub async fn xforward(&mut self, attrs: &[(&str, &str)]) -> crate::Result<Response<String>> {
+        self.x_command("XFORWARD", attrs).await
+    }
+
+    async fn x_command(
+        &mut self,
+        command: &'static str,
+        attrs: &[(&str, &str)],
+    ) -> crate::Result<Response<String>> {
+        if self.extension(command).is_none() {
+            return Err(crate::Error::UnsupportedExtension(command));
+        }
+
+        let mut cmd = String::from(command);
+        for (name, value) in attrs {
+            if name.is_empty()
+                || !name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+            {
+                return Err(crate::Error::InvalidAttribute(name.to_string()));
+            }
+            cmd.push(' ');
+            cmd.push_str(name);
+            cmd.push('=');
+            xtext_encode(value, &mut cmd);
+        }
+        cmd.push_str("\r\n");
+        self.cmd(cmd.as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    use crate::SmtpClient;
+
+    #[tokio::test]
+    async fn xclient_xforward() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            for (expected, reply) in [
+                (
+                    "EHLO relay.example.com",
+                    "250-mx.example.com\r\n250-XCLIENT NAME ADDR HELO\r\n250 XFORWARD NAME ADDR\r\n",
+                ),
+                (
+                    "XFORWARD NAME=client.example.org ADDR=192.0.2.1",
+                    "250 Ok\r\n",
+                ),
+                (
+                    "XCLIENT ADDR=192.0.2.1 HELO=my+20laptop",
+                    "220 mx.example.com ESMTP\r\n",
+                ),
+            ] {
+                let mut line = String::new();
+                server.read_line(&mut line).await.unwrap();
+                assert_eq!(line.trim_end(), expected);
+                server.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+
+        // Commands are not sent before the extensions are advertised
+        assert!(matches!(
+            client.xclient(&[("ADDR", "192.0.2.1")]).await,
+            Err(crate::Error::UnsupportedExtension("XCLIENT"))
+        ));
+
+        client.ehlo("relay.example.com").await.unwrap();
+        assert_eq!(client.extension("xclient"), Some("NAME ADDR HELO"));
+        let response = client
+            .xforward(&[("NAME", "client.example.org"), ("ADDR", "192.0.2.1")])
+            .await
+            .unwrap();
+        assert_eq!(response.code(), 250);
+
+        let response = client
+            .xclient(&[("ADDR", "192.0.2.1"), ("HELO", "my laptop")])
+            .await
+            .unwrap();
+        assert_eq!(response.code(), 220);
+        assert!(client.capabilities_cached().is_none());
+        assert!(matches!(
+            client.xforward(&[("ADDR", "192.0.2.1")]).await,
+            Err(crate::Error::UnsupportedExtension("XFORWARD"))
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_attribute_names() {
+        let (stream, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut line = String::new();
+            server.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "EHLO relay.example.com\r\n");
+            server
+                .write_all(b"250-mx.example.com\r\n250 XCLIENT NAME ADDR HELO\r\n")
+                .await
+                .unwrap();
+
+            // Nothing else is sent
+            let mut rest = Vec::new();
+            server.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+        let mut client = SmtpClient::new(stream, Duration::from_secs(30));
+        client.ehlo("relay.example.com").await.unwrap();
+
+        for name in ["", "ADDR=192.0.2.1 NAME", "NAME\r\nQUIT", "HÉLO", "NA ME"] {
+            assert!(
+                matches!(
+                    client.xclient(&[("HELO", "laptop"), (name, "value")]).await,
+                    Err(crate::Error::InvalidAttribute(attr)) if attr == name
+                ),
+                "{name:?}"
+            );
+        }
+        drop(client);
+        assert_eq!(server.await.unwrap(), b"");
+    }
+}